@@ -0,0 +1,151 @@
+// Named-option construction for DharaMap, so new options don't keep
+// growing DharaMap::new()'s argument list.
+
+use core::borrow::BorrowMut;
+use crate::journal::{check_page_size, DHARA_MAX_RETRIES};
+use crate::nand::DharaNand;
+use crate::{DharaError, DharaMap};
+
+/// Builds a DharaMap from named options:
+///
+/// ```no_run
+/// # use dhara_rs::builder::MapBuilder;
+/// # use dhara_rs::nand::{DharaBlock, DharaNand, DharaPage};
+/// # use dhara_rs::DharaError;
+/// # struct Driver;
+/// # impl DharaNand for Driver {
+/// #     fn get_log2_page_size(&self) -> u8 { 11 }
+/// #     fn get_log2_ppb(&self) -> u8 { 6 }
+/// #     fn get_num_blocks(&self) -> u32 { 1024 }
+/// #     fn is_bad(&mut self, _blk: DharaBlock) -> bool { unimplemented!() }
+/// #     fn mark_bad(&mut self, _blk: DharaBlock) -> () { unimplemented!() }
+/// #     fn erase(&mut self, _blk: DharaBlock) -> Result<(), DharaError> { unimplemented!() }
+/// #     fn prog(&mut self, _page: DharaPage, _data: &[u8]) -> Result<(), DharaError> { unimplemented!() }
+/// #     fn is_free(&mut self, _page: DharaPage) -> bool { unimplemented!() }
+/// #     fn read(&mut self, _page: u32, _offset: usize, _length: usize, _data: &mut [u8]) -> Result<(), DharaError> { unimplemented!() }
+/// #     fn copy(&mut self, _src: DharaPage, _dst: DharaPage) -> Result<(), DharaError> { unimplemented!() }
+/// # }
+/// # fn main() -> Result<(), DharaError> {
+/// # let driver = Driver;
+/// # let buf = [0u8; 2048];
+/// let map = MapBuilder::<2048, _>::new(driver, buf)
+///     .gc_ratio(4)
+///     .safety_margin_blocks(16)
+///     .build()?;
+/// # drop(map);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// gc_ratio has no default and must be set; see DharaMap::new() for what
+/// it means. The other options default to what DharaMap::new() uses.
+pub struct MapBuilder<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]> = [u8; N]> {
+    nand: T,
+    page_buf: B,
+    gc_ratio: u8,
+    max_retries: u8,
+    safety_blocks: u32,
+    find_cache: bool,
+    auto_sync: bool,
+    reclaim_slack: bool,
+    erase_verify: bool,
+    paranoid_reads: bool,
+}
+
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> MapBuilder<N,T,B> {
+    pub fn new(nand: T, page_buf: B) -> Self {
+        MapBuilder {
+            nand: nand,
+            page_buf: page_buf,
+            gc_ratio: 0,
+            max_retries: DHARA_MAX_RETRIES,
+            safety_blocks: DHARA_MAX_RETRIES as u32,
+            find_cache: false,
+            auto_sync: false,
+            reclaim_slack: false,
+            erase_verify: false,
+            paranoid_reads: false,
+        }
+    }
+
+    /// Garbage collection operations per write when collection is active.
+    /// Must be non-zero.
+    pub fn gc_ratio(mut self, ratio: u8) -> Self {
+        self.gc_ratio = ratio;
+        self
+    }
+
+    /// How many times the map lets a recovery start over, after further
+    /// failures, before giving up with DharaError::TooBad. Must be
+    /// non-zero.
+    pub fn max_retries(mut self, retries: u8) -> Self {
+        self.max_retries = retries;
+        self
+    }
+
+    /// Blocks held back from the capacity; see DharaMap::set_safety_margin().
+    /// Must be fewer than the chip has.
+    pub fn safety_margin_blocks(mut self, blocks: u32) -> Self {
+        self.safety_blocks = blocks;
+        self
+    }
+
+    /// Cache recent find() results; see DharaMap::set_find_cache().
+    pub fn find_cache(mut self, enabled: bool) -> Self {
+        self.find_cache = enabled;
+        self
+    }
+
+    /// Sync after every write and trim; see DharaMap::set_auto_sync().
+    pub fn auto_sync(mut self, enabled: bool) -> Self {
+        self.auto_sync = enabled;
+        self
+    }
+
+    /// Reclaim garbage at the tail on resume; see
+    /// DharaMap::set_reclaim_slack().
+    pub fn reclaim_slack(mut self, enabled: bool) -> Self {
+        self.reclaim_slack = enabled;
+        self
+    }
+
+    /// Verify each block after erasing it; see
+    /// DharaJournal::set_erase_verify().
+    pub fn erase_verify(mut self, enabled: bool) -> Self {
+        self.erase_verify = enabled;
+        self
+    }
+
+    /// Read critical pages twice; see DharaJournal::set_paranoid_reads().
+    pub fn paranoid_reads(mut self, enabled: bool) -> Self {
+        self.paranoid_reads = enabled;
+        self
+    }
+
+    /// Check the options and build the map. Returns DharaError::BadGeometry
+    /// if N isn't the NAND's page size, and DharaError::BadConfig if an
+    /// option is out of range. The map still needs resume() (or
+    /// resume_or_format()) before use.
+    pub fn build(self) -> Result<DharaMap<N,T,B>, DharaError> {
+        check_page_size::<N,T>(&self.nand)?;
+
+        // DharaMap::new() quietly turns a zero ratio into one, but here it
+        // most likely means the option was forgotten.
+        if self.gc_ratio == 0 || self.max_retries == 0 {
+            return Err(DharaError::BadConfig);
+        }
+        if self.safety_blocks >= self.nand.get_num_blocks() {
+            return Err(DharaError::BadConfig);
+        }
+
+        let mut map = DharaMap::new(self.nand, self.page_buf, self.gc_ratio);
+        map.set_safety_margin(self.safety_blocks);
+        map.max_retries = self.max_retries;
+        map.set_find_cache(self.find_cache);
+        map.set_auto_sync(self.auto_sync);
+        map.set_reclaim_slack(self.reclaim_slack);
+        map.journal.set_erase_verify(self.erase_verify);
+        map.journal.set_paranoid_reads(self.paranoid_reads);
+        Ok(map)
+    }
+}
@@ -0,0 +1,117 @@
+// A small cache of recent find() results, so repeated lookups of the same
+// few sectors don't walk the radix tree through NAND reads every time.
+
+use crate::nand::DharaPage;
+use crate::DharaSector;
+
+/// How many sectors the find cache remembers.
+pub const DHARA_FIND_CACHE_SIZE: usize = 4;
+
+// Every change to the tree moves the journal's head (a page is written),
+// tail (a page is dropped) or root, so entries are only trusted while all
+// three are as they were when the entries were made. That also catches
+// changes made through the journal directly, rather than the map. The
+// epoch is there because the head comes back round to the same page once
+// the journal has wrapped the chip, with a different tree behind it. Even
+// so, the map empties the cache itself on every write, trim and garbage
+// collection, and on resume and format, which wind the journal back.
+#[derive(Clone,Copy,PartialEq)]
+pub(crate) struct CacheKey {
+    pub root: DharaPage,
+    pub head: DharaPage,
+    pub tail: DharaPage,
+    pub epoch: u8,
+}
+
+/// Least recently used first out. Entry 0 is the most recently used.
+pub(crate) struct FindCache {
+    enabled: bool,
+    key: CacheKey,
+    entries: [Option<(DharaSector, DharaPage)>; DHARA_FIND_CACHE_SIZE],
+}
+
+impl FindCache {
+    pub fn new() -> Self {
+        FindCache {
+            enabled: false,
+            key: CacheKey {root: 0, head: 0, tail: 0, epoch: 0},
+            entries: [None; DHARA_FIND_CACHE_SIZE],
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) -> () {
+        self.enabled = enabled;
+        self.invalidate();
+    }
+
+    pub fn invalidate(&mut self) -> () {
+        self.entries = [None; DHARA_FIND_CACHE_SIZE];
+    }
+
+    pub fn get(&mut self, key: CacheKey, sector: DharaSector) -> Option<DharaPage> {
+        if !self.enabled {
+            return None;
+        }
+        if key != self.key {
+            self.invalidate();
+            return None;
+        }
+
+        let i = self.entries.iter().position(|e| matches!(e, Some((s, _)) if *s == sector))?;
+        let entry = self.entries[i];
+        self.entries[..=i].rotate_right(1);
+        entry.map(|(_, page)| page)
+    }
+
+    pub fn insert(&mut self, key: CacheKey, sector: DharaSector, page: DharaPage) -> () {
+        if !self.enabled {
+            return;
+        }
+        if key != self.key {
+            self.invalidate();
+            self.key = key;
+        }
+
+        self.entries.rotate_right(1);
+        self.entries[0] = Some((sector, page));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: CacheKey = CacheKey {root: 1, head: 2, tail: 0, epoch: 0};
+
+    #[test]
+    fn lru() -> () {
+        let mut c = FindCache::new();
+        c.insert(KEY, 1, 10);
+        assert_eq!(c.get(KEY, 1), None); // Disabled.
+
+        c.set_enabled(true);
+        for s in 0..DHARA_FIND_CACHE_SIZE as u32 {
+            c.insert(KEY, s, s + 10);
+        }
+        // Touch the oldest, so the next insert evicts sector 1 instead.
+        assert_eq!(c.get(KEY, 0), Some(10));
+        c.insert(KEY, 99, 109);
+        assert_eq!(c.get(KEY, 1), None);
+        assert_eq!(c.get(KEY, 0), Some(10));
+        assert_eq!(c.get(KEY, 99), Some(109));
+
+        // Anything else about the journal changing drops the lot.
+        let moved = CacheKey {head: 3, ..KEY};
+        assert_eq!(c.get(moved, 0), None);
+        assert_eq!(c.get(KEY, 0), None);
+
+        // Including a lap of the chip that brings the rest back round.
+        c.insert(KEY, 0, 10);
+        let wrapped = CacheKey {epoch: 1, ..KEY};
+        assert_eq!(c.get(wrapped, 0), None);
+    }
+}
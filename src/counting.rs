@@ -0,0 +1,177 @@
+// NAND wrapper that counts operations, for monitoring in the field.
+
+use core::borrow::BorrowMut;
+use crate::nand::{DharaBlock, DharaNand, DharaNandPartial, DharaPage};
+use crate::{DharaError, DharaMap};
+
+/// A snapshot of the operations performed on a NAND chip. Failures are
+/// counted in addition to the operation itself, so prog_fail <= prog.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpCounts {
+    pub is_bad: usize,
+    pub mark_bad: usize,
+    pub erase: usize,
+    pub erase_fail: usize,
+    pub erase_all: usize,
+    pub is_free: usize,
+    pub prog: usize,
+    pub prog_fail: usize,
+    pub read: usize,
+    pub read_bytes: usize,
+    pub copy: usize,
+    pub copy_fail: usize,
+}
+
+/// Wraps a NAND driver and tallies every operation dhara performs on it.
+/// Put it between the driver and the map to measure things like write
+/// amplification on a live system:
+///
+/// ```ignore
+/// let map = DharaMap::<2048, _>::new(CountingNand::new(driver), buf, 4);
+/// // ...
+/// let progs = map.journal.nand.counts().prog;
+/// ```
+pub struct CountingNand<T: DharaNand> {
+    nand: T,
+    counts: OpCounts,
+}
+
+impl<T: DharaNand> CountingNand<T> {
+    pub fn new(nand: T) -> Self {
+        CountingNand {
+            nand: nand,
+            counts: OpCounts::default(),
+        }
+    }
+
+    /// Get the counts so far.
+    pub fn counts(&self) -> OpCounts {
+        self.counts
+    }
+
+    /// Start counting again from zero.
+    pub fn reset_counts(&mut self) -> () {
+        self.counts = OpCounts::default();
+    }
+
+    /// Get the wrapped driver back.
+    pub fn into_inner(self) -> T {
+        self.nand
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.nand
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.nand
+    }
+}
+
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> DharaMap<N,CountingNand<T>,B> {
+    /// Pages programmed (or copied) on the NAND for each page the user
+    /// wrote, since both counters were last reset; call reset_counts() and
+    /// reset_user_writes() together. 1.0 is the ideal. Extra comes from
+    /// garbage collection, recovery and checkpoint metadata, so a larger
+    /// gc_ratio or a fuller map pushes it up. 0.0 if nothing was written.
+    pub fn write_amplification(&self) -> f32 {
+        let user = self.get_user_writes();
+        if user == 0 {
+            return 0.0;
+        }
+
+        let counts = self.journal.nand.counts();
+        (counts.prog + counts.copy) as f32 / user as f32
+    }
+}
+
+// Tally a fallible operation and its failure, passing the result through.
+fn tally(result: Result<(),DharaError>, count: &mut usize, fail: &mut usize) -> Result<(),DharaError> {
+    *count += 1;
+    if result.is_err() {
+        *fail += 1;
+    }
+    result
+}
+
+impl<T: DharaNand> DharaNand for CountingNand<T> {
+    fn get_log2_page_size(&self) -> u8 {self.nand.get_log2_page_size()}
+    fn get_log2_ppb(&self) -> u8 {self.nand.get_log2_ppb()}
+    fn get_num_blocks(&self) -> u32 {self.nand.get_num_blocks()}
+
+    fn is_bad(&mut self, blk: DharaBlock) -> bool {
+        self.counts.is_bad += 1;
+        self.nand.is_bad(blk)
+    }
+
+    fn mark_bad(&mut self, blk: DharaBlock) -> () {
+        self.counts.mark_bad += 1;
+        self.nand.mark_bad(blk)
+    }
+
+    fn erase(&mut self, blk: DharaBlock) -> Result<(),DharaError> {
+        let result = self.nand.erase(blk);
+        tally(result, &mut self.counts.erase, &mut self.counts.erase_fail)
+    }
+
+    // Forwarded, so a driver's own verify is still used. The read-back
+    // inside it isn't counted as reads; a failed verify counts as a
+    // failed erase.
+    fn erase_verify(&mut self, blk: DharaBlock) -> Result<(),DharaError> {
+        let result = self.nand.erase_verify(blk);
+        tally(result, &mut self.counts.erase, &mut self.counts.erase_fail)
+    }
+
+    // Forwarded, so a driver's own bulk erase is still used. The erases
+    // inside it aren't counted as erases.
+    fn erase_all(&mut self) -> Result<(),DharaError> {
+        self.counts.erase_all += 1;
+        self.nand.erase_all()
+    }
+
+    fn prog(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError> {
+        let result = self.nand.prog(page, data);
+        tally(result, &mut self.counts.prog, &mut self.counts.prog_fail)
+    }
+
+    fn is_free(&mut self, page: DharaPage) -> bool {
+        self.counts.is_free += 1;
+        self.nand.is_free(page)
+    }
+
+    fn read(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        self.counts.read += 1;
+        self.counts.read_bytes += length;
+        self.nand.read(page, offset, length, data)
+    }
+
+    fn read_ecc(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<u32, DharaError> {
+        self.counts.read += 1;
+        self.counts.read_bytes += length;
+        self.nand.read_ecc(page, offset, length, data)
+    }
+
+    fn copy(&mut self, src: DharaPage, dst: DharaPage) -> Result<(),DharaError> {
+        let result = self.nand.copy(src, dst);
+        tally(result, &mut self.counts.copy, &mut self.counts.copy_fail)
+    }
+}
+
+// So that counting can also sit underneath a SubdividedNand.
+impl<T: DharaNandPartial> DharaNandPartial for CountingNand<T> {
+    fn prog_partial(&mut self, page: DharaPage, offset: usize, data: &[u8]) -> Result<(),DharaError> {
+        let result = self.nand.prog_partial(page, offset, data);
+        tally(result, &mut self.counts.prog, &mut self.counts.prog_fail)
+    }
+
+    fn is_free_partial(&mut self, page: DharaPage, offset: usize, length: usize) -> bool {
+        self.counts.is_free += 1;
+        self.nand.is_free_partial(page, offset, length)
+    }
+
+    fn copy_partial(&mut self, src: DharaPage, src_offset: usize,
+            dst: DharaPage, dst_offset: usize, length: usize) -> Result<(),DharaError> {
+        let result = self.nand.copy_partial(src, src_offset, dst, dst_offset, length);
+        tally(result, &mut self.counts.copy, &mut self.counts.copy_fail)
+    }
+}
@@ -0,0 +1,77 @@
+// Page arithmetic shared by the journal and anything else that has to
+// reason about where pages sit, such as a driver translating page numbers.
+
+use crate::nand::DharaPage;
+
+/// Is this page aligned to n bits, i.e. the first of a group of 2**n
+/// pages? With n = log2_ppb, the first page of a block; with
+/// n = log2_ppc, the first page of a checkpoint group.
+///
+/// ```
+/// use dhara_rs::geometry::is_aligned;
+///
+/// assert!(is_aligned(128, 6));
+/// assert!(!is_aligned(129, 6));
+/// ```
+pub fn is_aligned(p: DharaPage, n: u8) -> bool {
+    p & ((1u32 << n) - 1) == 0
+}
+
+/// Are these two pages in the same aligned group of 2**n pages? E.g. the
+/// same block, or the same checkpoint group.
+///
+/// ```
+/// use dhara_rs::geometry::align_eq;
+///
+/// assert!(align_eq(17, 18, 2));  // Both in pages 16..20.
+/// assert!(!align_eq(27, 18, 2));
+/// ```
+pub fn align_eq(a: DharaPage, b: DharaPage, n: u8) -> bool {
+    (a ^ b) >> n == 0
+}
+
+/// Is this page the last of its checkpoint group of 2**log2_ppc pages,
+/// the one holding the group's metadata and checkpoint header, rather
+/// than user data?
+///
+/// ```
+/// use dhara_rs::geometry::{is_meta_page, is_user_page};
+///
+/// // Groups of 4 pages, as with the test simulator's 512-byte pages:
+/// // three of user data, then the metadata page.
+/// let log2_ppc = 2;
+/// assert!(is_user_page(8, log2_ppc));
+/// assert!(is_user_page(9, log2_ppc));
+/// assert!(is_user_page(10, log2_ppc));
+/// assert!(is_meta_page(11, log2_ppc));
+/// assert!(is_user_page(12, log2_ppc));
+/// ```
+pub fn is_meta_page(page: DharaPage, log2_ppc: u8) -> bool {
+    // is_aligned(page + 1), without overflowing on the last page number.
+    (!page) & ((1u32 << log2_ppc) - 1) == 0
+}
+
+/// Does this page hold user data, i.e. isn't it a metadata page? See
+/// is_meta_page().
+pub fn is_user_page(page: DharaPage, log2_ppc: u8) -> bool {
+    !is_meta_page(page, log2_ppc)
+}
+
+/// a mod b, for a < 2b: the cheap reduction for a page number that has
+/// had the chip size added to keep it from going negative. For example,
+/// the distance forward from page t to page h on a chip of size pages is
+/// wrap(h + size - t, size).
+///
+/// ```
+/// use dhara_rs::geometry::wrap;
+///
+/// assert_eq!(wrap(7, 3), 4);
+/// assert_eq!(wrap(3, 7), 3);
+/// ```
+pub fn wrap(a: DharaPage, b: DharaPage) -> DharaPage {
+    if a >= b {
+        a - b
+    } else {
+        a
+    }
+}
@@ -1,14 +1,29 @@
+use core::borrow::BorrowMut;
+use core::cell::Cell;
+use core::fmt;
 use crate::bytes::{dhara_r32, dhara_w32};
-use crate::nand::{DharaBlock, DharaNand, DharaPage};
+use crate::geometry::{align_eq, is_aligned, is_meta_page, wrap};
+use crate::nand::{DharaBlock, DharaNand, DharaNandRead, DharaPage};
 use crate::DharaError;
 
 /// Number of bytes used by the journal checkpoint header, as well
 /// as positions in the header (as laid out in map_internals.txt).
+#[cfg(not(feature = "format-version"))]
 const DHARA_HEADER_SIZE: usize = 16;
+#[cfg(feature = "format-version")]
+const DHARA_HEADER_SIZE: usize = 17;
 const DHARA_HEADER_EPOCH_IDX: usize = 3; // One byte after the 3-byte "magic number".
 const DHARA_HEADER_TAIL_IDX: usize = 4;  // 4-byte tail
 const DHARA_HEADER_BBC_IDX: usize = 8;   // 4-byte Bad Block before Current head
 const DHARA_HEADER_BBL_IDX: usize = 12;  // 4-byte est. total Bad Blocks
+#[cfg(feature = "format-version")]
+const DHARA_HEADER_VERSION_IDX: usize = 16; // 1-byte on-flash format version
+
+/// The on-flash format version written into every checkpoint header with
+/// the format-version feature, and expected by journal_resume(). Bump it
+/// whenever the layout changes incompatibly.
+#[cfg(feature = "format-version")]
+pub const DHARA_FORMAT_VERSION: u8 = 1;
 
 /// Global metadata available for a higher layer. This metadata is
 /// persistent once the journal reaches a checkpoint, and is restored on
@@ -51,7 +66,7 @@ const DHARA_JOURNAL_F_ENUM_DONE: u8 = 	0x08;
 /// assist with this. If the head meets the tail, the journal will refuse
 /// to enqueue more pages.
 /// 
-pub struct DharaJournal<const N: usize,T: DharaNand> {
+pub struct DharaJournal<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]> = [u8; N]> {
     // TODO: Need to deal with the NAND driver.
     // TODO: Made this public for jtutil's dequeue function.  Is there a 
     //       better way?  If we keep it like this, there are places where we could 
@@ -59,8 +74,10 @@ pub struct DharaJournal<const N: usize,T: DharaNand> {
     /// A NAND driver implementation.
     pub nand: T, 
     
-    /// The temporary buffer where page data are kept.
-    page_buf: [u8; N],
+    /// The temporary buffer where page data are kept. Usually owned, but
+    /// anything that lends out a [u8; N] will do, such as a &'static mut
+    /// to a buffer placed in a particular memory section.
+    page_buf: B,
 
 	/// In the journal, user data is grouped into checkpoints of
 	/// 2**log2_ppc contiguous aligned pages.
@@ -74,6 +91,14 @@ pub struct DharaJournal<const N: usize,T: DharaNand> {
     /// Epoch counter. This is incremented whenever the journal head
 	/// passes the end of the chip and wraps around.
 	/// 
+	/// It is a u8, as in the C header layout, and is allowed to wrap.
+	/// That is safe because the head programs blocks strictly in order,
+	/// erasing each one first, and never moves onto the tail's block.
+	/// So blocks before the head always carry epoch e, and blocks after
+	/// it carry e-1 (or nothing). Resume only ever compares epochs for
+	/// equality with the one found in the first checkblock, and e and
+	/// e-1 differ even modulo 256.
+	/// 
 	epoch: u8, 
 
 	/// General purpose flags field */
@@ -87,6 +112,11 @@ pub struct DharaJournal<const N: usize,T: DharaNand> {
 	bb_current: DharaBlock,
 	bb_last: DharaBlock,
 
+	/// journal_capacity(), which the map asks for on every write, but
+	/// which only changes with the bad block counters. None once either
+	/// has changed.
+	capacity: Cell<Option<DharaPage>>,
+
 	/// Log head and tail. The tail pointer points to the last user
 	/// page in the log, and the head pointer points to the next free
 	/// raw page. The root points to the last written user page.
@@ -110,13 +140,80 @@ pub struct DharaJournal<const N: usize,T: DharaNand> {
 	recover_next: DharaPage,
 	recover_root: DharaPage,
 	recover_meta: DharaPage,
+
+	/// Called when the journal goes from clean to dirty or back.
+	state_callback: Option<fn(JournalState, DharaPage)>,
+
+	/// A copy of the root's metadata, which every lookup in the map reads
+	/// first. Dropped whenever the root moves; see set_root().
+	root_meta: [u8; DHARA_META_SIZE],
+	root_meta_valid: bool,
+
+	/// Erase blocks with DharaNand::erase_verify() rather than erase().
+	verify_erase: bool,
+
+	/// Program checkpoint pages with DharaNand::prog_verify().
+	verify_checkpoints: bool,
+
+	/// Read checkpoint headers and the root's metadata twice; see
+	/// set_paranoid_reads().
+	paranoid_reads: bool,
+
+	/// The format version written and expected; see set_format_version().
+	#[cfg(feature = "format-version")]
+	format_version: u8,
+}
+
+// Written by hand, since the NAND driver needn't be Debug, and the page
+// buffer is just noise.
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> fmt::Debug for DharaJournal<N,T,B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DharaJournal")
+            .field("head", &self.head)
+            .field("tail", &self.tail)
+            .field("tail_sync", &self.tail_sync)
+            .field("root", &self.root)
+            .field("epoch", &self.epoch)
+            .field("flags", &self.flags)
+            .field("bb_current", &self.bb_current)
+            .field("bb_last", &self.bb_last)
+            .field("log2_ppc", &self.log2_ppc)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Whether everything enqueued so far has reached a checkpoint.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum JournalState {
+    /// All enqueued pages are durable.
+    Clean,
+    /// Some enqueued pages would be lost on power failure.
+    Dirty,
+}
+
+/// A checkpoint header as found on the chip, from
+/// DharaJournal::read_checkpoint_header(). Only headers with the magic
+/// number are decoded, so none of the fields are checked beyond that.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct CheckpointHeader {
+    /// The checkpoint page the header was read from.
+    pub page: DharaPage,
+    pub epoch: u8,
+    /// The synchronized tail when the checkpoint was written.
+    pub tail: DharaPage,
+    /// Bad blocks before the block holding the checkpoint.
+    pub bb_current: DharaBlock,
+    /// The estimate of bad blocks on the whole chip.
+    pub bb_last: DharaBlock,
+    /// The cookie, which the map uses for its sector count.
+    pub cookie: u32,
 }
 
 // ///////////////////////////////////////////////////////////////////////
 // Public interface
 // ///////////////////////////////////////////////////////////////////////
 //
-impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> DharaJournal<N,T,B> {
 
     // The original "init" was renamed "new" to match common Rust usage.
     // TODO: go back to "init" because we want to statically allocate
@@ -125,15 +222,16 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     /// Initialize a journal. You must supply a NAND chip
     /// driver, and a single page buffer. This page buffer will be used
     /// exclusively by the journal, but you are responsible for allocating
-    /// it, and freeing it (if necessary) at the end.
+    /// it, and freeing it (if necessary) at the end. Pass a &'static mut
+    /// instead of the array to keep the buffer where it already lives.
     /// No NAND operations are performed at this point.
     /// 
-    pub fn new(nand: T, page_buf: [u8; N]) -> Self {
+    pub fn new(nand: T, page_buf: B) -> Self {
         // Get these values before moving nand into the struct.
         let psize = nand.get_log2_page_size();
         let max = nand.get_log2_ppb();
 
-        let mut j = DharaJournal::<N,T> {
+        let mut j = DharaJournal::<N,T,B> {
             nand: nand,
             page_buf: page_buf,
             log2_ppc: choose_ppc(psize, max),
@@ -141,6 +239,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             flags: 0,
             bb_current: 0,
             bb_last: 0,  // Gets updated in reset_journal().
+            capacity: Cell::new(None),
             tail_sync: 0,
             tail: 0,
             head: 0,
@@ -148,6 +247,14 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             recover_next: 0,
             recover_root: 0,
             recover_meta: 0,
+            state_callback: None,
+            root_meta: [0xff; DHARA_META_SIZE],
+            root_meta_valid: false,
+            verify_erase: false,
+            verify_checkpoints: false,
+            paranoid_reads: false,
+            #[cfg(feature = "format-version")]
+            format_version: DHARA_FORMAT_VERSION,
         };
 
         j.reset_journal();
@@ -155,22 +262,48 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         j
     }
 
+    /// Like new(), but first checks that N is the NAND's page size (which
+    /// must be a power of two), returning DharaError::BadGeometry if not.
+    pub fn try_new(nand: T, page_buf: B) -> Result<Self, DharaError> {
+        check_page_size::<N,T>(&nand)?;
+        Ok(Self::new(nand, page_buf))
+    }
+
     /// Start up the journal -- search the NAND for the journal head, or
     /// initialize a blank journal if one isn't found. Returns Ok(0) on success
     /// or Err() if a (fatal) error occurs.
     /// 
     /// This operation is O(log N), where N is the number of pages in the
-    /// NAND chip. All other operations are O(1).
+    /// NAND chip. All other operations are O(1). The exception is a chip
+    /// with no checkpoint near the start, every page of which is checked
+    /// before it's called blank.
     /// 
     /// If this operation fails, the journal will be reset to an empty state.
+    /// Err(Blank) means no journal was ever written: every good block reads
+    /// as erased. Err(TooBad) means no checkpoint could be found on a chip
+    /// that isn't blank, e.g. one that fails to read or holds something
+    /// else; Err(CorruptMap) means one was found but can't be trusted.
     pub fn journal_resume(&mut self) -> Result<(),DharaError> {
-        let res = self.find_checkblock(0);
+        // Whatever is on the chip now may not be what we last saw.
+        self.root_meta_valid = false;
+        let mut res = self.find_checkblock(0);
+        // find_checkblock() only looks at the first few blocks, and the
+        // caller may format over a blank chip, so make sure.
+        if res == Err(DharaError::Blank) && !self.chip_is_erased() {
+            res = Err(DharaError::TooBad);
+        }
         match res {
             Err(e) => {
                 self.reset_journal();
                 Err(e)
             }
             Ok(first) => {
+                // A chip written in another format would be misread.
+                if !self.hdr_version_matches() {
+                    self.reset_journal();
+                    return Err(DharaError::VersionMismatch);
+                }
+
                 // Find the last checkpoint-containing block in this epoch.
                 self.epoch = self.hdr_get_epoch();
                 let last = self.find_last_checkblock(first);
@@ -182,12 +315,20 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
                     self.reset_journal();
                     return Err(e);
                 }
+                // A header can carry the magic and still be garbage (e.g. a
+                // torn write that happened to land on "Dha"). Restoring from
+                // it would send every later operation off the end of the chip.
+                if !self.hdr_is_consistent() {
+                    self.reset_journal();
+                    return Err(DharaError::CorruptMap);
+                }
 
                 // Restore setting from the checkpoint.
                 self.tail = self.hdr_get_tail();
                 self.bb_current = self.hdr_get_bb_current();
                 self.bb_last = self.hdr_get_bb_last();
-                self.hdr_clear_user(self.nand.get_log2_page_size() as usize);
+                self.capacity.set(None);
+                self.hdr_clear_user();
 
                 // Perform another linear scan to find the next free user page.
                 // Note that the C code checked for errors and reset the journal
@@ -199,7 +340,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
                 self.tail_sync = self.tail;
 
                 self.clear_recovery();
-                Ok(())
+                self.checked()
             }
         }
     }
@@ -207,21 +348,48 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     /// Obtain an upper bound on the number of user pages storable in the
     /// journal.
     pub fn journal_capacity(&self) -> DharaPage {
-        let max_bad: DharaBlock = if self.bb_last < self.bb_current {
-            self.bb_last 
+        if let Some(capacity) = self.capacity.get() {
+            return capacity;
+        }
+        let capacity = self.compute_capacity();
+        self.capacity.set(Some(capacity));
+        capacity
+    }
+
+    // Allow for the larger of the count from the last pass round the chip
+    // and the one so far on this pass, as the C code does. The smaller
+    // (which this port used to take) ignored the initial bad block guess
+    // until a pass had counted more, overstating capacity on a new map.
+    fn compute_capacity(&self) -> DharaPage {
+        let max_bad: DharaBlock = if self.bb_last > self.bb_current {
+            self.bb_last
         } else {
             self.bb_current
         };
-        let good_blocks: DharaBlock = self.nand.get_num_blocks() - max_bad - 1;
-        let log2_cpb = self.nand.get_log2_ppb() - self.log2_ppc;
-        let good_cps: DharaPage = good_blocks << log2_cpb;
+        capacity_for(self.nand.get_num_blocks(), self.nand.get_log2_ppb(), self.log2_ppc, max_bad)
+    }
 
-        // Good checkpoints * (checkpoint period -1)
-        (good_cps << self.log2_ppc) - good_cps
+    /// Replace the estimate of how many blocks are bad that capacity is
+    /// worked out from, until the journal has counted them itself on its
+    /// next pass round the chip. A new journal guesses; a caller with its
+    /// own bad block table can do better. Counts that would leave no good
+    /// blocks are clamped.
+    pub fn set_bad_block_estimate(&mut self, count: DharaBlock) -> () {
+        self.bb_last = count.min(self.nand.get_num_blocks().saturating_sub(1));
+        self.capacity.set(None);
     }
 
     /// Obtain an upper bound on the number of user pages consumed by the
     /// journal.
+    ///
+    /// This counts every user page slot from the synchronized tail up to,
+    /// but not including, the head. A page counts as soon as it's
+    /// programmed, even while its metadata is still buffered waiting for
+    /// the group's checkpoint, since it already occupies flash. Also
+    /// counted, hence the upper bound: pages dequeued since the last
+    /// checkpoint (see tail_lag()), pages in bad blocks skipped over, and
+    /// pages left behind in a block that failed, including while recovery
+    /// is still copying them. Checkpoint pages are never counted.
     pub fn journal_size(&self) -> DharaPage {
         // Find the number of raw pages, and the number of checkpoints
         // between the head and tail.  The difference between the two
@@ -230,7 +398,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         let mut num_cps = self.head >> self.log2_ppc;
 
         if self.head < self.tail_sync {
-            let total_pages: DharaPage = self.nand.get_num_blocks() << self.nand.get_log2_ppb();
+            let total_pages: DharaPage = self.nand.total_pages();
             num_pages += total_pages;
             num_cps += total_pages >> self.log2_ppc;
         }
@@ -243,12 +411,52 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
 
     /// Get the "cookie" data, a global metadata location for the map layer.
     pub fn get_cookie(&self) -> u32 {
-        dhara_r32(&self.page_buf[DHARA_HEADER_SIZE..(DHARA_HEADER_SIZE+DHARA_COOKIE_SIZE)])
+        dhara_r32(&self.page_buf.borrow()[DHARA_HEADER_SIZE..(DHARA_HEADER_SIZE+DHARA_COOKIE_SIZE)])
     }
 
     /// Set the "cookie" data, a global metadata location for the map layer.
     pub fn set_cookie(&mut self, value: u32) -> () {
-        dhara_w32(&mut self.page_buf[DHARA_HEADER_SIZE..(DHARA_HEADER_SIZE+DHARA_COOKIE_SIZE)], value);
+        dhara_w32(&mut self.page_buf.borrow_mut()[DHARA_HEADER_SIZE..(DHARA_HEADER_SIZE+DHARA_COOKIE_SIZE)], value);
+    }
+
+    /// Set the cookie to zero.
+    pub fn clear_cookie(&mut self) -> () {
+        self.set_cookie(0);
+    }
+
+    /// The user cookie: the end of each checkpoint page, which the
+    /// metadata doesn't use, kept for the application's own durable
+    /// state. Its size depends on the geometry (at least 48 bytes for
+    /// common page sizes; see user_cookie_size()). It's all 0xFF on a new
+    /// journal, and on chips written by the C implementation.
+    pub fn get_user_cookie(&self) -> &[u8] {
+        let start = self.user_cookie_offset();
+        &self.page_buf.borrow()[start..self.nand.page_size()]
+    }
+
+    /// The number of bytes in the user cookie.
+    pub fn user_cookie_size(&self) -> usize {
+        self.nand.page_size() - self.user_cookie_offset()
+    }
+
+    /// Replace the user cookie with data, padded with 0xFF. Like enqueued
+    /// pages, it's durable from the next checkpoint, and stays in every
+    /// checkpoint after that until changed. Data longer than
+    /// user_cookie_size() gives DharaError::BadLength.
+    pub fn set_user_cookie(&mut self, data: &[u8]) -> Result<(), DharaError> {
+        if data.len() > self.user_cookie_size() {
+            return Err(DharaError::BadLength);
+        }
+
+        let start = self.user_cookie_offset();
+        let end = self.nand.page_size();
+        let cookie = &mut self.page_buf.borrow_mut()[start..end];
+        cookie.fill(0xFF);
+        cookie[..data.len()].copy_from_slice(data);
+
+        // So that a sync() writes it out, even with nothing else to write.
+        self.set_dirty();
+        Ok(())
     }
 
     /// Obtain the locations of the first and last pages in the journal.
@@ -265,8 +473,8 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         let offset = self.hdr_user_offset(page & ppc_mask);
 
         // Special case: buffered metadata
-        if align_eq(page, self.head, self.log2_ppc) {
-            buf[..DHARA_META_SIZE].copy_from_slice(&self.page_buf[offset..offset+DHARA_META_SIZE]);
+        if let Some(meta) = self.pending_meta(page) {
+            buf[..DHARA_META_SIZE].copy_from_slice(meta);
             return Ok(());
         }
 
@@ -276,13 +484,139 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             return self.nand.read(self.recover_meta, offset, DHARA_META_SIZE, buf);
         }
 
+        if page == self.root && self.root_meta_valid {
+            buf[..DHARA_META_SIZE].copy_from_slice(&self.root_meta);
+            return Ok(());
+        }
+
         // General case: fetch from metadata page for checkpoint group
-        return self.nand.read(page | ppc_mask, offset, DHARA_META_SIZE, buf);
+        if page == self.root && self.paranoid_reads {
+            read_twice(&mut self.nand, page | ppc_mask, offset, &mut buf[..DHARA_META_SIZE])?;
+        } else {
+            self.nand.read(page | ppc_mask, offset, DHARA_META_SIZE, buf)?;
+        }
+
+        if page == self.root {
+            self.root_meta.copy_from_slice(&buf[..DHARA_META_SIZE]);
+            self.root_meta_valid = true;
+        }
+        Ok(())
+    }
+
+    /// Decode the header of the first checkpoint in a block, for tools
+    /// that look over the chip. Ok(None) if there's no checkpoint there,
+    /// as in an erased or never finished block. Reading a bad block may
+    /// fail like any other read. DharaError::NotFound if the block is
+    /// past the end of the chip. The journal's own state isn't touched.
+    pub fn read_checkpoint_header(&mut self, block: DharaBlock) -> Result<Option<CheckpointHeader>,DharaError> {
+        if block >= self.nand.get_num_blocks() {
+            return Err(DharaError::NotFound);
+        }
+
+        let ppc_mask: DharaPage = (1 << self.log2_ppc) - 1;
+        let page = (block << self.nand.get_log2_ppb()) | ppc_mask;
+        let mut hdr = [0u8; DHARA_HEADER_SIZE + DHARA_COOKIE_SIZE];
+        if self.paranoid_reads {
+            read_twice(&mut self.nand, page, 0, &mut hdr)?;
+        } else {
+            self.nand.read(page, 0, hdr.len(), &mut hdr)?;
+        }
+
+        if hdr[0..3] != *b"Dha" {
+            return Ok(None);
+        }
+        Ok(Some(CheckpointHeader {
+            page: page,
+            epoch: hdr[DHARA_HEADER_EPOCH_IDX],
+            tail: dhara_r32(&hdr[DHARA_HEADER_TAIL_IDX..DHARA_HEADER_BBC_IDX]),
+            bb_current: dhara_r32(&hdr[DHARA_HEADER_BBC_IDX..DHARA_HEADER_BBL_IDX]),
+            bb_last: dhara_r32(&hdr[DHARA_HEADER_BBL_IDX..DHARA_HEADER_BBL_IDX+4]),
+            cookie: dhara_r32(&hdr[DHARA_HEADER_SIZE..]),
+        }))
+    }
+
+    /// journal_read_meta() through another handle on the same chip, for
+    /// callers holding the journal immutably. The cached root metadata is
+    /// used if present, but not filled in. Paranoid reads apply as usual.
+    pub fn read_meta_with<R: DharaNandRead>(&self, nand: &mut R, page: DharaPage, buf: &mut [u8]) -> Result<(),DharaError> {
+        let ppc_mask: DharaPage = (1 << self.log2_ppc) - 1;
+        let offset = self.hdr_user_offset(page & ppc_mask);
+
+        if let Some(meta) = self.pending_meta(page) {
+            buf[..DHARA_META_SIZE].copy_from_slice(meta);
+            return Ok(());
+        }
+
+        if (self.recover_meta != DHARA_PAGE_NONE)
+                && align_eq(page, self.recover_root, self.log2_ppc) {
+            return nand.read_page(self.recover_meta, offset, DHARA_META_SIZE, buf);
+        }
+
+        if page == self.root && self.root_meta_valid {
+            buf[..DHARA_META_SIZE].copy_from_slice(&self.root_meta);
+            return Ok(());
+        }
+
+        if page == self.root && self.paranoid_reads {
+            read_twice(nand, page | ppc_mask, offset, &mut buf[..DHARA_META_SIZE])
+        } else {
+            nand.read_page(page | ppc_mask, offset, DHARA_META_SIZE, buf)
+        }
+    }
+
+    /// Read the metadata stored for any user page on the chip, live or
+    /// not, straight from its group's checkpoint page. Unlike
+    /// journal_read_meta(), this ignores the journal's state entirely, so
+    /// tools can dump stale groups too. A group that never got its
+    /// checkpoint reads as erased. Checkpoint pages have no metadata of
+    /// their own, and give DharaError::NotFound.
+    pub fn raw_read_meta(&mut self, page: DharaPage, buf: &mut [u8]) -> Result<(),DharaError> {
+        let ppc_mask: DharaPage = (1 << self.log2_ppc) - 1;
+        if page & ppc_mask == ppc_mask {
+            return Err(DharaError::NotFound);
+        }
+        let offset = self.hdr_user_offset(page & ppc_mask);
+        self.nand.read(page | ppc_mask, offset, DHARA_META_SIZE, &mut buf[..DHARA_META_SIZE])
+    }
+
+    /// Metadata for a page whose checkpoint group hasn't been written yet.
+    /// Until the group fills, its metadata exists only in the page buffer,
+    /// so this is what a crash right now would lose. Returns None for pages
+    /// in any other group, whose metadata is already on the NAND.
+    pub fn pending_meta(&self, page: DharaPage) -> Option<&[u8]> {
+        if !align_eq(page, self.head, self.log2_ppc) {
+            return None;
+        }
+        let ppc_mask: DharaPage = (1 << self.log2_ppc) - 1;
+        let offset = self.hdr_user_offset(page & ppc_mask);
+        Some(&self.page_buf.borrow()[offset..offset+DHARA_META_SIZE])
     }
 
     /// Advance the tail to the next non-bad block and return the page that's
     /// ready to read. If no page is ready, return DHARA_PAGE_NONE.
+    ///
+    /// Despite the name, this isn't free of side effects: when the tail is
+    /// at the start of a block, it moves the tail past any bad blocks, and
+    /// if that brings it to the head, the root is cleared, since the
+    /// journal is then empty. GC relies on both. Moving the tail this way
+    /// is idempotent, so a second call returns the same page, but it isn't
+    /// undone. peek_readable() asks the same question without moving
+    /// anything.
     pub fn journal_peek(&mut self) -> DharaPage {
+        let page = self.peek_readable();
+        if page != DHARA_PAGE_NONE && page != self.tail {
+            self.tail = page;
+            if self.tail == self.head {
+                self.set_root(DHARA_PAGE_NONE);
+            }
+        }
+        return page;
+    }
+
+    /// The page journal_peek() would return, leaving the tail and root
+    /// alone, so it's safe to call speculatively. It still asks the NAND
+    /// which blocks are bad.
+    pub fn peek_readable(&mut self) -> DharaPage {
         if self.head == self.tail {
             return DHARA_PAGE_NONE;
         }
@@ -293,11 +627,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             for _ in 0..DHARA_MAX_RETRIES {
                 if (block == (self.head >> self.nand.get_log2_ppb())) 
                         || !self.nand.is_bad(block) {
-                    self.tail = block << self.nand.get_log2_ppb();
-                    if self.tail == self.head {
-                        self.root = DHARA_PAGE_NONE;
-                    }
-                    return self.tail;
+                    return block << self.nand.get_log2_ppb();
                 }
                 block = self.next_block(block);
             }
@@ -305,6 +635,51 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         return self.tail;
     }
 
+    /// Is this page the metadata page of its checkpoint group, rather
+    /// than one holding user data? See geometry::is_meta_page().
+    pub fn is_meta_page(&self, page: DharaPage) -> bool {
+        is_meta_page(page, self.log2_ppc)
+    }
+
+    /// The complement of is_meta_page().
+    pub fn is_user_page(&self, page: DharaPage) -> bool {
+        !self.is_meta_page(page)
+    }
+
+    /// Is this block part of the live log, from the block holding the
+    /// synchronized tail up to and including the head's block? Blocks
+    /// outside it hold nothing the journal needs, so something else may
+    /// borrow them, but only until the head comes round: the journal
+    /// erases and reuses every block in turn.
+    pub fn block_in_use(&self, blk: DharaBlock) -> bool {
+        let num_blocks = self.nand.get_num_blocks();
+        let tail_blk = self.tail_sync >> self.nand.get_log2_ppb();
+        let head_blk = self.head >> self.nand.get_log2_ppb();
+        // Distances forward from the tail, so wraparound needs no special
+        // case.
+        let span = (head_blk + num_blocks - tail_blk) % num_blocks;
+        let offset = (blk + num_blocks - tail_blk) % num_blocks;
+        offset <= span
+    }
+
+    /// block_in_use() for every block on the chip, in order.
+    pub fn blocks_in_use(&self) -> impl Iterator<Item = (DharaBlock, bool)> + '_ {
+        (0..self.nand.get_num_blocks()).map(move |blk| (blk, self.block_in_use(blk)))
+    }
+
+    /// Pop user pages off the tail, yielding each page with its metadata.
+    /// Filler pages (whose metadata id is all 1s) are dropped without
+    /// being yielded. As with journal_dequeue(), a yielded page's data stays
+    /// readable until more is enqueued, and the removal isn't permanent
+    /// until the next checkpoint. If metadata can't be read, the error is
+    /// yielded, that page is left at the tail, and iteration stops.
+    pub fn journal_drain(&mut self) -> JournalDrain<'_,N,T,B> {
+        JournalDrain {
+            journal: self,
+            failed: false,
+        }
+    }
+
     /// Remove the last page from the journal. This doesn't take permanent
     /// effect until the next checkpoint.
     pub fn journal_dequeue(&mut self) -> () {
@@ -320,23 +695,243 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             self.tail_sync = self.tail;
         }
 
-        let chip_size: DharaPage = self.nand.get_num_blocks() << self.nand.get_log2_ppb();
+        let chip_size: DharaPage = self.nand.total_pages();
         let raw_size: DharaPage = wrap(self.head + chip_size - self.tail, chip_size);
         let root_offset: DharaPage = wrap(self.head + chip_size - self.root, chip_size);
 
         if root_offset > raw_size {
-            self.root = DHARA_PAGE_NONE;
+            self.set_root(DHARA_PAGE_NONE);
+        }
+    }
+
+    /// The number of pages between the synchronized tail and the tail.
+    /// These have been dequeued, but the last checkpoint still refers to
+    /// them, so their blocks can't be reused until the next checkpoint.
+    /// Pages dequeued while the journal is clean never count here; they
+    /// are reusable straight away.
+    pub fn tail_lag(&self) -> DharaPage {
+        let chip_size: DharaPage = self.nand.total_pages();
+        wrap(self.tail + chip_size - self.tail_sync, chip_size)
+    }
+
+    /// Make the space counted by tail_lag() reusable without waiting for a
+    /// checkpoint, which is only safe once the journal is clean. On a dirty
+    /// journal, returns false having changed nothing: the last checkpoint
+    /// still refers to those blocks, so the caller should checkpoint
+    /// instead.
+    pub fn reclaim_tail(&mut self) -> bool {
+        if !self.journal_is_clean() {
+            return false;
+        }
+
+        self.set_tail_sync(self.tail);
+        true
+    }
+
+    /// Experimental: roll the journal back to an earlier checkpoint, still
+    /// on the chip, discarding everything written after it. 0 means the
+    /// last checkpoint written, which drops only what hasn't been
+    /// checkpointed yet; 1 the one before that, and so on. Groups whose
+    /// checkpoint can't be read back don't count. Any recovery in progress
+    /// is abandoned.
+    ///
+    /// Fails with DharaError::NotFound, having changed nothing, if there
+    /// aren't that many checkpoints since the head last wrapped around the
+    /// chip, or if the head has since erased a block the target still
+    /// needs. Any other error means the target's page couldn't be read in
+    /// full, and the journal should be resumed.
+    ///
+    /// The blocks written after the target are erased, but its own block
+    /// still holds the checkpoints that followed it, so the rollback isn't
+    /// durable until the next checkpoint. The journal is left dirty for
+    /// that reason.
+    pub fn rewind(&mut self, checkpoints_back: u32) -> Result<(), DharaError> {
+        let log2_ppb = self.nand.get_log2_ppb();
+        let chip_size: DharaPage = self.nand.total_pages();
+        let ppc_mask: DharaPage = (1 << self.log2_ppc) - 1;
+        let old_head = self.head;
+
+        // The last checkpoint written closes the group before the head's.
+        // Only headers are read while searching, so that the page buffer,
+        // which holds a dirty group's pending metadata, survives a refusal.
+        let mut hdr = [0u8; DHARA_HEADER_SIZE];
+        let mut group = self.head & !ppc_mask;
+        let mut found: u32 = 0;
+        let cp = loop {
+            let cp = self.prev_checkpoint(group).ok_or(DharaError::NotFound)?;
+            group = cp & !ppc_mask;
+
+            let result = if self.paranoid_reads {
+                read_twice(&mut self.nand, cp, 0, &mut hdr)
+            } else {
+                self.nand.read(cp, 0, DHARA_HEADER_SIZE, &mut hdr)
+            };
+            if result.is_err() || hdr[0..3] != *b"Dha" {
+                continue;
+            }
+            // Anything from an earlier epoch is older than the start of
+            // this pass over the chip, and possibly overwritten.
+            if hdr[DHARA_HEADER_EPOCH_IDX] != self.epoch {
+                return Err(DharaError::NotFound);
+            }
+            if found == checkpoints_back {
+                break cp;
+            }
+            found += 1;
+        };
+
+        // The head erased every block it entered on its way from the
+        // target to where it is now. The target's tail block must not be
+        // one of them.
+        let tail = dhara_r32(&hdr[DHARA_HEADER_TAIL_IDX..DHARA_HEADER_BBC_IDX]);
+        let tail_block = tail & !((1 << log2_ppb) - 1);
+        if tail >= chip_size || wrap(cp + chip_size - tail_block, chip_size)
+                >= wrap(old_head + chip_size - tail_block, chip_size) {
+            return Err(DharaError::NotFound);
+        }
+
+        if self.paranoid_reads {
+            read_twice(&mut self.nand, cp, 0, self.page_buf.borrow_mut())?;
+        } else {
+            self.nand.read(cp, 0, self.nand.page_size(), self.page_buf.borrow_mut())?;
+        }
+        if !self.hdr_is_consistent() {
+            return Err(DharaError::CorruptMap);
+        }
+
+        // From here on, as journal_resume() would have done had the target
+        // been the last checkpoint.
+        self.tail = self.hdr_get_tail();
+        self.bb_current = self.hdr_get_bb_current();
+        self.bb_last = self.hdr_get_bb_last();
+        self.capacity.set(None);
+        self.hdr_clear_user();
+        self.set_root(cp - 1);
+        self.find_head(group);
+        self.flags = 0;
+        self.tail_sync = self.tail;
+        self.clear_recovery();
+
+        // Newer checkpoints in the blocks after the head would otherwise
+        // be found by the next resume, and outrank the one the head
+        // writes next.
+        let mut blk = self.head >> log2_ppb;
+        if !is_aligned(self.head, log2_ppb) {
+            blk += 1;
+        }
+        while blk <= old_head >> log2_ppb {
+            if !self.nand.is_bad(blk) {
+                match self.erase_block(blk) {
+                    Ok(_) => (),
+                    Err(DharaError::BadBlock) => self.nand.mark_bad(blk),
+                    Err(e) => return Err(e),
+                }
+            }
+            blk += 1;
         }
+
+        self.set_dirty();
+        Ok(())
+    }
+
+    /// Erase every block not already marked bad, marking bad any that fail
+    /// to erase, and start an empty journal. Returns the number of bad
+    /// blocks on the chip afterwards. Everything on the chip is lost.
+    ///
+    /// Nothing is written, so until the first checkpoint, resume() sees a
+    /// blank chip. The bad block count found here seeds the capacity
+    /// estimate in the meantime, in place of the usual guess.
+    ///
+    /// Fails with DharaError::TooBad if fewer than two good blocks remain,
+    /// since the head and tail need a block each.
+    pub fn journal_format(&mut self) -> Result<DharaBlock, DharaError> {
+        // Verifying has to go block by block; otherwise the driver may
+        // have a faster way.
+        if self.verify_erase {
+            for blk in 0..self.nand.get_num_blocks() {
+                if self.nand.is_bad(blk) {
+                    continue;
+                }
+
+                match self.erase_block(blk) {
+                    Ok(_) => (),
+                    Err(DharaError::BadBlock) => self.nand.mark_bad(blk),
+                    Err(e) => return Err(e),
+                }
+            }
+        } else {
+            self.nand.erase_all()?;
+        }
+
+        let mut bad: DharaBlock = 0;
+        for blk in 0..self.nand.get_num_blocks() {
+            if self.nand.is_bad(blk) {
+                bad += 1;
+            }
+        }
+
+        self.reset_journal();
+        self.bb_last = bad;
+        self.capacity.set(None);
+
+        if bad + 2 > self.nand.get_num_blocks() {
+            return Err(DharaError::TooBad);
+        }
+        Ok(bad)
+    }
+
+    /// Check that each block really is erased before writing to it, using
+    /// DharaNand::erase_verify(). A block that fails the check is treated
+    /// like one that failed to erase: marked bad and skipped. Off by
+    /// default, since it reads back every block the journal erases.
+    pub fn set_erase_verify(&mut self, enabled: bool) -> () {
+        self.verify_erase = enabled;
+    }
+
+    /// Read back each checkpoint page after programming it, using
+    /// DharaNand::prog_verify(). These are the pages resume() depends on,
+    /// so one that reads back wrong is treated like a failed program: the
+    /// journal recovers onto a fresh block. Off by default, since it reads
+    /// back every checkpoint.
+    pub fn set_verify_checkpoints(&mut self, enabled: bool) -> () {
+        self.verify_checkpoints = enabled;
+    }
+
+    /// Read the pages everything else hangs off (checkpoint headers while
+    /// resuming, and the root's metadata) twice, and only accept them once
+    /// two reads in a row agree, for controllers prone to transient read
+    /// errors that ECC doesn't catch. If no two reads agree after
+    /// DHARA_MAX_RETRIES tries, the read fails with DharaError::ECC. Off by
+    /// default, since it at least doubles the cost of those reads.
+    pub fn set_paranoid_reads(&mut self, enabled: bool) -> () {
+        self.paranoid_reads = enabled;
+    }
+
+    /// Write, and expect on resume, a format version other than
+    /// DHARA_FORMAT_VERSION, e.g. for a migration tool reading a chip laid
+    /// out by an older version. journal_resume() refuses a chip written
+    /// with any other version with DharaError::VersionMismatch.
+    #[cfg(feature = "format-version")]
+    pub fn set_format_version(&mut self, version: u8) -> () {
+        self.format_version = version;
+    }
+
+    /// The format version in use. See set_format_version().
+    #[cfg(feature = "format-version")]
+    pub fn get_format_version(&self) -> u8 {
+        self.format_version
     }
 
     /// Remove all pages from the journal. This doesn't take permanent effect
-    /// until the next checkpoint.
+    /// until the next checkpoint. The cookie goes back to zero, since
+    /// whatever it described went with the pages; the user cookie is kept.
     pub fn journal_clear(&mut self) -> () {
         self.tail = self.head;
-        self.root = DHARA_PAGE_NONE;
-        self.flags |= DHARA_JOURNAL_F_DIRTY;
+        self.set_root(DHARA_PAGE_NONE);
+        self.set_dirty();
 
-        self.hdr_clear_user(self.nand.get_log2_page_size() as usize);
+        self.hdr_clear_user();
+        self.clear_cookie();
     }
 
     /// Append a page to the journal. Both raw page data and metadata must be
@@ -352,6 +947,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     /// be restarted.
     /// 
     pub fn journal_enqueue(&mut self, data: Option<&[u8]>, meta: Option<&[u8]>) -> Result<(), DharaError> {
+        self.checked()?;
 
         for _ in 0..DHARA_MAX_RETRIES {
             // Only try to program if head preparation succeeds.
@@ -377,6 +973,25 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         Err(DharaError::TooBad)
     }
 
+    /// Get the head ready and return the page the next journal_enqueue()
+    /// or journal_copy() will program, for callers that want to record
+    /// where data will land before writing it.
+    ///
+    /// This isn't a pure query: it may erase the head's block, or skip
+    /// past bad blocks, exactly as an enqueue would, and it marks the
+    /// journal dirty. Afterwards the head stays put until something is
+    /// written, so the answer holds unless that write fails and the
+    /// journal has to relocate. Errors are as for journal_enqueue().
+    pub fn next_write_page(&mut self) -> Result<DharaPage, DharaError> {
+        for _ in 0..DHARA_MAX_RETRIES {
+            match self.prepare_head() {
+                Ok(_) => {return Ok(self.head);},
+                Err(e) => {self.recover_from(e)?;},
+            }
+        }
+        Err(DharaError::TooBad)
+    }
+
     /// Copy an existing page to the front of the journal. New metadata must
     /// be specified. This operation is not persistent until a checkpoint is
     /// reached.
@@ -394,6 +1009,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         // and put the self.recover_from() in both the Err(e) branches?
         // let mut my_err: Result<u8,DharaError> = Ok(0);
         let mut my_err: Result<(),DharaError>; // Always gets assigned in the loop.
+        self.checked()?;
 
         for _ in 0..DHARA_MAX_RETRIES {
             my_err = self.prepare_head();
@@ -410,9 +1026,107 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         Err(DharaError::TooBad)
     }
 
+    /// Copy the root page, with its metadata unchanged, to the front of the
+    /// journal. The data is programmed afresh, which resets its retention
+    /// clock, so a journal left idle for a long time can have this done on
+    /// a schedule. Does nothing if the journal is empty. As with
+    /// journal_copy(), the copy isn't persistent until a checkpoint, and
+    /// DharaError::Recover means the upper layer must recover and retry.
+    pub fn refresh_root(&mut self) -> Result<(),DharaError> {
+        let mut meta = [0u8; DHARA_META_SIZE];
+        self.refresh_root_with(&mut meta)
+    }
+
+    /// refresh_root(), reading the root's metadata into a buffer the
+    /// caller already has, rather than one on the stack.
+    pub fn refresh_root_with(&mut self, meta: &mut [u8; DHARA_META_SIZE]) -> Result<(),DharaError> {
+        let root = self.root;
+        if root == DHARA_PAGE_NONE {
+            return Ok(());
+        }
+
+        self.journal_read_meta(root, meta)?;
+        self.journal_copy(root, Some(meta))
+    }
+
+    /// Check the invariants that relate the journal's pointers, returning
+    /// DharaError::CorruptMap if any fail. Cheap (no NAND access), so it
+    /// can be used as a production assertion, e.g. after journal_resume().
+    pub fn self_check(&self) -> Result<(), DharaError> {
+        let chip_size: DharaPage = self.nand.total_pages();
+        let ppc_mask: DharaPage = (1 << self.log2_ppc) - 1;
+        // In range, and not the metadata page at the end of a group.
+        let is_user_page = |p: DharaPage| p < chip_size && (!p) & ppc_mask != 0;
+        // How far forward, around the chip, it is from one page to another.
+        let distance = |from: DharaPage, to: DharaPage| wrap(to + chip_size - from, chip_size);
+
+        if !is_user_page(self.head) || !is_user_page(self.tail)
+                || !is_user_page(self.tail_sync) {
+            return Err(DharaError::CorruptMap);
+        }
+
+        // The head never advances forward onto the same block as the tail.
+        if align_eq(self.head, self.tail_sync, self.nand.get_log2_ppb())
+                && self.head < self.tail_sync {
+            return Err(DharaError::CorruptMap);
+        }
+
+        // The current tail lies between the synchronized tail and the head.
+        if distance(self.tail_sync, self.tail) > distance(self.tail_sync, self.head) {
+            return Err(DharaError::CorruptMap);
+        }
+
+        // The root is a user page within a non-empty journal.
+        if self.root != DHARA_PAGE_NONE && (!is_user_page(self.root)
+                || distance(self.tail, self.root) >= distance(self.tail, self.head)) {
+            return Err(DharaError::CorruptMap);
+        }
+
+        Ok(())
+    }
+
+    // self_check() with the "checked" feature, otherwise nothing. Called
+    // where a broken state would otherwise be acted on.
+    pub(crate) fn checked(&self) -> Result<(), DharaError> {
+        #[cfg(feature = "checked")]
+        return self.self_check();
+        #[cfg(not(feature = "checked"))]
+        return Ok(());
+    }
+
+    /// Register a function to be told whenever the journal becomes dirty
+    /// (something was enqueued or cleared) or clean again (a checkpoint was
+    /// written), along with the root at that moment. Once it reports Clean,
+    /// everything up to that root is durable, so cached copies can be let
+    /// go. Pass None to stop. Resets done by journal_resume() aren't
+    /// reported.
+    pub fn set_state_callback(&mut self, cb: Option<fn(JournalState, DharaPage)>) -> () {
+        self.state_callback = cb;
+    }
+
     /// Mark the journal dirty.
     pub fn journal_mark_dirty(&mut self) -> () {
-        self.flags |= DHARA_JOURNAL_F_DIRTY;
+        self.set_dirty();
+    }
+
+    /// Mark a block bad on the user's behalf, for damage found some other
+    /// way than a failed erase or program. Nothing is moved off it: the
+    /// caller must already have relocated anything live, and the head must
+    /// not be on it. If the head has already passed it this epoch, it's
+    /// counted among the bad blocks it would otherwise have skipped. The
+    /// journal is left dirty, so the new count is saved at the next
+    /// checkpoint.
+    pub fn journal_mark_bad(&mut self, blk: DharaBlock) -> () {
+        if self.nand.is_bad(blk) {
+            return;
+        }
+
+        self.nand.mark_bad(blk);
+        if blk < self.head >> self.nand.get_log2_ppb() {
+            self.bb_current += 1;
+            self.capacity.set(None);
+        }
+        self.set_dirty();
     }
 
     /// Is the journal checkpointed? If true, then all pages enqueued are now
@@ -467,6 +1181,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     pub fn get_tail_sync(&self) -> u32 {self.tail_sync}
     pub fn get_bb_current(&self) -> u32 {self.bb_current}
     pub fn get_bb_last(&self) -> u32 {self.bb_last}
+    pub fn get_epoch(&self) -> u8 {self.epoch}
     // TODO: get_root and journal_root do the same thing.  Eliminate one.
     pub fn get_root(&self) -> u32 {self.root}
     pub fn get_log2_ppb(&self) -> u8 {self.nand.get_log2_ppb()}
@@ -489,7 +1204,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
 // Private methods
 // ///////////////////////////////////////////////////////////////////////
 //
-impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> DharaJournal<N,T,B> {
     // TODO: A lot of these were marked as "inline" in the C code.
     // Leaving without that annotation for now, and we'll check results later.
 
@@ -503,60 +1218,91 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
 
     // Does the page buffer contain a valid checkpoint page?
     fn hdr_has_magic(&self) -> bool {
-        (self.page_buf[0] == b'D')
-            && (self.page_buf[1] == b'h')
-            && (self.page_buf[2] == b'a')
+        (self.page_buf.borrow()[0] == b'D')
+            && (self.page_buf.borrow()[1] == b'h')
+            && (self.page_buf.borrow()[2] == b'a')
     }
 
-    // Insert the magic characters into the buffer.
+    // Insert the magic characters into the buffer, and with them the
+    // format version, so that a header never has one without the other.
     fn hdr_put_magic(&mut self) -> () {
-        self.page_buf[0] = b'D';
-        self.page_buf[1] = b'h';
-        self.page_buf[2] = b'a';
+        self.page_buf.borrow_mut()[0] = b'D';
+        self.page_buf.borrow_mut()[1] = b'h';
+        self.page_buf.borrow_mut()[2] = b'a';
+        #[cfg(feature = "format-version")]
+        {
+            self.page_buf.borrow_mut()[DHARA_HEADER_VERSION_IDX] = self.format_version;
+        }
+    }
+
+    // Was the header in the buffer written in the format we expect?
+    #[cfg(feature = "format-version")]
+    fn hdr_version_matches(&self) -> bool {
+        self.page_buf.borrow()[DHARA_HEADER_VERSION_IDX] == self.format_version
+    }
+
+    #[cfg(not(feature = "format-version"))]
+    fn hdr_version_matches(&self) -> bool {
+        true
     }
 
     // What epoch is this page?
     fn hdr_get_epoch(&self) -> u8 {
-        self.page_buf[DHARA_HEADER_EPOCH_IDX]
+        self.page_buf.borrow()[DHARA_HEADER_EPOCH_IDX]
     }
 
     // Set the epoch.
     fn hdr_set_epoch(&mut self, e: u8) -> () {
-        self.page_buf[DHARA_HEADER_EPOCH_IDX] = e;
+        self.page_buf.borrow_mut()[DHARA_HEADER_EPOCH_IDX] = e;
     }
 
     // Get the tail value in the page buffer.
     fn hdr_get_tail(&self) -> DharaPage {
-        dhara_r32(&self.page_buf[DHARA_HEADER_TAIL_IDX..DHARA_HEADER_BBC_IDX])
+        dhara_r32(&self.page_buf.borrow()[DHARA_HEADER_TAIL_IDX..DHARA_HEADER_BBC_IDX])
     }
 
     // Set the tail.
     fn hdr_set_tail(&mut self, tail: DharaPage) -> () {
-        dhara_w32(&mut self.page_buf[DHARA_HEADER_TAIL_IDX..DHARA_HEADER_BBC_IDX], tail)
+        dhara_w32(&mut self.page_buf.borrow_mut()[DHARA_HEADER_TAIL_IDX..DHARA_HEADER_BBC_IDX], tail)
     }
 
     fn hdr_get_bb_current(&self) -> DharaPage {
-        dhara_r32(&self.page_buf[DHARA_HEADER_BBC_IDX..DHARA_HEADER_BBL_IDX])
+        dhara_r32(&self.page_buf.borrow()[DHARA_HEADER_BBC_IDX..DHARA_HEADER_BBL_IDX])
     }
 
     fn hdr_set_bb_current(&mut self, bbc: DharaPage) -> () {
-        dhara_w32(&mut self.page_buf[DHARA_HEADER_BBC_IDX..DHARA_HEADER_BBL_IDX], bbc)
+        dhara_w32(&mut self.page_buf.borrow_mut()[DHARA_HEADER_BBC_IDX..DHARA_HEADER_BBL_IDX], bbc)
     }
 
     fn hdr_get_bb_last(&self) -> DharaPage {
-        dhara_r32(&self.page_buf[DHARA_HEADER_BBL_IDX..DHARA_HEADER_SIZE])
+        dhara_r32(&self.page_buf.borrow()[DHARA_HEADER_BBL_IDX..DHARA_HEADER_BBL_IDX+4])
     }
 
     fn hdr_set_bb_last(&mut self, bbl: DharaPage) -> () {
-        dhara_w32(&mut self.page_buf[DHARA_HEADER_BBL_IDX..DHARA_HEADER_SIZE], bbl)
+        dhara_w32(&mut self.page_buf.borrow_mut()[DHARA_HEADER_BBL_IDX..DHARA_HEADER_BBL_IDX+4], bbl)
+    }
+
+    // Check that the header in the page buffer describes something that
+    // could exist on this chip.
+    fn hdr_is_consistent(&self) -> bool {
+        let num_blocks = self.nand.get_num_blocks();
+        let num_pages: DharaPage = num_blocks << self.nand.get_log2_ppb();
+        self.hdr_get_tail() < num_pages
+            && self.hdr_get_bb_current() <= num_blocks
+            && self.hdr_get_bb_last() <= num_blocks
     }
 
-    // TODO: In the C code, this is only ever called with the NAND's 
-    // log2 page size. For now, I've retained the size, but we could probably remove it.
-    fn hdr_clear_user(&mut self, log2_page_size: usize) -> () {
+    // Only the metadata slots: the user cookie after them is carried
+    // from one checkpoint to the next.
+    fn hdr_clear_user(&mut self) -> () {
         let start = DHARA_HEADER_SIZE + DHARA_COOKIE_SIZE;
-        let end = 1 << log2_page_size;
-        self.page_buf[start..end].fill(0xFF);
+        let end = self.user_cookie_offset();
+        self.page_buf.borrow_mut()[start..end].fill(0xFF);
+    }
+
+    // The user cookie takes whatever the metadata slots leave of the page.
+    fn user_cookie_offset(&self) -> usize {
+        self.hdr_user_offset((1 << self.log2_ppc) - 1)
     }
 
     fn hdr_user_offset(&self, which: u32) -> usize {
@@ -567,7 +1313,9 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     // Page geometry helpers on the struct
 
     // What is the successor of this block?
+    // blk must be on the chip, so blk + 1 is at most get_num_blocks().
     fn next_block(&self, blk: DharaBlock) -> DharaBlock {
+        debug_assert!(blk < self.nand.get_num_blocks());
         let mut block = blk + 1;
         if block >= self.nand.get_num_blocks() {
             block = 0;
@@ -590,14 +1338,19 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         Ok(0)
     }
 
+    // page must be on the chip. Neither addition can then overflow:
+    // total_pages() fits in a DharaPage, and the chip's last page is
+    // always a metadata page, so p reaches at most total_pages(), which
+    // wraps to 0.
     fn next_upage(&self, page: DharaPage) -> DharaPage {
+        debug_assert!(page < self.nand.total_pages());
         let mut p = page + 1;
 
-        if is_aligned(p + 1, self.log2_ppc) {
+        if is_meta_page(p, self.log2_ppc) {
             p += 1;
         }
 
-        if p >= (self.nand.get_num_blocks() << self.nand.get_log2_ppb()) {
+        if p >= self.nand.total_pages() {
             p = 0;
         }
         p
@@ -619,35 +1372,90 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         // We don't yet have a bad block estimate, so make
         // a conservative guess.
         self.epoch = 0;
-        self.bb_last = self.nand.get_num_blocks() >> 6; // TODO: why?
+        self.bb_last = initial_bad_guess(self.nand.get_num_blocks());
         self.bb_current = 0;
+        self.capacity.set(None);
         self.flags = 0;
         // Empty journal
         self.head = 0;
         self.tail = 0;
         self.tail_sync = 0;
-        self.root = DHARA_PAGE_NONE;
+        self.set_root(DHARA_PAGE_NONE);
 
         // No recovery required.
         self.clear_recovery();
 
         // Empty metadata buffer.
-        self.page_buf.fill(0xFF);
+        self.page_buf.borrow_mut().fill(0xFF);
+    }
+
+    // The cached root metadata stays valid for as long as the root does:
+    // the root is always in the live log, whose blocks are never erased.
+    fn set_root(&mut self, root: DharaPage) -> () {
+        if root != self.root {
+            self.root_meta_valid = false;
+        }
+        self.root = root;
+    }
+
+    fn set_dirty(&mut self) -> () {
+        if self.flags & DHARA_JOURNAL_F_DIRTY == 0 {
+            self.flags |= DHARA_JOURNAL_F_DIRTY;
+            if let Some(cb) = self.state_callback {
+                cb(JournalState::Dirty, self.root);
+            }
+        }
+    }
+
+    fn set_clean(&mut self) -> () {
+        if self.flags & DHARA_JOURNAL_F_DIRTY != 0 {
+            self.flags &= !DHARA_JOURNAL_F_DIRTY;
+            if let Some(cb) = self.state_callback {
+                cb(JournalState::Clean, self.root);
+            }
+        }
+    }
+
+    // The checkpoint page closing the group before the one starting at
+    // group, skipping bad blocks. None at the start of the chip, since
+    // anything before that is from the previous epoch.
+    fn prev_checkpoint(&mut self, group: DharaPage) -> Option<DharaPage> {
+        let log2_ppb = self.nand.get_log2_ppb();
+        if !is_aligned(group, log2_ppb) {
+            return Some(group - 1);
+        }
+
+        let mut blk = group >> log2_ppb;
+        while blk > 0 {
+            blk -= 1;
+            if !self.nand.is_bad(blk) {
+                return Some(((blk + 1) << log2_ppb) - 1);
+            }
+        }
+        None
     }
 
     fn roll_stats(&mut self) -> () {
         self.bb_last = self.bb_current;
         self.bb_current = 0;
-        self.epoch += 1;
+        self.capacity.set(None);
+        self.epoch = self.epoch.wrapping_add(1);
     }
 
     // Find the first checkpoint-containing block. If a block contains any
     // checkpoints at all, then it must contain one in the first checkpoint
     // location -- otherwise, we would have considered the block eraseable.
     //
+    // Not finding one is only Err(Blank) if every block looked at had its
+    // first checkpoint page erased, or was bad. Anything else (a read that
+    // failed, or a page programmed without the magic) may be a map we
+    // can't read; that's Err(TooBad), as in C. Blank only covers the
+    // blocks looked at, so journal_resume() checks the rest of the chip
+    // before passing it on.
     fn find_checkblock(&mut self, block: DharaBlock) -> Result<DharaBlock,DharaError> {
         let mut i: u8 = 0;
         let mut blk = block;
+        let mut blank = true;
 
         while blk < self.nand.get_num_blocks() && i < DHARA_MAX_RETRIES {
             let p: DharaPage = (blk << self.nand.get_log2_ppb())
@@ -658,10 +1466,14 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             // has_magic() used the read.)
             // We're going to read and handle the Result differently.
             if !self.nand.is_bad(blk) {
-                let res = self.nand.read(p, 0, 1 << self.nand.get_log2_page_size(), &mut self.page_buf);
+                let res = self.nand.read(p, 0, self.nand.page_size(), self.page_buf.borrow_mut());
                 match res {
-                    Err(_e) => (),
-                    Ok(_) => if self.hdr_has_magic() {return Ok(blk);}
+                    Err(_e) => blank = false,
+                    Ok(_) => if self.hdr_has_magic() {
+                        return Ok(blk);
+                    } else if !self.nand.is_free(p) {
+                        blank = false;
+                    }
                 }
             }
             blk += 1;
@@ -669,7 +1481,31 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         }
 
         // If we get this far, we haven't found one.
-        Err(DharaError::TooBad)
+        if blank {
+            Err(DharaError::Blank)
+        } else {
+            Err(DharaError::TooBad)
+        }
+    }
+
+    // Every page of every good block erased: nothing has ever been written.
+    // This is a scan of the whole chip when it's true, but resume only
+    // comes here on a chip that already looks blank, which normally means
+    // first boot. A programmed page ends the scan early.
+    fn chip_is_erased(&mut self) -> bool {
+        let log2_ppb = self.nand.get_log2_ppb();
+        for blk in 0..self.nand.get_num_blocks() {
+            if self.nand.is_bad(blk) {
+                continue;
+            }
+            let first: DharaPage = blk << log2_ppb;
+            for page in first..(first + (1 << log2_ppb)) {
+                if !self.nand.is_free(page) {
+                    return false;
+                }
+            }
+        }
+        true
     }
 
     // Perform a binary search for the last checkblock, starting
@@ -776,17 +1612,21 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     // Side effect is to change the root field.
     fn find_root(&mut self, start: DharaPage) -> Result<(), DharaError> {
         let block: DharaBlock = start >> self.nand.get_log2_ppb();
-        let mut i: u32 = (start & ((1 << self.nand.get_log2_ppb()) - 1)) >> self.log2_ppc;
+        let mut i: u32 = (start & (self.nand.pages_per_block() - 1)) >> self.log2_ppc;
 
         loop {
             let page: DharaPage = (block << self.nand.get_log2_ppb()) + 
                 ((i + 1) << self.log2_ppc) - 1;
             // Read a page into the buffer, which is also used by subsequent
             // functions.
-            let result = self.nand.read(page, 0, 1 << self.nand.get_log2_page_size(), &mut self.page_buf);
+            let result = if self.paranoid_reads {
+                read_twice(&mut self.nand, page, 0, self.page_buf.borrow_mut())
+            } else {
+                self.nand.read(page, 0, self.nand.page_size(), self.page_buf.borrow_mut())
+            };
             if result.is_ok() && self.hdr_has_magic() 
                     && (self.hdr_get_epoch() == self.epoch) {
-                self.root = page - 1; // Found the root.
+                self.set_root(page - 1); // Found the root.
                 return Ok(());
             }
 
@@ -797,7 +1637,9 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
                 i -= 1;
             }
         }
-        Err(DharaError::TooBad)
+        // find_checkblock() saw magic in this epoch, so a map is here, but
+        // none of its checkpoints can be read back.
+        Err(DharaError::CorruptMap)
     }
 
     // Starting from the last good checkpoint, find either:
@@ -807,11 +1649,13 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
     // The block we end up on might be bad, but that's OK --
     // we'll skip it when we go to prepare the next write.
     // Note that C code returned an int, but it is always zero, and no error code.
+    //
+    // The scan starts on the checkpoint's own group rather than the next
+    // user page. With two pages per group, that page is already in the next
+    // group, possibly at the start of the tail's block, and the tail check
+    // below would never see it.
     fn find_head(&mut self, start: DharaPage) -> () {
-        self.head = self.next_upage(start);
-        if self.head == 0 {
-            self.roll_stats();
-        }
+        self.head = start;
 
         loop {
             // How many free pages trail this checkpoint group?
@@ -832,7 +1676,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
 
             // Skip to the next checkpoint group.
             self.head = first + ppc;
-            if self.head >= (self.nand.get_num_blocks() << self.nand.get_log2_ppb()) {
+            if self.head >= self.nand.total_pages() {
                 self.head = 0;
                 self.roll_stats();
             }
@@ -848,6 +1692,14 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         }
     }
 
+    fn erase_block(&mut self, blk: DharaBlock) -> Result<(),DharaError> {
+        if self.verify_erase {
+            self.nand.erase_verify(blk)
+        } else {
+            self.nand.erase(blk)
+        }
+    }
+
     // Make sure the head pointer is on a ready-to-program page.
     fn prepare_head(&mut self) -> Result<(),DharaError> {
         let next = self.next_upage(self.head);
@@ -859,7 +1711,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             return Err(DharaError::JournalFull);
         }
 
-        self.flags |= DHARA_JOURNAL_F_DIRTY;
+        self.set_dirty();
         if !is_aligned(self.head, self.nand.get_log2_ppb()) {
             return Ok(());
         }
@@ -868,10 +1720,11 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             let block: DharaBlock = self.head >> self.nand.get_log2_ppb();
 
             if !self.nand.is_bad(block) {
-                return self.nand.erase(block);
+                return self.erase_block(block);
             }
 
             self.bb_current += 1;
+            self.capacity.set(None);
             self.skip_block()?; // Returning the error, ignoring the Ok() case.
         }
 
@@ -893,8 +1746,8 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         // start of the original bad block, and reset the destination 
         // enumeration to the newly found good block.
         self.flags &= !DHARA_JOURNAL_F_ENUM_DONE;
-        self.recover_next = self.recover_root & !((1u32 << self.nand.get_log2_ppb()) - 1);
-        self.root = self.recover_root;
+        self.recover_next = self.recover_root & !(self.nand.pages_per_block() - 1);
+        self.set_root(self.recover_root);
     }
 
     fn dump_meta(&mut self) -> Result<(),DharaError> {
@@ -903,7 +1756,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         
         for _ in 0..DHARA_MAX_RETRIES {
             let my_err = self.prepare_head()
-                .and_then(|_| self.nand.prog(self.head, &self.page_buf));
+                .and_then(|_| self.nand.prog(self.head, self.page_buf.borrow()));
             
             if my_err.is_ok() {
                 self.recover_meta = self.head;
@@ -911,8 +1764,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
                 if self.head == 0 {
                     self.roll_stats();
                 }
-                // Using "into()" method of u8 rather than "as usize".
-                self.hdr_clear_user(self.nand.get_log2_page_size().into());
+                self.hdr_clear_user();
                 return Ok(());
             }
             
@@ -923,6 +1775,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
             }
 
             self.bb_current += 1;
+            self.capacity.set(None);
             self.nand.mark_bad(self.head >> self.nand.get_log2_ppb());
             self.skip_block()?;
         }
@@ -940,6 +1793,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
 
         // Advance to the next free page.
         self.bb_current += 1;
+        self.capacity.set(None);
         self.skip_block()?;
 
         // Are we already in the middle of a recovery?
@@ -955,7 +1809,7 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         }
 
         self.recover_root = self.root;
-        self.recover_next = self.recover_root & !((1u32 << self.nand.get_log2_ppb()) - 1);
+        self.recover_next = self.recover_root & !(self.nand.pages_per_block() - 1);
 
         // Are we holding buffered metadata?  Dump it first.
         if !is_aligned(old_head, self.log2_ppc) {
@@ -991,13 +1845,13 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         // We have just written a user page.  Add the metadata
         // to the buffer.
         match meta {
-            Some(meta) => self.page_buf[offset..offset+DHARA_META_SIZE].copy_from_slice(meta),
-            None => self.page_buf[offset..offset+DHARA_META_SIZE].fill(0xFF),
+            Some(meta) => self.page_buf.borrow_mut()[offset..offset+DHARA_META_SIZE].copy_from_slice(meta),
+            None => self.page_buf.borrow_mut()[offset..offset+DHARA_META_SIZE].fill(0xFF),
         }
 
         // Unless we've filled the buffer, don't do any I/O.
         if !is_aligned(self.head + 2, self.log2_ppc) {
-            self.root = self.head;
+            self.set_root(self.head);
             self.head += 1;
             return Ok(());
         }
@@ -1010,12 +1864,17 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
         self.hdr_set_bb_current(self.bb_current);
         self.hdr_set_bb_last(self.bb_last);
 
-        if let Err(e) = self.nand.prog(self.head + 1, &self.page_buf) {
+        let result = if self.verify_checkpoints {
+            self.nand.prog_verify(self.head + 1, self.page_buf.borrow())
+        } else {
+            self.nand.prog(self.head + 1, self.page_buf.borrow())
+        };
+        if let Err(e) = result {
             return self.recover_from(e);
         }
 
-        self.flags &= !DHARA_JOURNAL_F_DIRTY;
-        self.root = old_head;
+        self.set_root(old_head);
+        self.set_clean();
         self.head = self.next_upage(self.head);
 
         if self.head == 0 {
@@ -1035,30 +1894,161 @@ impl<const N: usize,T: DharaNand> DharaJournal<N,T> {
 
 }
 
+/// Iterator returned by DharaJournal::journal_drain().
+pub struct JournalDrain<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> {
+    journal: &'a mut DharaJournal<N,T,B>,
+    failed: bool,
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> Iterator for JournalDrain<'a,N,T,B> {
+    type Item = Result<(DharaPage, [u8; DHARA_META_SIZE]), DharaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            let page = self.journal.journal_peek();
+            if page == DHARA_PAGE_NONE {
+                return None;
+            }
+
+            let mut meta: [u8; DHARA_META_SIZE] = [0u8; DHARA_META_SIZE];
+            if let Err(e) = self.journal.journal_read_meta(page, &mut meta) {
+                // Carrying on would skip a page the caller never saw.
+                self.failed = true;
+                return Some(Err(e));
+            }
+            self.journal.journal_dequeue();
+
+            if dhara_r32(&meta[0..4]) != DHARA_PAGE_NONE {
+                return Some(Ok((page, meta)));
+            }
+        }
+    }
+}
+
 // ********************************************************************
 // Page geometry helpers independent of the struct
 
-// Is this page aligned to N bits?
-fn is_aligned(p: DharaPage, n: u8) -> bool {
-    p & ((1u32 << n) - 1) == 0
+/// The page buffer size, N, that goes with a NAND's page size. Being a
+/// const fn, it can size the buffer itself:
+///
+/// ```
+/// use dhara_rs::journal::page_buf_size;
+///
+/// let buf = [0u8; page_buf_size(9)];
+/// assert_eq!(buf.len(), 512);
+/// ```
+pub const fn page_buf_size(log2_page_size: u8) -> usize {
+    1usize << log2_page_size
 }
 
-// Are these two pages from the same alignment group?
-fn align_eq(a: DharaPage, b: DharaPage, n: u8) -> bool {
-    (a ^ b) >> n == 0
+/// Compile-time check that a page buffer of N bytes suits pages of
+/// 2**LOG2_PAGE_SIZE bytes. Naming OK fails the build on a mismatch,
+/// rather than leaving it for try_new() to catch at run time:
+///
+/// ```
+/// use dhara_rs::journal::PageBufCheck;
+///
+/// const _: () = PageBufCheck::<512, 9>::OK;
+/// ```
+///
+/// ```compile_fail
+/// use dhara_rs::journal::PageBufCheck;
+///
+/// const _: () = PageBufCheck::<500, 9>::OK;
+/// ```
+///
+/// It also works on generic parameters, where the check happens when the
+/// code is instantiated:
+///
+/// ```compile_fail
+/// use dhara_rs::journal::PageBufCheck;
+///
+/// fn make_buf<const N: usize>() -> [u8; N] {
+///     let () = PageBufCheck::<N, 11>::OK;
+///     [0u8; N]
+/// }
+/// let _ = make_buf::<512>();
+/// ```
+pub struct PageBufCheck<const N: usize, const LOG2_PAGE_SIZE: u8>;
+
+impl<const N: usize, const LOG2_PAGE_SIZE: u8> PageBufCheck<N, LOG2_PAGE_SIZE> {
+    pub const OK: () = assert!(N == page_buf_size(LOG2_PAGE_SIZE),
+        "page buffer size doesn't match the NAND page size");
 }
 
-fn wrap(a: DharaPage, b: DharaPage) -> DharaPage {
-    if a >= b {
-        a - b
-    } else {
-        a
+// The journal does all its page arithmetic with 1 << log2_page_size, but
+// reads and writes through the N byte buffer, so the two must agree.
+pub(crate) fn check_page_size<const N: usize,T: DharaNand>(nand: &T) -> Result<(),DharaError> {
+    let log2_page_size = nand.get_log2_page_size();
+    if !N.is_power_of_two() || log2_page_size >= usize::BITS as u8
+            || N != 1usize << log2_page_size {
+        return Err(DharaError::BadGeometry);
     }
+    Ok(())
 }
 
-// Calculate a checkpoint period: the largest value of ppc such that
-// (2**ppc - 1) metadata blocks can fit on a page with one journal header.
-fn choose_ppc(log2_psize: u8, max: u8) -> u8 {
+// journal_capacity() for a chip of this geometry, with max_bad blocks
+// expected to go bad.
+// Until the journal has been all the way round the chip and counted, allow
+// for 1 block in 64 (about 1.5%) being bad, in line with the worst case
+// most datasheets give for blocks bad from the factory or going bad.
+pub(crate) fn initial_bad_guess(num_blocks: u32) -> DharaBlock {
+    num_blocks >> 6
+}
+
+// The bad block estimate comes off a checkpoint, so it can be anything;
+// an estimate leaving no good blocks is zero capacity, not an underflow.
+// A chip with more pages than a DharaPage can count saturates.
+pub(crate) fn capacity_for(num_blocks: u32, log2_ppb: u8, log2_ppc: u8, max_bad: DharaBlock) -> DharaPage {
+    let good_blocks: DharaBlock = num_blocks.saturating_sub(max_bad).saturating_sub(1);
+    let log2_cpb = log2_ppb.saturating_sub(log2_ppc);
+    let good_cps: DharaPage = good_blocks.saturating_mul(1 << log2_cpb);
+
+    // Good checkpoints * (checkpoint period -1)
+    good_cps.saturating_mul((1 << log2_ppc) - 1)
+}
+
+// Read into buf until two reads in a row agree. The second read of each
+// pair is compared in pieces, so no second buffer is needed.
+fn read_twice<T: DharaNandRead>(nand: &mut T, page: DharaPage, offset: usize, buf: &mut [u8]) -> Result<(),DharaError> {
+    let mut check = [0u8; 32];
+
+    'retry: for _ in 0..DHARA_MAX_RETRIES {
+        nand.read_page(page, offset, buf.len(), buf)?;
+
+        let mut pos = 0;
+        while pos < buf.len() {
+            let len = check.len().min(buf.len() - pos);
+            nand.read_page(page, offset + pos, len, &mut check[..len])?;
+            if check[..len] != buf[pos..pos+len] {
+                continue 'retry;
+            }
+            pos += len;
+        }
+        return Ok(());
+    }
+    Err(DharaError::ECC)
+}
+
+/// Calculate the log2 of the checkpoint period for a given geometry: the
+/// largest value of ppc such that (2**ppc - 1) metadata blocks can fit on a
+/// page with one journal header, but no more than a block's worth of pages.
+/// One page in every 2**ppc holds metadata rather than user data, so this
+/// predicts the journal's overhead before building one.
+///
+/// ```
+/// use dhara_rs::journal::choose_ppc;
+///
+/// // 2 KiB pages, 64 pages per block: a checkpoint every 16 pages.
+/// assert_eq!(choose_ppc(11, 6), 4);
+/// // 512 byte pages, 8 pages per block: a checkpoint every 4 pages.
+/// assert_eq!(choose_ppc(9, 3), 2);
+/// ```
+pub fn choose_ppc(log2_psize: u8, max: u8) -> u8 {
     let max_meta: usize = (1 << log2_psize)
         - DHARA_HEADER_SIZE - DHARA_COOKIE_SIZE;
     let mut total_meta: usize = DHARA_META_SIZE;
@@ -1107,6 +2097,26 @@ mod tests {
         // fn thaw(&mut self) -> () {()}
     }
 
+    // As many pages as a DharaPage can number, near enough: the last
+    // block ends 64 pages short of 2**32.
+    struct HugeNand {}
+
+    impl DharaNand for HugeNand {
+        fn get_log2_page_size(&self) -> u8 {11}
+        fn get_log2_ppb(&self) -> u8 {6}
+        fn get_num_blocks(&self) -> u32 {(1 << 26) - 1}
+        fn is_bad(&mut self, _blk: DharaBlock) -> bool {false}
+        fn is_free(&mut self, _page: DharaPage) -> bool {true}
+        fn mark_bad(&mut self, _blk: DharaBlock) -> () {()}
+        fn read(&mut self, _page: u32, _offset: usize, _length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+            data.fill(0x55);
+            Ok(())
+        }
+        fn erase(&mut self, _blk: DharaBlock) -> Result<(),DharaError> {Ok(())}
+        fn copy(&mut self, _src: DharaPage, _dst: DharaPage) -> Result<(),DharaError> {Ok(())}
+        fn prog(&mut self, _page: DharaPage, _data: &[u8]) -> Result<(),DharaError> {Ok(())}
+    }
+
     fn make_journal() -> DharaJournal::<512, SimpleNand> {
         let nand: SimpleNand = SimpleNand{};
         let buf: [u8; 512] = [0u8; 512]; // We start it with 0, but it gets changed to 0xFF when initialized.
@@ -1143,18 +2153,46 @@ mod tests {
         j.hdr_set_bb_last(0xAA558920);
         assert_eq!(j.hdr_get_bb_last(), 0xAA558920);
 
-        // clear user
-        // TODO: is there a way we can test clear_user()?
 
         // hdr_usr_offset
-        assert_eq!(j.hdr_user_offset(2), 16+4+2*132);
+        assert_eq!(j.hdr_user_offset(2), DHARA_HEADER_SIZE+4+2*132);
+    }
+
+    #[test]
+    fn capacity_cache() -> () {
+        let mut j = make_journal();
+        let initial = j.journal_capacity();
+        assert_eq!(initial, j.compute_capacity());
+
+        // Bad blocks behind the head, on either side of a wrap. Only once
+        // both counters have some does the estimate change.
+        j.head = 5 << j.nand.get_log2_ppb();
+        j.journal_mark_bad(2);
+        assert_eq!(j.journal_capacity(), j.compute_capacity());
+        j.roll_stats();
+        assert_eq!(j.journal_capacity(), j.compute_capacity());
+        j.journal_mark_bad(3);
+        assert_eq!(j.journal_capacity(), j.compute_capacity());
+        assert!(j.journal_capacity() < initial);
+
+        j.reset_journal();
+        assert_eq!(j.journal_capacity(), initial);
     }
 
     #[test]
-    #[should_panic]
-    fn clear_too_much() -> () {
+    fn clear_user() -> () {
         let mut j = make_journal();
-        j.hdr_clear_user(10);  // Clears 1024 bytes rather than 512.
+        j.page_buf.fill(0);
+        j.hdr_clear_user();
+
+        // Header and cookie untouched, metadata slots cleared, user
+        // cookie untouched.
+        let slots = DHARA_HEADER_SIZE + DHARA_COOKIE_SIZE;
+        let end = j.hdr_user_offset(3);
+        assert!(j.page_buf[..slots].iter().all(|&b| b == 0));
+        assert!(j.page_buf[slots..end].iter().all(|&b| b == 0xFF));
+        assert!(j.page_buf[end..].iter().all(|&b| b == 0));
+        assert_eq!(j.user_cookie_size(), 512 - end);
     }
 
     #[test]
@@ -1178,4 +2216,67 @@ mod tests {
         assert_eq!(j.next_upage(14), 16); // 15 user pages, then journal, so next is #16.
     }
 
+    #[test]
+    fn geometry_boundaries() -> () {
+        let j = make_journal();
+        assert_eq!(j.next_block(14), 15);
+        assert_eq!(j.next_upage(125), 126);
+        assert_eq!(j.next_upage(126), 0); // Last user page; 127 is metadata.
+
+        let j = DharaJournal::<2048, HugeNand>::new(HugeNand {}, [0u8; 2048]);
+        let chip = j.nand.total_pages();
+        assert_eq!(chip, u32::MAX - 63);
+        assert_eq!(j.log2_ppc, 4);
+
+        let last_block = j.nand.get_num_blocks() - 1;
+        assert_eq!(j.next_block(last_block - 1), last_block);
+        assert_eq!(j.next_block(last_block), 0);
+
+        // Before a group's metadata page, within the chip and at its end.
+        assert!(j.is_meta_page(chip - 17));
+        assert_eq!(j.next_upage(chip - 19), chip - 18);
+        assert_eq!(j.next_upage(chip - 18), chip - 16);
+        assert!(j.is_meta_page(chip - 1));
+        assert_eq!(j.next_upage(chip - 3), chip - 2);
+        assert_eq!(j.next_upage(chip - 2), 0);
+    }
+
+    #[test]
+    fn capacity_extremes() -> () {
+        // 16 blocks of 8 pages, in groups of 4: 3 user pages per group.
+        assert_eq!(capacity_for(16, 3, 2, 0), 15 * 2 * 3);
+        assert_eq!(capacity_for(16, 3, 2, 14), 2 * 3);
+        assert_eq!(capacity_for(16, 3, 2, 15), 0);
+        assert_eq!(capacity_for(16, 3, 2, 16), 0);
+        assert_eq!(capacity_for(16, 3, 2, u32::MAX), 0);
+        assert_eq!(capacity_for(0, 3, 2, 0), 0);
+        assert_eq!(capacity_for(u32::MAX, 31, 2, 0), u32::MAX);
+
+        // As an estimate restored from a damaged checkpoint might be.
+        let mut j = make_journal();
+        for bad in [15, 16, 1000, u32::MAX] {
+            j.bb_last = bad;
+            j.bb_current = bad;
+            j.capacity.set(None);
+            assert_eq!(j.journal_capacity(), 0);
+        }
+    }
+
+    #[test]
+    fn capacity_larger_bad_count() -> () {
+        // 16 blocks of 8 pages, in groups of 4: 3 user pages per group.
+        let mut j = make_journal();
+        for (last, current) in [(5, 2), (2, 5), (0, 5), (5, 0)] {
+            j.bb_last = last;
+            j.bb_current = current;
+            j.capacity.set(None);
+            assert_eq!(j.journal_capacity(), capacity_for(16, 3, 2, 5));
+        }
+
+        // A new journal allows for its guess before counting any.
+        j.reset_journal();
+        assert_eq!(j.bb_current, 0);
+        assert_eq!(j.journal_capacity(), capacity_for(16, 3, 2, initial_bad_guess(16)));
+    }
+
 }
@@ -1,11 +1,35 @@
+// The library itself needs neither std nor an allocator. The alloc
+// feature only adds conveniences for host-side tools.
+#![cfg_attr(not(test), no_std)]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+pub mod builder;
 pub mod bytes;
+pub mod cache;
+pub mod counting;
+pub mod geometry;
 pub mod journal;
 pub mod nand;
-
+#[cfg(feature = "alloc")]
+pub mod replay;
+pub mod subdivided;
+pub mod subpage;
+pub mod view;
+pub mod wear;
+pub mod writer;
+
+use core::borrow::BorrowMut;
+use core::fmt;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
 use bytes::{dhara_r32, dhara_w32};
-use journal::{DharaJournal, DHARA_MAX_RETRIES, DHARA_META_SIZE, DHARA_PAGE_NONE};
-use nand::{DharaNand, DharaPage};
+use cache::{CacheKey, FindCache};
+use journal::{capacity_for, check_page_size, choose_ppc, initial_bad_guess, DharaJournal, DHARA_MAX_RETRIES, DHARA_META_SIZE, DHARA_PAGE_NONE};
+use nand::{DharaBlock, DharaNand, DharaNandRead, DharaPage};
+use view::ReadOnlyMap;
+use writer::SectorWriter;
 
 // Types
 
@@ -14,11 +38,14 @@ use nand::{DharaNand, DharaPage};
 pub type DharaSector = u32;
 
 // Constants
-// This sector value is reserved.
-const DHARA_SECTOR_NONE: DharaSector = 0xffffffff;  // TODO: if we have Option/Result return types, do we need this?
-const DHARA_RADIX_DEPTH: usize = size_of::<DharaSector>() << 3;
-
-// TODO: possible move to a new module, to include human-readable functions.
+/// This sector value is reserved. Metadata uses it to mark filler pages
+/// (and the on-NAND format is shared with the C code), so it can never be
+/// mapped: writes to it fail with DharaError::BadSector, and it always
+/// reads as unmapped.
+pub const DHARA_SECTOR_NONE: DharaSector = 0xffffffff;
+pub(crate) const DHARA_RADIX_DEPTH: usize = size_of::<DharaSector>() << 3;
+
+// TODO: possible move to a new module.
 #[derive(Debug,PartialEq)]
 pub enum DharaError {
     BadBlock,
@@ -29,24 +56,180 @@ pub enum DharaError {
     NotFound,
     MapFull,
     CorruptMap,
+    Blank,      // No map was found at all; the chip looks unformatted.
+    BadLength,  // A buffer wasn't the size the operation needs.
+    BadGeometry,// The page buffer doesn't match the NAND's page size.
+    BadSector,  // The sector number is reserved (DHARA_SECTOR_NONE).
+    BadConfig,  // Map options that can't work together; see MapBuilder.
+    Cancelled,  // The cancel check asked to stop; see set_cancel_check().
+    VersionMismatch, // The chip was written in another on-flash format.
     Max,        // TODO: do we need "max", because Rust knows how many are in an enum?
 }
 
+impl DharaError {
+    /// A short description, for logging. Needs neither std nor an
+    /// allocator. The wording follows dhara_strerror() in the C code where
+    /// it has the same error.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DharaError::BadBlock => "Bad page/eraseblock",
+            DharaError::ECC => "ECC failure",
+            DharaError::TooBad => "Too many bad blocks",
+            DharaError::Recover => "Journal recovery is required",
+            DharaError::JournalFull => "Journal is full",
+            DharaError::NotFound => "No such sector",
+            DharaError::MapFull => "Sector map is full",
+            DharaError::CorruptMap => "Sector map is corrupted",
+            DharaError::Blank => "No map found on the chip",
+            DharaError::BadLength => "Buffer is the wrong size",
+            DharaError::BadGeometry => "Page buffer doesn't match the page size",
+            DharaError::BadSector => "Sector number is reserved",
+            DharaError::BadConfig => "Map options can't work together",
+            DharaError::Cancelled => "Operation cancelled",
+            DharaError::VersionMismatch => "Chip was written in another format",
+            DharaError::Max => "Unknown error",
+        }
+    }
+}
+
+/// What resume_or_format() found on the chip.
+#[derive(Debug,PartialEq)]
+pub enum ResumeOutcome {
+    /// Existing map state was restored.
+    Resumed,
+    /// Nothing usable was found, so an empty map was started.
+    Formatted,
+}
+
+/// Where recover_step() has got to.
+#[derive(Debug,PartialEq)]
+pub enum RecoverState {
+    /// There is more to do; call recover_step() again.
+    InProgress,
+    /// The journal is out of recovery (or never was in it).
+    Done,
+}
+
+/// Where a mapped sector currently lives, from stat().
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct SectorStat {
+    /// The physical page holding the sector's data.
+    pub page: DharaPage,
+    /// The erase block containing that page.
+    pub block: DharaBlock,
+    /// The checkpoint group containing that page, counted from the start
+    /// of the chip. Its metadata lives in the group's last page.
+    pub checkpoint_group: DharaPage,
+}
+
+/// Which sector write_all() stopped at, and why. Sectors before it were
+/// written; it and those after it weren't.
+#[derive(Debug,PartialEq)]
+pub struct WriteAllError {
+    pub sector: DharaSector,
+    pub error: DharaError,
+}
+
+// So that ? can pass it up as a plain DharaError when the sector isn't
+// needed.
+impl From<WriteAllError> for DharaError {
+    fn from(e: WriteAllError) -> DharaError {
+        e.error
+    }
+}
+
+/// What scrub() did.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct ScrubReport {
+    /// Sectors rewritten because their pages needed too much correction.
+    pub refreshed: usize,
+    /// Blocks marked bad because rewriting couldn't help.
+    pub retired: usize,
+}
+
+/// A point in the journal's history, from snapshot(), for finding what
+/// has been written since with iter_changes_since(). Plain data, so it can
+/// be stored alongside a backup and used after a reboot.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Snapshot {
+    /// The journal's epoch, counting passes round the chip.
+    pub epoch: u8,
+    /// The next page the journal was going to write.
+    pub head: DharaPage,
+}
+
+/// How the journal's pages are shared out, from capacity_breakdown().
+/// gc_reserve + safety_margin + usable == raw.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct CapacityBreakdown {
+    /// The journal's capacity: user pages on the good blocks.
+    pub raw: DharaPage,
+    /// Kept free so garbage collection always has room to work;
+    /// raw / (gc_ratio + 1).
+    pub gc_reserve: DharaPage,
+    /// The blocks from set_safety_margin(), in pages, or whatever is left
+    /// if that's less.
+    pub safety_margin: DharaPage,
+    /// What's left for sectors; get_capacity().
+    pub usable: DharaSector,
+}
+
 /// Generics:
 /// N: The number of bytes on a NAND flash page.
-pub struct DharaMap<const N: usize,T: DharaNand> {
+/// B: How the page buffer is held; see new().
+pub struct DharaMap<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]> = [u8; N]> {
     // TODO: Journal is public so that tests can reach in and examine it.
     //       Change that somehow?
-    pub journal: DharaJournal<N,T>,
+    pub journal: DharaJournal<N,T,B>,
     gc_ratio: u8,
     count: DharaSector,
+    // Blocks held back from get_capacity() so recovery has room to work.
+    safety_blocks: u32,
+    // Restarts allowed before recover_step() gives up.
+    max_retries: u8,
+    // Successful write() and copy_page() calls, set against the NAND's
+    // program count to estimate write amplification.
+    user_writes: usize,
+    find_cache: FindCache,
+    // Metadata buffers borrowed by the tree walks, rather than each call
+    // putting its own on the stack. walk_meta holds the node being
+    // visited; path_meta is where trace_path() builds the new path. Only
+    // write() and copy_page() still keep their own copy, since garbage
+    // collection can run (and reuse both) while they hold it. On the
+    // deepest chain, write() through gc() to trace_path(), this saves two
+    // of three DHARA_META_SIZE arrays, 264 bytes; find() and trim() no
+    // longer put any on the stack, where they had two.
+    walk_meta: [u8; DHARA_META_SIZE],
+    path_meta: [u8; DHARA_META_SIZE],
+    // Times recovery has had to start over because of a further failure,
+    // and steps taken, in all attempts. Kept here so that recover_step()
+    // can give up across calls.
+    recover_restarts: u8,
+    recover_steps: u32,
+    // Looked at between steps of long operations, to see whether to give up.
+    cancel_check: Option<&'static AtomicBool>,
+    // Sync after every write() and trim(); see set_auto_sync().
+    auto_sync: bool,
+    // Dequeue garbage at the tail on resume; see set_reclaim_slack().
+    reclaim_slack: bool,
+}
+
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> fmt::Debug for DharaMap<N,T,B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DharaMap")
+            .field("count", &self.count)
+            .field("gc_ratio", &self.gc_ratio)
+            .field("safety_blocks", &self.safety_blocks)
+            .field("journal", &self.journal)
+            .finish_non_exhaustive()
+    }
 }
 
 // ///////////////////////////////////////////////////////////////////////
 // Public interface
 // ///////////////////////////////////////////////////////////////////////
 //
-impl<const N: usize,T: DharaNand> DharaMap<N,T> {
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> DharaMap<N,T,B> {
     // The original "init" was renamed "new" to match common Rust usage.
 
     /// Initialize a map. You need to supply 
@@ -54,31 +237,58 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     ///     It must have a page size that matches the constant generic N.
     /// 
     /// page_buf: A buffer of size N that the journal uses to hold page
-    ///     metadata. The buffer will be owned by the map and its journal.
+    ///     metadata. Either the buffer itself, which the map then owns, or
+    ///     a &'static mut to one, so it can live wherever you place it.
     /// 
     /// gc_ratio: a garbage collection ratio. This is the ratio of garbage
     ///     collection operations to real writes when automatic collection is
     ///     active. Smaller values lead to faster and more predictable IO, at
     ///     the expense of capacity. You should always initialize the same 
     ///     chip with the same garbage collection ratio.
-    pub fn new(nand: T, page_buf: [u8; N], gc_ratio: u8) -> Self {
+    pub fn new(nand: T, page_buf: B, gc_ratio: u8) -> Self {
         let mut ratio: u8 = gc_ratio;
         if ratio == 0 {
             ratio = 1;
         }
 
-        let journal = DharaJournal::<N,T>::new(nand, page_buf);
+        let journal = DharaJournal::<N,T,B>::new(nand, page_buf);
         
         DharaMap {
             journal: journal,
             gc_ratio: ratio,
             count: 0, // This will get updated when resume() is called.
+            safety_blocks: DHARA_MAX_RETRIES as u32,
+            max_retries: DHARA_MAX_RETRIES,
+            user_writes: 0,
+            find_cache: FindCache::new(),
+            walk_meta: [0u8; DHARA_META_SIZE],
+            path_meta: [0u8; DHARA_META_SIZE],
+            recover_restarts: 0,
+            recover_steps: 0,
+            cancel_check: None,
+            auto_sync: false,
+            reclaim_slack: false,
         }
     }
 
+    /// Like new(), but first checks that N is the NAND's page size (which
+    /// must be a power of two), returning DharaError::BadGeometry if not.
+    /// A mismatch would otherwise go unnoticed and corrupt data.
+    pub fn try_new(nand: T, page_buf: B, gc_ratio: u8) -> Result<Self, DharaError> {
+        check_page_size::<N,T>(&nand)?;
+        Ok(Self::new(nand, page_buf, gc_ratio))
+    }
+
     /// Recover stored state, if possible. If there is no valid stored state
     /// on the chip, an error is returned, and an empty map is initialized.
+    ///
+    /// Resuming a map costs O(log N) page reads, N being the pages on the
+    /// chip. A chip with no checkpoint near the start is the exception:
+    /// before it's reported as DharaError::Blank, every page of every good
+    /// block is checked with is_free(), stopping at the first programmed
+    /// one. On a new chip that's a full scan, once, on first boot.
     pub fn resume(&mut self) -> Result<(), DharaError> {
+        self.find_cache.invalidate();
         match self.journal.journal_resume() {
             Err(e) => {
                 self.count = 0;
@@ -86,12 +296,88 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
             },
             Ok(_) => {
                 self.count = self.journal.get_cookie();
+                if self.reclaim_slack {
+                    self.reclaim_garbage_at_tail();
+                }
                 Ok(())
             },
         }
     }
 
-    /// Clear the map (delete all sectors).
+    /// Like resume(), but with a bad block table kept by the caller to
+    /// improve the capacity estimate. Until the journal has been round the
+    /// chip once, it can only guess at how many blocks are bad; when resume
+    /// leaves an empty map, the number of distinct blocks listed is used
+    /// instead. After a successful resume, the checkpoint's own count is
+    /// only ever raised to it, since the journal may since have found more.
+    /// Blocks out of range are ignored. Nothing is marked bad; is_bad()
+    /// still decides which blocks the journal skips.
+    pub fn resume_with_bbt(&mut self, known_bad: &[DharaBlock]) -> Result<(), DharaError> {
+        let result = self.resume();
+
+        let num_blocks = self.journal.nand.get_num_blocks();
+        let known = known_bad.iter().enumerate()
+            .filter(|&(i, &blk)| blk < num_blocks && !known_bad[..i].contains(&blk))
+            .count() as DharaBlock;
+        match result {
+            Ok(_) if known <= self.journal.get_bb_last() => (),
+            _ => self.journal.set_bad_block_estimate(known),
+        }
+        result
+    }
+
+    /// Like resume(), but then count the mapped sectors with count_exact()
+    /// and compare with the count restored from the checkpoint cookie. If
+    /// they disagree (a checkpoint written by code that didn't keep the
+    /// cookie up to date, say), the counted value is used, and true is
+    /// returned. The journal is left dirty, so the corrected count is made
+    /// durable by the next sync().
+    ///
+    /// This walks the whole map, so it costs a find for every sector.
+    pub fn resume_verify_count(&mut self) -> Result<bool, DharaError> {
+        self.resume()?;
+
+        let counted = self.count_exact()?;
+        if counted == self.count {
+            return Ok(false);
+        }
+        self.count = counted;
+        self.journal.set_cookie(counted);
+        self.journal.journal_mark_dirty();
+        Ok(true)
+    }
+
+    /// Like resume(), but a blank chip isn't an error: an empty map is
+    /// started instead, and the outcome says which happened. Only other
+    /// errors are returned, including for a chip that holds something
+    /// that can't be read as a map, which may be one that's damaged.
+    pub fn resume_or_format(&mut self) -> Result<ResumeOutcome, DharaError> {
+        match self.resume() {
+            Ok(_) => Ok(ResumeOutcome::Resumed),
+            // No map was ever written here. resume() has already left us
+            // with an empty map. A map that exists but is damaged
+            // (CorruptMap), or that can't be found on a chip that isn't
+            // blank (TooBad), is returned, since starting over would
+            // silently throw away whatever is still recoverable.
+            Err(DharaError::Blank) => Ok(ResumeOutcome::Formatted),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Experimental: roll the map back to the state it was in at an
+    /// earlier checkpoint, 0 being the last one. Every sync() writes one,
+    /// but so does each checkpoint group's worth of writes in between. See
+    /// DharaJournal::rewind() for how far back this can go. Like other
+    /// changes, it isn't durable until the next sync().
+    pub fn rewind(&mut self, checkpoints_back: u32) -> Result<(), DharaError> {
+        self.find_cache.invalidate();
+        self.journal.rewind(checkpoints_back)?;
+        self.count = self.journal.get_cookie();
+        Ok(())
+    }
+
+    /// Clear the map (delete all sectors). Like other changes, this isn't
+    /// durable until the next sync(); see trim_all().
     pub fn clear(&mut self) -> () {
         if self.count != 0 {
             self.count = 0;
@@ -99,6 +385,40 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
         }
     }
 
+    /// Provision a chip: erase every block, marking bad any whose erase
+    /// fails, and start an empty map. Returns the number of bad blocks
+    /// found. Anything already on the chip is lost.
+    ///
+    /// Fails with DharaError::TooBad if the bad blocks leave no capacity
+    /// for sectors. The empty map isn't written until the first sync() (or
+    /// write that fills a checkpoint group), so resume() in between sees a
+    /// blank chip, which resume_or_format() accepts.
+    pub fn format(&mut self) -> Result<DharaBlock, DharaError> {
+        self.count = 0;
+        self.recover_restarts = 0;
+        self.recover_steps = 0;
+        self.find_cache.invalidate();
+        let bad = self.journal.journal_format()?;
+
+        if self.get_capacity() == 0 {
+            return Err(DharaError::TooBad);
+        }
+        Ok(bad)
+    }
+
+    /// Clear the map and make the space it used available right away.
+    /// After clear(), old blocks can't be reused until the next checkpoint
+    /// lands, because until then a power failure would bring them back.
+    /// This writes that checkpoint immediately, so the clear is also
+    /// durable once it returns. Unlike clear(), any error writing the
+    /// checkpoint is returned.
+    pub fn trim_all(&mut self) -> Result<(), DharaError> {
+        self.count = 0;
+        // Even an empty map can have a journal full of garbage.
+        self.journal.journal_clear();
+        self.sync()
+    }
+
     // Renamed functions from dhara_map_capacity() and dhara_map_size()
     // to get_capacity() and get_size() to reflect their actions.
 
@@ -106,70 +426,679 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     /// This might be zero if amounts reserved for garbage collection
     /// and a safety margin exceed the journal's capacity.
     pub fn get_capacity(&self) -> DharaSector {
-        let cap = self.journal.journal_capacity();
-        let reserve = cap / (self.gc_ratio as u32 + 1);
-        let safety_margin = (DHARA_MAX_RETRIES as u32) << self.journal.nand.get_log2_ppb();
+        map_capacity(self.journal.journal_capacity(), self.gc_ratio,
+            self.safety_blocks, self.journal.nand.get_log2_ppb())
+    }
 
-        cap.saturating_sub(reserve + safety_margin)
+    /// Whether a new sector could ever be written: false when
+    /// get_capacity() is zero, e.g. on a chip too small for the safety
+    /// margin. Nothing breaks in that state; writes of new sectors just
+    /// fail with DharaError::MapFull, and reads see every sector blank.
+    /// It's worked out afresh each time, since bad blocks and
+    /// set_safety_margin() move the capacity after construction.
+    pub fn is_usable(&self) -> bool {
+        self.get_capacity() > 0
     }
 
-    /// Obtain the current number of allocated sectors.
+    /// get_capacity(), with the space it leaves out accounted for.
+    pub fn capacity_breakdown(&self) -> CapacityBreakdown {
+        capacity_breakdown(self.journal.journal_capacity(), self.gc_ratio,
+            self.safety_blocks, self.journal.nand.get_log2_ppb())
+    }
+
+    /// What get_capacity() will say for a new map over a chip of this
+    /// geometry, with the default safety margin, without a NAND driver or
+    /// a map to hand, e.g. for sizing a partition. Like a new map, this
+    /// allows for the journal's initial guess at bad blocks; once it has
+    /// counted them, each takes roughly a block's worth of user pages off.
+    pub fn estimate_capacity(num_blocks: u32, log2_ppb: u8, log2_page_size: u8, gc_ratio: u8) -> DharaSector {
+        let log2_ppc = choose_ppc(log2_page_size, log2_ppb);
+        let cap = capacity_for(num_blocks, log2_ppb, log2_ppc, initial_bad_guess(num_blocks));
+        map_capacity(cap, gc_ratio.max(1), DHARA_MAX_RETRIES as u32, log2_ppb)
+    }
+
+    /// Set how many blocks' worth of pages get_capacity() holds back.
+    /// The default, DHARA_MAX_RETRIES, leaves room for recovery to relocate
+    /// data after a run of failed blocks. Less than that gives more usable
+    /// space, but a full map may then hit JournalFull partway through a
+    /// recovery. Flash that fails often may want more.
+    pub fn set_safety_margin(&mut self, blocks: u32) -> () {
+        self.safety_blocks = blocks;
+    }
+
+    /// The safety margin, in blocks. See set_safety_margin().
+    pub fn get_safety_margin(&self) -> u32 {
+        self.safety_blocks
+    }
+
+    /// The garbage collection ratio the map was created with.
+    pub fn get_gc_ratio(&self) -> u8 {
+        self.gc_ratio
+    }
+
+    /// Register a flag looked at between the steps of anything that can
+    /// run long (the retries in write() and copy_page(), each garbage
+    /// collection step, sync(), compact() and recovery). Once it is set,
+    /// the operation gives up with DharaError::Cancelled. A real-time loop
+    /// can use it to bound the time spent in the map, with the flag set by
+    /// a timer interrupt or another task. The map never clears it. Pass
+    /// None to stop looking.
+    ///
+    /// What has been done so far stays done, and the map stays consistent,
+    /// but only what reached a checkpoint is durable; a cancelled write
+    /// didn't happen. If cancelled during recovery (see
+    /// DharaJournal::journal_in_recovery()), finish it with recover_step()
+    /// before anything else, or resume().
+    pub fn set_cancel_check(&mut self, check: Option<&'static AtomicBool>) -> () {
+        self.cancel_check = check;
+    }
+
+    /// The number of pages written at the user's request (by write(),
+    /// copy_page() and the functions built on them) since the map was
+    /// created or reset_user_writes() was called. Garbage collection,
+    /// recovery and checkpoints aren't included.
+    pub fn get_user_writes(&self) -> usize {
+        self.user_writes
+    }
+
+    /// Start counting user writes again from zero.
+    pub fn reset_user_writes(&mut self) -> () {
+        self.user_writes = 0;
+    }
+
+    /// The application's own durable state, kept in the checkpoints beside
+    /// the sector count. See DharaJournal::get_user_cookie().
+    pub fn get_user_cookie(&self) -> &[u8] {
+        self.journal.get_user_cookie()
+    }
+
+    /// Replace the user cookie. It becomes durable with the next sync()
+    /// (or checkpoint), and resume() restores it. Data longer than
+    /// DharaJournal::user_cookie_size() gives DharaError::BadLength.
+    pub fn set_user_cookie(&mut self, data: &[u8]) -> Result<(), DharaError> {
+        self.journal.set_user_cookie(data)
+    }
+
+    /// Obtain the current number of allocated sectors. This is the logical
+    /// size; see size_on_flash() for the space actually taken.
     pub fn get_size(&self) -> DharaSector {
         self.count
     }
 
+    /// Obtain the number of user pages the journal is taking up on flash:
+    /// live sectors, plus stale copies of them not yet collected, plus
+    /// pages lost to bad blocks. This is the physical size, and it's what
+    /// garbage collection works to keep under get_capacity(); get_size()
+    /// can stay small while this grows. Checkpoint pages aren't counted.
+    pub fn size_on_flash(&self) -> DharaPage {
+        self.journal.journal_size()
+    }
+
     /// Find the physical page which holds the current data for this sector.
     /// If the sector does not exist, the error will be DharaError::NotFound.
     pub fn find(&mut self, target: DharaSector) -> Result<DharaPage, DharaError> {
-        let mut unused: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
-        self.trace_path(target, &mut unused)
+        let key = self.cache_key();
+        if let Some(page) = self.find_cache.get(key, target) {
+            return Ok(page);
+        }
+
+        let page = self.trace_path(target)?;
+        self.find_cache.insert(key, target, page);
+        Ok(page)
     }
 
-    /// Read from the given logical sector. If the sector is unmapped, a
-    /// blank page (0xff) will be returned.
-    /// TODO: Should we say anything about the size of the slice?
-    pub fn read(&mut self, sector: DharaSector, data: &mut [u8]) -> Result<(), DharaError> {
+    /// A view for looking sectors up and reading them with the map only
+    /// borrowed immutably, reading the NAND through the handle given
+    /// instead of the map's own driver. See view::ReadOnlyMap.
+    pub fn read_only<'a,R: DharaNandRead>(&'a self, nand: &'a mut R) -> ReadOnlyMap<'a,N,T,B,R> {
+        ReadOnlyMap::new(self, nand)
+    }
+
+    /// Turn on (or off) a cache of the last few sectors found, up to
+    /// cache::DHARA_FIND_CACHE_SIZE of them, so that repeated find() and
+    /// read() calls on the same sectors skip the NAND reads of a tree walk.
+    /// Off by default. Anything that changes the journal empties it.
+    pub fn set_find_cache(&mut self, enabled: bool) -> () {
+        self.find_cache.set_enabled(enabled);
+    }
+
+    /// Whether the find cache is on. See set_find_cache().
+    pub fn get_find_cache(&self) -> bool {
+        self.find_cache.is_enabled()
+    }
+
+    /// Sync after every write() and trim(), so each is durable once it
+    /// returns, without the caller having to sync(). Off by default.
+    ///
+    /// This costs wear. Without it, a checkpoint group's metadata page is
+    /// shared by its 2**log2_ppc - 1 user pages; each sync pads out the
+    /// rest of the group, so with it every write programs a whole group.
+    /// With 4-page groups, that's 4 pages programmed per write rather than
+    /// 4/3, and erases go up to match. Where only some writes need to be
+    /// durable, sync() after just those instead.
+    pub fn set_auto_sync(&mut self, enabled: bool) -> () {
+        self.auto_sync = enabled;
+    }
+
+    /// Whether auto sync is on. See set_auto_sync().
+    pub fn get_auto_sync(&self) -> bool {
+        self.auto_sync
+    }
+
+    /// Have resume() dequeue the garbage at the tail of the journal: the
+    /// pages up to the first one still holding a live sector. The journal
+    /// is clean straight after a resume, so their space is free at once,
+    /// rather than once garbage collection gets to them. This costs a
+    /// metadata read and a tree walk per page, which resume() otherwise
+    /// avoids. Off by default.
+    pub fn set_reclaim_slack(&mut self, enabled: bool) -> () {
+        self.reclaim_slack = enabled;
+    }
+
+    /// Whether resume() reclaims garbage. See set_reclaim_slack().
+    pub fn get_reclaim_slack(&self) -> bool {
+        self.reclaim_slack
+    }
+
+    /// Find where a sector is stored, or None if it isn't mapped.
+    pub fn stat(&mut self, sector: DharaSector) -> Result<Option<SectorStat>, DharaError> {
         match self.find(sector) {
-            Err(DharaError::NotFound) => {
-                data.fill(0xFF);
-                Ok(())
-            },
+            Err(DharaError::NotFound) => Ok(None),
             Err(e) => Err(e),
-            Ok(page) => self.journal.nand.read(page, 0, 1usize << self.journal.nand.get_log2_page_size(), data),
+            Ok(page) => Ok(Some(SectorStat {
+                page: page,
+                block: page >> self.journal.nand.get_log2_ppb(),
+                checkpoint_group: page >> self.journal.get_log2_ppc(),
+            })),
+        }
+    }
+
+    /// The erase block holding a sector's data, or None if it isn't
+    /// mapped, as a locality hint for ordering accesses. It goes through
+    /// the find cache, so with that on, a recently found sector costs no
+    /// NAND reads. For now the answer is exact, agreeing with stat(), but
+    /// it's only promised to be a hint: the data may move at the next
+    /// write or garbage collection anyway.
+    pub fn hint_block(&mut self, sector: DharaSector) -> Result<Option<DharaBlock>, DharaError> {
+        match self.find(sector) {
+            Err(DharaError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+            Ok(page) => Ok(Some(page >> self.journal.nand.get_log2_ppb())),
+        }
+    }
+
+    /// Iterate over the mapped sectors in ascending order. Each step is a
+    /// find_nearest(), so the map can't be changed while this is running.
+    /// Iteration stops after the first error.
+    pub fn sectors(&mut self) -> MapSectors<'_,N,T,B> {
+        MapSectors {
+            map: self,
+            next: Some(0),
+        }
+    }
+
+    /// Count the mapped sectors by walking the map, rather than trusting
+    /// get_size(). Each sector costs a find_nearest().
+    pub fn count_exact(&mut self) -> Result<DharaSector, DharaError> {
+        let mut n = 0;
+        for sector in self.sectors() {
+            sector?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Record where the journal has got to, so that iter_changes_since()
+    /// can later list the sectors written after this point.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            epoch: self.journal.get_epoch(),
+            head: self.journal.get_head(),
+        }
+    }
+
+    /// Iterate, in ascending order, over the mapped sectors whose current
+    /// data was written after the snapshot was taken, e.g. for an
+    /// incremental backup. Sectors trimmed since aren't listed, so compare
+    /// with sectors() to find those.
+    ///
+    /// What counts is where the data now sits, not whether it changed: a
+    /// sector moved since, by garbage collection or by the trim of a
+    /// neighbour, is listed too, even though it wasn't rewritten. A
+    /// snapshot older than the journal's live window (after the head has
+    /// been round the chip) lists everything. Epochs wrap, so one more than
+    /// 127 passes old is misread. Iteration stops after the first error.
+    pub fn iter_changes_since(&mut self, snapshot: Snapshot) -> MapChanges<'_,N,T,B> {
+        MapChanges {
+            sectors: self.sectors(),
+            snapshot: snapshot,
+        }
+    }
+
+    /// Set one bit in out for each sector in [start, start + count): bit
+    /// i % 8 of out[i / 8] (least significant first) says whether sector
+    /// start + i is mapped, e.g. for checking a filesystem's allocation
+    /// bitmap against the map after a crash. out must have at least
+    /// ceil(count / 8) bytes, or DharaError::BadLength is returned; only
+    /// those are written. Costs a find_nearest() per mapped sector in the
+    /// range, plus one.
+    pub fn existence_bitmap(&mut self, start: DharaSector, count: usize, out: &mut [u8]) -> Result<(), DharaError> {
+        let bytes = count.div_ceil(8);
+        if out.len() < bytes {
+            return Err(DharaError::BadLength);
+        }
+        out[..bytes].fill(0);
+
+        let sectors = MapSectors {
+            map: self,
+            next: Some(start),
+        };
+        for sector in sectors {
+            let i = (sector? - start) as usize;
+            if i >= count {
+                break;
+            }
+            out[i / 8] |= 1 << (i % 8);
         }
+        Ok(())
+    }
+
+    /// Iterate over the mapped sectors in ascending order, along with each
+    /// one's data, e.g. to export an image of the map. Iteration stops
+    /// after the first error.
+    pub fn iter_pairs(&mut self) -> MapPairs<'_,N,T,B> {
+        MapPairs {
+            sectors: self.sectors(),
+        }
+    }
+
+    /// Write a sequence of (sector, data) pairs, such as one captured with
+    /// iter_pairs(), into the map, normally a freshly cleared one. The map
+    /// is synced every block's worth of writes and at the end, so that a
+    /// long restore doesn't build up a large unsynced backlog.
+    ///
+    /// The number of pairs is checked against the room left before anything
+    /// is written: if there are more than will fit, nothing is written and
+    /// DharaError::MapFull is returned. That's why the length must be known
+    /// up front; collect a filtered or otherwise unsized iterator first.
+    /// Any other error part way through is returned as soon as it happens,
+    /// with the pairs before it already written.
+    pub fn restore<I>(&mut self, items: I) -> Result<(), DharaError>
+    where
+        I: IntoIterator<Item = (DharaSector, [u8; N])>,
+        I::IntoIter: ExactSizeIterator,
+    {
+        let items = items.into_iter();
+        let room = self.get_capacity().saturating_sub(self.count) as usize;
+        if items.len() > room {
+            return Err(DharaError::MapFull);
+        }
+        self.write_pairs(items)
+    }
+
+    /// Copy every mapped sector of another map into this one, normally an
+    /// empty one, e.g. to migrate to a new device. The two can be over
+    /// different drivers, but must share a page size. Syncs as restore()
+    /// does.
+    ///
+    /// If the source has more sectors than this map has room for, nothing
+    /// is copied and DharaError::MapFull is returned. Sectors this map
+    /// already has count against the room, even if the source has them
+    /// too. An error reading the source stops the copy, with the sectors
+    /// before it copied and synced.
+    pub fn copy_from<U: DharaNand, C: BorrowMut<[u8; N]>>(&mut self, src: &mut DharaMap<N,U,C>) -> Result<(), DharaError> {
+        let room = self.get_capacity().saturating_sub(self.count);
+        if src.get_size() > room {
+            return Err(DharaError::MapFull);
+        }
+
+        let mut read_error = None;
+        let pairs = src.iter_pairs().map_while(|item| match item {
+            Ok(pair) => Some(pair),
+            Err(e) => {
+                read_error = Some(e);
+                None
+            },
+        });
+        self.write_pairs(pairs)?;
+
+        match read_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Find the smallest mapped sector that is >= target, or None if there
+    /// isn't one. Useful for walking a sparse map in sector order.
+    pub fn find_nearest(&mut self, target: DharaSector) -> Result<Option<DharaSector>, DharaError> {
+        let mut depth: usize = 0;
+        let mut p = self.journal.get_root();
+
+        if p == DHARA_PAGE_NONE {
+            return Ok(None);
+        }
+        self.journal.journal_read_meta(p, &mut self.walk_meta)?;
+        if is_filler_meta(&self.walk_meta) {
+            return Ok(None);
+        }
+
+        // Follow the path to target, as trace_path() does. Wherever the
+        // tree branches off with a 1 where target has a 0, everything down
+        // that branch is larger than target. Each such branch found is
+        // closer to target than the ones before it, so only the last one
+        // needs to be remembered: (page, depth its subtree starts at).
+        let mut candidate: Option<(DharaPage, usize)> = None;
+
+        while depth < DHARA_RADIX_DEPTH {
+            let id = meta_get_id(&self.walk_meta);
+            let bit = d_bit(depth);
+
+            if (target ^ id) & bit != 0 {
+                // This node, and the rest of its subtree below here, is on
+                // the wrong side of target. The alt-pointer continues the
+                // path.
+                if target & bit == 0 {
+                    candidate = Some((p, depth + 1));
+                }
+                p = meta_get_alt(&self.walk_meta, depth);
+                if p == DHARA_PAGE_NONE {
+                    break;
+                }
+                self.journal.journal_read_meta(p, &mut self.walk_meta)?;
+            } else if target & bit == 0 {
+                let alt = meta_get_alt(&self.walk_meta, depth);
+                if alt != DHARA_PAGE_NONE {
+                    candidate = Some((alt, depth + 1));
+                }
+            }
+            depth += 1;
+        }
+
+        if depth == DHARA_RADIX_DEPTH {
+            // The path ran all the way down: target itself is mapped.
+            return Ok(Some(target));
+        }
+
+        match candidate {
+            None => Ok(None),
+            Some((page, depth)) => Ok(Some(self.subtree_min(page, depth)?)),
+        }
+    }
+
+    /// Read from the given logical sector. If the sector is unmapped, a
+    /// blank page (0xff) will be returned. data must be exactly one page
+    /// long; anything else gives DharaError::BadLength, having read
+    /// nothing.
+    pub fn read(&mut self, sector: DharaSector, data: &mut [u8]) -> Result<(), DharaError> {
+        self.read_filled(sector, data, 0xFF)
+    }
+
+    /// read(), but an unmapped sector comes back filled with fill rather
+    /// than 0xff. A mapped sector is returned as it is, whatever it holds.
+    pub fn read_filled(&mut self, sector: DharaSector, data: &mut [u8], fill: u8) -> Result<(), DharaError> {
+        self.find_and_read_filled(sector, data, fill).map(|_| ())
+    }
+
+    /// read(), also returning the physical page the data came from, or
+    /// None if the sector is unmapped (and data was filled with 0xff).
+    /// The same as find() then read(), but with one walk of the tree.
+    pub fn find_and_read(&mut self, sector: DharaSector, data: &mut [u8]) -> Result<Option<DharaPage>, DharaError> {
+        self.find_and_read_filled(sector, data, 0xFF)
+    }
+
+    /// Read several sectors at once, requests[i] into out[i], with
+    /// unmapped sectors filled with 0xFF as read() does. Returns
+    /// DharaError::BadLength, having read nothing, unless there is exactly
+    /// one output page per request. Stops at the first failing read.
+    pub fn read_many(&mut self, requests: &[DharaSector], out: &mut [[u8; N]]) -> Result<(), DharaError> {
+        if requests.len() != out.len() {
+            return Err(DharaError::BadLength);
+        }
+
+        // One at a time for now. Keeping the whole batch in one call
+        // leaves room to merge reads of physically adjacent pages later.
+        for (&sector, data) in requests.iter().zip(out.iter_mut()) {
+            self.read(sector, data)?;
+        }
+        Ok(())
+    }
+
+    /// Like read(), but allocates a page-sized buffer for the data.
+    #[cfg(feature = "alloc")]
+    pub fn read_vec(&mut self, sector: DharaSector) -> Result<alloc::vec::Vec<u8>, DharaError> {
+        let mut data = alloc::vec![0u8; self.journal.nand.page_size()];
+        self.read(sector, &mut data)?;
+        Ok(data)
     }
 
     /// Write data to a logical sector.
-    /// TODO: can this be a partial write, or if not, specify that data must be a full page long.
+    /// data must be exactly one page long; anything else is refused with
+    /// DharaError::BadLength before anything is written.
+    /// DHARA_SECTOR_NONE is reserved, and fails with DharaError::BadSector.
+    ///
+    /// DharaError::MapFull means every logical sector is in use, and
+    /// trimming some will make room. If bad blocks have instead left no
+    /// physical space to write or relocate into, the error is
+    /// DharaError::TooBad, never the journal's own JournalFull.
     pub fn write(&mut self, dst: DharaSector, data: &[u8]) -> Result<(), DharaError> {
+        if data.len() != self.journal.nand.page_size() {
+            return Err(DharaError::BadLength);
+        }
         let mut meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
 
         loop {
+            self.cancelled()?;
             let old_count = self.count;
 
-            self.prepare_write(dst, &mut meta)?;
+            self.prepare_write(dst, &mut meta).map_err(journal_full_to_too_bad)?;
 
             match self.journal.journal_enqueue(Some(data), Some(&meta)) {
-                Ok(_) => {return Ok(());},
+                Ok(_) => {
+                    self.user_writes = self.user_writes.wrapping_add(1);
+                    if self.auto_sync {
+                        return self.sync().map_err(journal_full_to_too_bad);
+                    }
+                    return Ok(());
+                },
                 Err(e) => {
                     self.count = old_count;
-                    self.try_recover(e)?; // Breaks/returns on error.
+                    // Breaks/returns on error.
+                    self.try_recover(e).map_err(journal_full_to_too_bad)?;
                 }
             }
         }
     }
 
+    /// True once the journal has grown to the point where the next write
+    /// will garbage collect first. Callers with idle time can call gc()
+    /// while this holds, so writes don't pay for it later.
+    pub fn needs_gc(&self) -> bool {
+        self.journal.journal_size() >= self.get_capacity()
+    }
+
+    /// Write a full page of data to a logical sector, after checking that
+    /// data is exactly one page (N bytes) long. A slice of any other
+    /// length is refused with DharaError::BadLength before anything is
+    /// written.
+    pub fn write_page_direct(&mut self, sector: DharaSector, data: &[u8]) -> Result<(), DharaError> {
+        let page_size = self.journal.nand.page_size();
+        if data.len() != page_size || data.len() != N {
+            return Err(DharaError::BadLength);
+        }
+        self.write(sector, data)
+    }
+
+    /// Write a page of zeros to a logical sector, without the caller
+    /// having to provide one. The zeros are really written and take a
+    /// page like any other write: there is no special marker, so read()
+    /// costs the same. Compare trim(), which frees the sector's space, but
+    /// leaves it reading as blank (0xff) rather than zero.
+    pub fn write_zeros(&mut self, sector: DharaSector) -> Result<(), DharaError> {
+        let zeros: [u8; N] = [0u8; N];
+        self.write(sector, &zeros)
+    }
+
+    /// Write an object of any length across consecutive sectors: the first
+    /// page of data to start, the next to start + 1, and so on. A last
+    /// chunk shorter than a page is padded out with 0xFF, the value blank
+    /// sectors read as, so the caller has to know the object's length to
+    /// read it back. Empty data writes nothing.
+    ///
+    /// Sectors are written one by one, without syncing. On failure, the
+    /// error says which sector failed; those before it were written, and
+    /// retrying from there carries on. If the range would run into
+    /// DHARA_SECTOR_NONE, nothing is written and the first sector out of
+    /// range is reported with DharaError::BadSector.
+    pub fn write_all(&mut self, start: DharaSector, data: &[u8]) -> Result<(), WriteAllError> {
+        let page_size = self.journal.nand.page_size();
+        let chunks = data.len().div_ceil(page_size);
+        if chunks == 0 {
+            return Ok(());
+        }
+
+        let last = (chunks - 1) as u64 + start as u64;
+        if last >= DHARA_SECTOR_NONE as u64 {
+            return Err(WriteAllError { sector: DHARA_SECTOR_NONE, error: DharaError::BadSector });
+        }
+
+        for (i, chunk) in data.chunks(page_size).enumerate() {
+            let sector = start + i as DharaSector;
+            let result = if chunk.len() == page_size {
+                self.write(sector, chunk)
+            } else {
+                let mut padded: [u8; N] = [0xffu8; N];
+                padded[..chunk.len()].copy_from_slice(chunk);
+                self.write(sector, &padded[..page_size])
+            };
+            result.map_err(|e| WriteAllError { sector: sector, error: e })?;
+        }
+        Ok(())
+    }
+
+    /// Run f with the NAND driver, for driver-specific operations (a
+    /// vendor read-retry command, say, or reading the device's own
+    /// statistics) on the chip the map owns, and return what it returns.
+    ///
+    /// Nothing stops f from changing the chip under the map, so it is up
+    /// to the caller not to: f mustn't program, erase or mark bad anything
+    /// dhara uses, which is every block. Reads, and commands that leave the
+    /// contents alone, are fine.
+    pub fn with_nand<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        f(&mut self.journal.nand)
+    }
+
+    /// A writer that takes data in pieces of any size and writes it out a
+    /// page at a time to consecutive sectors from start; see
+    /// writer::SectorWriter.
+    pub fn sector_writer(&mut self, start: DharaSector) -> SectorWriter<'_,N,T,B> {
+        SectorWriter::new(self, start)
+    }
+
+    /// Rewrite every live sector whose page needed more than threshold bits
+    /// of ECC correction when read, before it decays past the point where
+    /// ECC can save it.
+    ///
+    /// Each sector is looked at once, so a page that keeps reading badly
+    /// can't keep scrub() going. Instead, a block that rewriting can't help
+    /// is retired with retire_block(), which moves its other live sectors
+    /// off it: one holding a page already beyond ECC, or one whose fresh
+    /// copy is still over threshold. The unreadable page's own data can't
+    /// be saved; it stays mapped, so read() goes on reporting the loss.
+    pub fn scrub(&mut self, threshold: u32) -> Result<ScrubReport, DharaError> {
+        // Only the correction count is wanted, but the driver has to read
+        // the whole page to produce it.
+        let mut buf: [u8; N] = [0u8; N];
+        let mut report = ScrubReport { refreshed: 0, retired: 0 };
+        let mut next = self.find_nearest(0)?;
+
+        while let Some(sector) = next {
+            let page = self.find(sector)?;
+            let worn = match self.journal.nand.read_ecc(page, 0, N, &mut buf) {
+                Ok(bits) if bits > threshold => {
+                    self.copy_page(page, sector)?;
+                    report.refreshed += 1;
+                    let fresh = self.find(sector)?;
+                    match self.journal.nand.read_ecc(fresh, 0, N, &mut buf) {
+                        Ok(bits) if bits > threshold => Some(fresh),
+                        Ok(_) => None,
+                        Err(DharaError::ECC) => Some(fresh),
+                        Err(e) => return Err(e),
+                    }
+                },
+                Ok(_) => None,
+                Err(DharaError::ECC) => Some(page),
+                Err(e) => return Err(e),
+            };
+
+            if let Some(bad) = worn {
+                let blk = bad >> self.journal.nand.get_log2_ppb();
+                if !self.journal.nand.is_bad(blk) {
+                    self.retire_block(blk)?;
+                    report.retired += 1;
+                }
+            }
+
+            if sector == DHARA_SECTOR_NONE - 1 {
+                break;
+            }
+            next = self.find_nearest(sector + 1)?;
+        }
+        Ok(report)
+    }
+
+    /// Rewrite the mapped sectors in [start, start + count) in ascending
+    /// order, so that they end up next to each other in the journal, for
+    /// faster sequential reads. Unmapped sectors in the range are skipped.
+    /// Returns the number of sectors rewritten.
+    ///
+    /// The pages are only as contiguous as the journal allows: checkpoint
+    /// pages, bad blocks and garbage collection along the way all come in
+    /// between. Each sector is moved with copy_page(), so this is as safe
+    /// as any other write, and isn't durable until the next sync(). A range
+    /// running into DHARA_SECTOR_NONE gives DharaError::BadSector, with
+    /// nothing moved.
+    pub fn defragment(&mut self, start: DharaSector, count: DharaSector) -> Result<usize, DharaError> {
+        let end = start as u64 + count as u64;
+        if end > DHARA_SECTOR_NONE as u64 {
+            return Err(DharaError::BadSector);
+        }
+        if count == 0 {
+            return Ok(0);
+        }
+
+        let mut moved: usize = 0;
+        let mut next = self.find_nearest(start)?;
+        while let Some(sector) = next {
+            if sector as u64 >= end {
+                break;
+            }
+            let page = self.find(sector)?;
+            self.copy_page(page, sector)?;
+            moved += 1;
+
+            if sector == DHARA_SECTOR_NONE - 1 {
+                break;
+            }
+            next = self.find_nearest(sector + 1)?;
+        }
+        Ok(moved)
+    }
+
     /// Copy any flash page to a logical sector.
     pub fn copy_page(&mut self, src_page: DharaPage, dst_sector: DharaSector) -> Result<(), DharaError> {
         let mut meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
 
         loop {
+            self.cancelled()?;
             let old_count = self.count;
 
             self.prepare_write(dst_sector, &mut meta)?;
 
             match self.journal.journal_copy(src_page, Some(&meta)) {
-                Ok(_) => {return Ok(());},
+                Ok(_) => {
+                    self.user_writes = self.user_writes.wrapping_add(1);
+                    return Ok(());
+                },
                 Err(e) => {
                     self.count = old_count;
                     self.try_recover(e)?; // Breaks/returns on error.
@@ -178,6 +1107,15 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
         }
     }
 
+    /// copy_page(), then sync(), so the destination sector is durable once
+    /// this returns. The checkpoint pads out the current group, which can
+    /// cost up to a group's worth of filler pages per call; sync once after
+    /// a batch of copy_page() calls where that matters.
+    pub fn copy_page_sync(&mut self, src_page: DharaPage, dst_sector: DharaSector) -> Result<(), DharaError> {
+        self.copy_page(src_page, dst_sector)?;
+        self.sync()
+    }
+
     /// Copy one sector to another. If the source sector is unmapped, the
     /// destination sector will be trimmed.
     pub fn copy_sector(&mut self, src: DharaSector, dst: DharaSector) -> Result<(), DharaError> {
@@ -195,6 +1133,29 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
         loop {
             self.auto_gc()?;
             match self.try_delete(sector) {
+                Ok(_) => {
+                    if self.auto_sync {
+                        return self.sync();
+                    }
+                    return Ok(());
+                },
+                Err(e) => {
+                    self.try_recover(e)?;
+                }
+            }
+        }
+    }
+
+    /// Rewrite the root page further up the journal, to reset its data
+    /// retention clock, for a map that sits idle for long periods; see
+    /// DharaJournal::refresh_root(). Only the root is rewritten, not the
+    /// rest of the map. Does nothing on an empty map. Like a write, it
+    /// isn't durable until the next sync().
+    pub fn refresh_root(&mut self) -> Result<(), DharaError> {
+        loop {
+            self.auto_gc()?;
+            self.journal.set_cookie(self.count);
+            match self.journal.refresh_root_with(&mut self.walk_meta) {
                 Ok(_) => {return Ok(());},
                 Err(e) => {
                     self.try_recover(e)?;
@@ -208,37 +1169,201 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     /// that unsynchronized changes will be persistent.
     pub fn sync(&mut self) -> Result<(), DharaError> {
         while !self.journal.journal_is_clean() {
-            let p = self.journal.journal_peek();
-            
-            let ret = if p == DHARA_PAGE_NONE {
-                self.pad_queue()
-            } else {
-                let result = self.raw_gc(p);
-                if result.is_ok() {
-                    self.journal.journal_dequeue();
+            self.cancelled()?;
+            self.sync_step()?;
+        }
+        Ok(())
+    }
+
+    /// Move every live sector off a block, and then mark it bad, for when
+    /// damage is found some other way than a failed erase or program (by
+    /// scrub(), say). The map is synced afterwards, so the move and the
+    /// bad block count are durable when this returns. A block already
+    /// marked bad is left alone.
+    ///
+    /// If the head is on the block, it's first padded off the end, which
+    /// writes up to a block's worth of pages there. A page beyond ECC can't
+    /// be moved, and is left where it is: its sector then goes on reading
+    /// as DharaError::ECC, rather than as blank. Returns
+    /// DharaError::NotFound if blk isn't on the chip.
+    pub fn retire_block(&mut self, blk: DharaBlock) -> Result<(), DharaError> {
+        if blk >= self.journal.nand.get_num_blocks() {
+            return Err(DharaError::NotFound);
+        }
+
+        let log2_ppb = self.journal.nand.get_log2_ppb();
+        while self.journal.get_head() >> log2_ppb == blk {
+            self.sync_step()?;
+        }
+        // Padding can fail there, and recovery retires it for us.
+        if self.journal.nand.is_bad(blk) {
+            return Ok(());
+        }
+
+        let first: DharaPage = blk << log2_ppb;
+        for page in first..(first + (1 << log2_ppb)) {
+            if let Some(sector) = self.live_sector_at(page)? {
+                match self.copy_page(page, sector) {
+                    Ok(()) | Err(DharaError::ECC) => (),
+                    Err(e) => return Err(e),
                 }
-                result
-            };
+            }
+        }
 
-            match ret {
-                Ok(_) => (),
-                Err(e) => {
-                    self.try_recover(e)?;
-                },
+        self.journal.journal_mark_bad(blk);
+        self.sync()
+    }
+
+    /// Call f(sector, page) for each live sector whose current data is in
+    /// the given block, in page order. Only the block's own pages are
+    /// looked at, with a find() for each to check it's still current,
+    /// rather than walking every sector in the map. The first error from
+    /// f stops the walk and is returned. DharaError::NotFound if blk isn't
+    /// on the chip.
+    ///
+    /// f can't change the map; collect what it finds to act on afterwards.
+    pub fn foreach_in_block<F>(&mut self, blk: DharaBlock, mut f: F) -> Result<(), DharaError>
+    where
+        F: FnMut(DharaSector, DharaPage) -> Result<(), DharaError>,
+    {
+        if blk >= self.journal.nand.get_num_blocks() {
+            return Err(DharaError::NotFound);
+        }
+
+        let log2_ppb = self.journal.nand.get_log2_ppb();
+        let first: DharaPage = blk << log2_ppb;
+        for page in first..(first + (1 << log2_ppb)) {
+            if let Some(sector) = self.live_sector_at(page)? {
+                f(sector, page)?;
             }
         }
         Ok(())
     }
 
+    /// Carry out one unit of an assisted recovery: relocate one page, or
+    /// pad the queue. Operations on the map run recovery to completion
+    /// themselves, so this is for when a journal operation has left the
+    /// journal in recovery (journal_in_recovery()) and the caller would
+    /// rather spread the work out than do it in one burst.
+    pub fn recover_step(&mut self) -> Result<RecoverState, DharaError> {
+        if !self.journal.journal_in_recovery() {
+            return Ok(RecoverState::Done);
+        }
+
+        // Restarts are limited below, and each attempt should finish in a
+        // bounded number of steps, but don't rely on the journal for that.
+        if self.recover_steps >= self.recover_step_limit() {
+            return Err(DharaError::TooBad);
+        }
+        self.recover_steps += 1;
+
+        let p = self.journal.journal_next_recoverable();
+
+        let ret = if p == DHARA_PAGE_NONE {
+            self.pad_queue()
+        } else {
+            self.raw_gc(p).map(|_| ())
+        };
+
+        match ret {
+            Ok(_) => (),
+            // Another failure; the journal has restarted the recovery.
+            Err(DharaError::Recover) => {
+                if self.recover_restarts >= self.max_retries {
+                    return Err(DharaError::TooBad);
+                }
+                self.recover_restarts += 1;
+            },
+            Err(e) => return Err(e),
+        }
+
+        if self.journal.journal_in_recovery() {
+            Ok(RecoverState::InProgress)
+        } else {
+            self.recover_restarts = 0;
+            self.recover_steps = 0;
+            Ok(RecoverState::Done)
+        }
+    }
+
+    /// Run f, which should make a group of related changes to the map, then
+    /// sync() so they become durable together.
+    ///
+    /// The map syncs first, so the transaction starts on a fresh checkpoint
+    /// group. If f fails, nothing is synced: the map resumes from that
+    /// checkpoint, which undoes f's changes in memory as well as on the
+    /// chip, and f's error is returned. A power failure before the final
+    /// sync likewise resumes to the state before the transaction.
+    ///
+    /// That all-or-nothing guarantee only holds while f's changes fit in
+    /// the group, because the journal checkpoints by itself each time a
+    /// group fills: at most 2^log2_ppc - 2 pages written, counting
+    /// garbage collection's copies, which a nearly full map may add. A
+    /// bigger transaction still syncs once at the end if it succeeds, but
+    /// a failure part way through leaves whatever was written up to the
+    /// last of those automatic checkpoints.
+    pub fn transaction<F>(&mut self, f: F) -> Result<(), DharaError>
+            where F: FnOnce(&mut Self) -> Result<(), DharaError> {
+        self.sync()?;
+
+        match f(self) {
+            Ok(_) => self.sync(),
+            Err(e) => {
+                // The error from f is the one worth reporting; if resume
+                // fails too, the caller will find out on the next resume.
+                let _ = self.resume();
+                Err(e)
+            },
+        }
+    }
+
+    /// sync(), but only if there's anything to sync. Returns Ok(false),
+    /// having done no I/O, if the map was already clean, and Ok(true) if a
+    /// checkpoint had to be written.
+    pub fn sync_if_dirty(&mut self) -> Result<bool, DharaError> {
+        if self.journal.journal_is_clean() {
+            return Ok(false);
+        }
+        self.sync()?;
+        Ok(true)
+    }
+
     /// Perform one garbage collection step. You can do this whenever you
     /// like, but it's not necessary -- garbage collection happens
     /// automatically and is interleaved with other operations.
     pub fn gc(&mut self) -> Result<(), DharaError> {
-        if self.count == 0 {
-            return Ok(());
+        self.gc_step().map(|_| ())
+    }
+
+    /// Perform up to budget garbage collection steps, as gc() does, so a
+    /// background task can collect garbage ahead of time while bounding
+    /// how long each call takes. Returns how many steps reclaimed a page
+    /// of garbage. Steps that instead had to copy a live page to the head
+    /// use up the budget too, but free nothing, so they aren't counted.
+    pub fn gc_steps(&mut self, budget: usize) -> Result<usize, DharaError> {
+        let mut reclaimed = 0;
+
+        for _ in 0..budget {
+            if self.gc_step()? {
+                reclaimed += 1;
+            }
         }
+        Ok(reclaimed)
+    }
 
-        loop {
+    /// Collect all the garbage currently in the journal, then synchronize.
+    /// Afterwards the journal holds little more than the live sectors. This
+    /// is a burst of writes (every live page is rewritten once), so keep it
+    /// for maintenance windows.
+    pub fn compact(&mut self) -> Result<(), DharaError> {
+        // Look at each page that was in the journal when we started,
+        // exactly once. Live pages are rewritten at the head, behind the
+        // pages still to be checked, so without a bound we'd chase them
+        // forever.
+        let mut remaining = self.journal.journal_size();
+
+        while remaining > 0 {
+            self.cancelled()?;
             let tail = self.journal.journal_peek();
 
             if tail == DHARA_PAGE_NONE {
@@ -248,15 +1373,36 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
             match self.raw_gc(tail) {
                 Ok(_) => {
                     self.journal.journal_dequeue();
-                    break;
+                    remaining -= 1;
                 },
                 Err(e) => {
                     self.try_recover(e)?;
                 }
             }
         }
-        Ok(())
-    } 
+
+        // Dequeued space isn't reusable until a checkpoint records the
+        // new tail.
+        self.sync()
+    }
+
+    /// Shrink the journal to as few pages as the live sectors allow, e.g.
+    /// to free blocks before something else borrows them (see
+    /// DharaJournal::block_in_use()). Returns how many pages the journal
+    /// gave up, by journal_size(); the checkpoints and padding written
+    /// along the way are netted off. A maintenance-window operation.
+    ///
+    /// This is compact(), plus the count. Compacting, reclaiming the tail
+    /// and syncing are what it takes, but compact() already ends with the
+    /// sync, and its checkpoint moves the synchronized tail up to the
+    /// tail. That leaves reclaim_tail() nothing to do (tail_lag() is 0).
+    /// Use compact() where the count isn't wanted. Crash safe throughout,
+    /// since the tail only moves with a checkpoint.
+    pub fn shrink_to(&mut self) -> Result<DharaPage, DharaError> {
+        let before = self.journal.journal_size();
+        self.compact()?;
+        Ok(before.saturating_sub(self.journal.journal_size()))
+    }
 
 }
 
@@ -264,7 +1410,17 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
 // Private methods
 // ///////////////////////////////////////////////////////////////////////
 //
-impl<const N: usize,T: DharaNand> DharaMap<N,T> {
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> DharaMap<N,T,B> {
+
+    // The journal state a find cache entry is valid for.
+    fn cache_key(&self) -> CacheKey {
+        CacheKey {
+            root: self.journal.get_root(),
+            head: self.journal.get_head(),
+            tail: self.journal.get_tail(),
+            epoch: self.journal.get_epoch(),
+        }
+    }
 
     // Trace the path from the root to the given sector, emitting
     // alt-pointers and alt-full bits in the given metadata buffer. This
@@ -272,14 +1428,14 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     // exists.
     // 
     // If the page can't be found, a suitable path will be constructed
-    // (containing PAGE_NONE alt-pointers), mutating new_meta, and 
+    // (containing PAGE_NONE alt-pointers) in self.path_meta, and
     // DHARA_E_NOT_FOUND will be returned.
     //
     // The C code passes in pointers to buffer, page location, and error code.
     // The buffer and page location can be NULL, in which case they will not
     // be written.  This saves allocating a DHARA_META_SIZE buffer in one case
-    // where the function is called (out of 4).  For simplicity, this version
-    // requires that you always allocate that buffer.  The return value is 
+    // where the function is called (out of 4).  This version always builds
+    // the path in self.path_meta, which costs nothing extra.  The return value is
     // a Result, containing either the page value or an error.  In no case in
     // the original C code is the page value used if an error is returned,
     // thouth the mutated buffer is used when prepare_write() calls
@@ -287,93 +1443,178 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     //
     // Also, the C code uses a goto to exit in some errors, and I've elected
     // to have a function call take care of it.  If inlined, it will be the same.
-    fn trace_path(&mut self, target: DharaSector, new_meta: &mut [u8]) -> Result<DharaPage, DharaError> {
-        let mut meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
+    // The new path to target is left in self.path_meta.
+    fn trace_path(&mut self, target: DharaSector) -> Result<DharaPage, DharaError> {
+        self.journal.checked()?;
         let mut depth: usize = 0;
         let mut p = self.journal.get_root();
 
-        meta_set_id(new_meta, target);
+        meta_set_id(&mut self.path_meta, target);
 
         if p == DHARA_PAGE_NONE {
-            return trace_not_found(new_meta, depth);
+            return trace_not_found(&mut self.path_meta, depth);
         }
 
-        self.journal.journal_read_meta(p, &mut meta)?;
+        self.journal.journal_read_meta(p, &mut self.walk_meta)?;
 
-        while depth < DHARA_RADIX_DEPTH {
-            let id = meta_get_id(&meta);
+        // The root is the last sector written, often the one wanted next.
+        // With every bit matching, the walk below would never branch, and
+        // the new path would be the root's own alt-pointers, so take them
+        // in one go.
+        if !is_filler_meta(&self.walk_meta) && meta_get_id(&self.walk_meta) == target {
+            self.path_meta.copy_from_slice(&self.walk_meta);
+            return Ok(p);
+        }
 
-            if id == DHARA_SECTOR_NONE {
-                return trace_not_found(new_meta, depth);
+        while depth < DHARA_RADIX_DEPTH {
+            if is_filler_meta(&self.walk_meta) {
+                return trace_not_found(&mut self.path_meta, depth);
             }
+            let id = meta_get_id(&self.walk_meta);
 
             if (target ^ id) & d_bit(depth) != 0 {
-                meta_set_alt(new_meta, depth, p);
-                p = meta_get_alt(&meta, depth);
+                meta_set_alt(&mut self.path_meta, depth, p);
+                p = meta_get_alt(&self.walk_meta, depth);
 
                 if p == DHARA_PAGE_NONE {
                     depth += 1;
-                    return trace_not_found(new_meta, depth);
+                    return trace_not_found(&mut self.path_meta, depth);
                 }
 
-                self.journal.journal_read_meta(p, &mut meta)?;
+                self.journal.journal_read_meta(p, &mut self.walk_meta)?;
             } else {
-                let value = meta_get_alt(&meta, depth);
-                meta_set_alt(new_meta, depth, value);
+                let value = meta_get_alt(&self.walk_meta, depth);
+                meta_set_alt(&mut self.path_meta, depth, value);
             }
             depth += 1;
         }
         Ok(p)
     }
 
+    // Find the smallest sector in the subtree rooted at page p, whose
+    // branching starts at the given depth. Take the 0 side of every
+    // branch on the way down.
+    fn subtree_min(&mut self, mut p: DharaPage, mut depth: usize) -> Result<DharaSector, DharaError> {
+        self.journal.journal_read_meta(p, &mut self.walk_meta)?;
+
+        while depth < DHARA_RADIX_DEPTH {
+            if meta_get_id(&self.walk_meta) & d_bit(depth) != 0 {
+                let alt = meta_get_alt(&self.walk_meta, depth);
+                if alt != DHARA_PAGE_NONE {
+                    p = alt;
+                    self.journal.journal_read_meta(p, &mut self.walk_meta)?;
+                }
+            }
+            depth += 1;
+        }
+        Ok(meta_get_id(&self.walk_meta))
+    }
+
+    // One step of gc(). Ok(true) if the tail page was garbage and has
+    // been dropped, Ok(false) if it had to be copied, or there was nothing
+    // to do.
+    fn gc_step(&mut self) -> Result<bool, DharaError> {
+        if self.count == 0 {
+            return Ok(false);
+        }
+
+        loop {
+            self.cancelled()?;
+            let tail = self.journal.journal_peek();
+
+            if tail == DHARA_PAGE_NONE {
+                return Ok(false);
+            }
+
+            match self.raw_gc(tail) {
+                Ok(copied) => {
+                    self.journal.journal_dequeue();
+                    return Ok(!copied);
+                },
+                Err(e) => {
+                    self.try_recover(e)?;
+                }
+            }
+        }
+    }
+
     // Check the given page. If it's garbage, do nothing. Otherwise, rewrite
     // it at the front of the map. Return raw errors from the journal (do
-    // not perform recovery).
-    fn raw_gc(&mut self, src: DharaPage) -> Result<(),DharaError> {
-        let mut meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
-
+    // not perform recovery). Returns whether the page was live, and so
+    // copied to the head.
+    fn raw_gc(&mut self, src: DharaPage) -> Result<bool,DharaError> {
         // Get meta and return if error.
-        self.journal.journal_read_meta(src, &mut meta)?;
+        self.journal.journal_read_meta(src, &mut self.path_meta)?;
 
         // Is the page just filler/garbage?
-        let target = meta_get_id(&meta);
-        if target == DHARA_SECTOR_NONE {
-            return Ok(());
+        if is_filler_meta(&self.path_meta) {
+            return Ok(false);
         }
+        let target = meta_get_id(&self.path_meta);
 
         // Find out where the sector once represented by this page
         // currently resides (if anywhere).
-        match self.trace_path(target, &mut meta) {
-            Err(DharaError::NotFound) => Ok(()),
+        match self.trace_path(target) {
+            Err(DharaError::NotFound) => Ok(false),
             Err(e) => Err(e),
             Ok(current_page) => {
                 // Is this page still the most current representative?
                 // If not, do nothing.
                 if current_page != src {
-                    return Ok(());
+                    return Ok(false);
                 }
 
                 // Rewrite it at the front of the journal with updated metadata.
+                self.find_cache.invalidate();
                 self.journal.set_cookie(self.count);
-                self.journal.journal_copy(src, Some(&meta))?;
-                Ok(())
+                self.journal.journal_copy(src, Some(&self.path_meta))?;
+                Ok(true)
             },
         }
     }
 
-    fn pad_queue(&mut self) -> Result<(),DharaError> {
-        let p = self.journal.get_root();
-        let mut root_meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
+    // One step towards a checkpoint: move the tail page to the head, or
+    // pad the queue if it's empty, recovering from any failure.
+    fn sync_step(&mut self) -> Result<(),DharaError> {
+        let p = self.journal.journal_peek();
+
+        let ret = if p == DHARA_PAGE_NONE {
+            self.pad_queue()
+        } else {
+            let result = self.raw_gc(p);
+            if result.is_ok() {
+                self.journal.journal_dequeue();
+            }
+            result.map(|_| ())
+        };
 
+        match ret {
+            Ok(_) => Ok(()),
+            Err(e) => self.try_recover(e),
+        }
+    }
+
+    fn pad_queue(&mut self) -> Result<(),DharaError> {
         self.journal.set_cookie(self.count);
 
-        if p == DHARA_PAGE_NONE {
+        if self.journal.get_root() == DHARA_PAGE_NONE {
             return self.journal.journal_enqueue(None, None);
         }
 
-        self.journal.journal_read_meta(p, &mut root_meta)?;
+        return self.journal.refresh_root_with(&mut self.walk_meta);
+    }
 
-        return self.journal.journal_copy(p, Some(&root_meta));
+    // restore() without the room check, for callers that have done their
+    // own.
+    fn write_pairs<I: Iterator<Item = (DharaSector, [u8; N])>>(&mut self, items: I) -> Result<(), DharaError> {
+        let sync_every: usize = 1 << self.journal.nand.get_log2_ppb();
+        for (i, (sector, data)) in items.enumerate() {
+            self.write(sector, &data)?;
+            if (i + 1) % sync_every == 0 {
+                self.sync()?;
+            }
+        }
+        self.sync()
     }
 
     // Attempt to recover the journal.
@@ -382,33 +1623,85 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
             return Err(cause);
         }
 
-        let mut restart_count: u8 = 0;
-
-        while self.journal.journal_in_recovery() {
-            let p = self.journal.journal_next_recoverable();
+        self.recover_restarts = 0;
+        self.recover_steps = 0;
+        loop {
+            self.cancelled()?;
+            if self.recover_step()? == RecoverState::Done {
+                return Ok(());
+            }
+        }
+    }
 
-            let ret = if p == DHARA_PAGE_NONE {
-                self.pad_queue()
-            } else {
-                self.raw_gc(p)
-            };
+    // An attempt at recovery relocates the pages of the failed block (at
+    // most a block's worth), then pads out the group (less than another
+    // block). So this is every permitted attempt running to the end.
+    fn recover_step_limit(&self) -> u32 {
+        (self.max_retries as u32 + 1) * (2 << self.journal.nand.get_log2_ppb())
+    }
 
-            match ret {
-                Ok(_) => {continue;},
-                Err(DharaError::Recover) => {
-                    if restart_count >= DHARA_MAX_RETRIES {
-                        return Err(DharaError::TooBad);
-                    }
-                    restart_count += 1;
-                },
-                Err(e) => return Err(e),
+    // Errors just stop this early: it's only an optimization, and
+    // garbage collection will come to the same page later and report it.
+    fn reclaim_garbage_at_tail(&mut self) -> () {
+        while self.journal.journal_is_clean() {
+            let tail = self.journal.journal_peek();
+            if tail == DHARA_PAGE_NONE {
+                break;
+            }
+            match self.live_sector_at(tail) {
+                Ok(None) => self.journal.journal_dequeue(),
+                _ => break,
             }
         }
-        Ok(())
+    }
+
+    fn find_and_read_filled(&mut self, sector: DharaSector, data: &mut [u8], fill: u8) -> Result<Option<DharaPage>, DharaError> {
+        if data.len() != self.journal.nand.page_size() {
+            return Err(DharaError::BadLength);
+        }
+
+        match self.find(sector) {
+            Err(DharaError::NotFound) => {
+                data.fill(fill);
+                Ok(None)
+            },
+            Err(e) => Err(e),
+            Ok(page) => {
+                self.journal.nand.read(page, 0, self.journal.nand.page_size(), data)?;
+                Ok(Some(page))
+            },
+        }
+    }
+
+    // The sector whose current data is this page, if any. Checkpoint
+    // pages, unprogrammed pages, filler and superseded copies give None.
+    fn live_sector_at(&mut self, page: DharaPage) -> Result<Option<DharaSector>,DharaError> {
+        let ppc_mask: DharaPage = (1 << self.journal.get_log2_ppc()) - 1;
+        if page & ppc_mask == ppc_mask || self.journal.nand.is_free(page) {
+            return Ok(None);
+        }
+
+        self.journal.journal_read_meta(page, &mut self.walk_meta)?;
+        if is_filler_meta(&self.walk_meta) {
+            return Ok(None);
+        }
+        let sector = meta_get_id(&self.walk_meta);
+        match self.find(sector) {
+            Ok(current) if current == page => Ok(Some(sector)),
+            Ok(_) | Err(DharaError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn cancelled(&self) -> Result<(),DharaError> {
+        match self.cancel_check {
+            Some(flag) if flag.load(Ordering::Relaxed) => Err(DharaError::Cancelled),
+            _ => Ok(()),
+        }
     }
 
     fn auto_gc(&mut self) -> Result<(),DharaError> {
-        if self.journal.journal_size() < self.get_capacity() {
+        if !self.needs_gc() {
             return Ok(());
         }
 
@@ -419,9 +1712,19 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     }
 
     fn prepare_write(&mut self, dst: DharaSector, meta: &mut [u8]) -> Result<(),DharaError> {
+        // A page tagged with the filler id would be taken for garbage and
+        // dropped, and would cut off the rest of the tree below it.
+        if dst == DHARA_SECTOR_NONE {
+            return Err(DharaError::BadSector);
+        }
+        self.journal.checked()?;
+
         self.auto_gc()?;  // Collect garbage and return if error.
+        self.find_cache.invalidate();
 
-        match self.trace_path(dst, meta) {
+        let found = self.trace_path(dst);
+        meta.copy_from_slice(&self.path_meta);
+        match found {
             Ok(_) => (),
             Err(DharaError::NotFound) => {
                 if self.count >= self.get_capacity() {
@@ -436,49 +1739,50 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
     }
 
     fn try_delete(&mut self, sector: DharaSector) -> Result<(),DharaError> {
-        let mut meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
-        let mut alt_meta: [u8; DHARA_META_SIZE]= [0u8; DHARA_META_SIZE];
         let mut level = DHARA_RADIX_DEPTH - 1;
         let mut alt_page: DharaPage;
 
+        self.find_cache.invalidate();
         // The value of this expression is the return value of the function.
-        match self.trace_path(sector, &mut meta) {
+        match self.trace_path(sector) {
             Err(DharaError::NotFound) => Ok(()),
             Err(e) => Err(e),
             Ok(_) => {
                 // Select any of the closest cousins of this node which are
                 // subtrees of at least the requested order.
                 loop {
-                    alt_page = meta_get_alt(&meta, level);
+                    alt_page = meta_get_alt(&self.path_meta, level);
                     if alt_page != DHARA_PAGE_NONE {
                         break;
                     }
 
-                    level -= 1;
-                    
-                    // Special case: deletion of last sector
+                    // Special case: deletion of last sector. Only once level 0
+                    // has been checked too, or a sector whose sole cousin is
+                    // there would take the whole map with it.
                     if level == 0 {
                         self.count = 0;
                         self.journal.journal_clear();
                         return Ok(());
                     }
+
+                    level -= 1;
                 }
 
                 // Rewrite the cousin with an up-to-date path which doesn't
                 // point to the original node.
-                self.journal.journal_read_meta(alt_page, &mut alt_meta)?;
+                self.journal.journal_read_meta(alt_page, &mut self.walk_meta)?;
 
-                meta_set_id(&mut meta, meta_get_id(&alt_meta));
+                meta_set_id(&mut self.path_meta, meta_get_id(&self.walk_meta));
 
-                meta_set_alt(&mut meta, level, DHARA_PAGE_NONE);
+                meta_set_alt(&mut self.path_meta, level, DHARA_PAGE_NONE);
                 for i in (level+1)..DHARA_RADIX_DEPTH {
-                    meta_set_alt(&mut meta, i, meta_get_alt(&alt_meta, i));
+                    meta_set_alt(&mut self.path_meta, i, meta_get_alt(&self.walk_meta, i));
                 }
-                meta_set_alt(&mut meta, level, DHARA_PAGE_NONE); // TODO: is this statement redundant?
+                meta_set_alt(&mut self.path_meta, level, DHARA_PAGE_NONE); // TODO: is this statement redundant?
 
                 self.journal.set_cookie(self.count - 1);
 
-                self.journal.journal_copy(alt_page, Some(&meta))?;  // TODO: document why this function takes an Option.
+                self.journal.journal_copy(alt_page, Some(&self.path_meta))?;  // TODO: document why this function takes an Option.
 
                 self.count -= 1;
                 Ok(())
@@ -488,6 +1792,104 @@ impl<const N: usize,T: DharaNand> DharaMap<N,T> {
 
 }
 
+/// Iterator returned by DharaMap::sectors().
+pub struct MapSectors<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> {
+    map: &'a mut DharaMap<N,T,B>,
+    // Where to search from next; None once finished.
+    next: Option<DharaSector>,
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> Iterator for MapSectors<'a,N,T,B> {
+    type Item = Result<DharaSector, DharaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let from = self.next?;
+        match self.map.find_nearest(from) {
+            Ok(Some(sector)) => {
+                // DHARA_SECTOR_NONE can't be mapped, so sector + 1 only
+                // overflows past the last possible sector.
+                self.next = if sector < DHARA_SECTOR_NONE - 1 { Some(sector + 1) } else { None };
+                Some(Ok(sector))
+            },
+            Ok(None) => {
+                self.next = None;
+                None
+            },
+            Err(e) => {
+                self.next = None;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Iterator returned by DharaMap::iter_changes_since().
+pub struct MapChanges<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> {
+    sectors: MapSectors<'a,N,T,B>,
+    snapshot: Snapshot,
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> MapChanges<'a,N,T,B> {
+    // Was this page written at or after the snapshot's head? Live pages
+    // lie behind the head, less than a chip's length back, so those past
+    // it were written on the previous pass.
+    fn is_newer(&self, page: DharaPage) -> bool {
+        let journal = &self.sectors.map.journal;
+        let mut epoch = journal.get_epoch();
+        if page >= journal.get_head() {
+            epoch = epoch.wrapping_sub(1);
+        }
+
+        let passes = epoch.wrapping_sub(self.snapshot.epoch) as i8;
+        passes > 0 || (passes == 0 && page >= self.snapshot.head)
+    }
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> Iterator for MapChanges<'a,N,T,B> {
+    type Item = Result<DharaSector, DharaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let sector = match self.sectors.next()? {
+                Ok(sector) => sector,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.sectors.map.find(sector) {
+                Ok(page) if self.is_newer(page) => return Some(Ok(sector)),
+                Ok(_) => (),
+                Err(e) => {
+                    self.sectors.next = None;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+/// Iterator returned by DharaMap::iter_pairs().
+pub struct MapPairs<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> {
+    sectors: MapSectors<'a,N,T,B>,
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> Iterator for MapPairs<'a,N,T,B> {
+    type Item = Result<(DharaSector, [u8; N]), DharaError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sector = match self.sectors.next()? {
+            Ok(sector) => sector,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let mut data: [u8; N] = [0u8; N];
+        if let Err(e) = self.sectors.map.read(sector, &mut data) {
+            self.sectors.next = None;
+            return Some(Err(e));
+        }
+        Some(Ok((sector, data)))
+    }
+}
+
 // ///////////////////////////////////////////////////////////////////////
 // Helper functions
 // ///////////////////////////////////////////////////////////////////////
@@ -498,6 +1900,16 @@ pub fn meta_get_id(meta: &[u8]) -> DharaSector {
     dhara_r32(&meta[0..4])
 }
 
+/// Is this the metadata of a filler page, which holds no sector and is
+/// always garbage? Only the id is looked at. Filler is written with no
+/// metadata at all, so its checkpoint slot is left all 0xff, the same as
+/// the reference C code writes; images from either read the same way.
+/// A group whose checkpoint was never written reads as erased, and so
+/// as filler too.
+pub fn is_filler_meta(meta: &[u8]) -> bool {
+    meta_get_id(meta) == DHARA_SECTOR_NONE
+}
+
 fn meta_set_id(meta: &mut [u8], value: DharaSector) -> () {
     dhara_w32(&mut meta[0..4], value);
 }
@@ -516,11 +1928,40 @@ fn meta_set_alt(meta: &mut [u8], level: usize, alt: DharaPage) -> () {
     dhara_w32(&mut meta[idx..idx+4], alt);
 }
 
-fn d_bit(depth: usize) -> DharaSector {
+pub(crate) fn d_bit(depth: usize) -> DharaSector {
     let temp: DharaSector = 1;
     temp << (DHARA_RADIX_DEPTH - depth - 1)
 }
 
+// Once recovery has given up, running out of journal is a symptom of too
+// many bad blocks, not something the map's user can act on differently.
+fn journal_full_to_too_bad(e: DharaError) -> DharaError {
+    match e {
+        DharaError::JournalFull => DharaError::TooBad,
+        _ => e,
+    }
+}
+
+// The journal's capacity less the garbage collection reserve and the
+// safety margin.
+fn map_capacity(cap: DharaPage, gc_ratio: u8, safety_blocks: u32, log2_ppb: u8) -> DharaSector {
+    capacity_breakdown(cap, gc_ratio, safety_blocks, log2_ppb).usable
+}
+
+// Where the margin doesn't fit, it takes what's left, so that the parts
+// always add up to the whole.
+fn capacity_breakdown(cap: DharaPage, gc_ratio: u8, safety_blocks: u32, log2_ppb: u8) -> CapacityBreakdown {
+    let reserve = cap / (gc_ratio as u32 + 1);
+    let safety_margin = safety_blocks.saturating_mul(1 << log2_ppb).min(cap - reserve);
+
+    CapacityBreakdown {
+        raw: cap,
+        gc_reserve: reserve,
+        safety_margin: safety_margin,
+        usable: cap - reserve - safety_margin,
+    }
+}
+
 fn trace_not_found(new_meta: &mut [u8], mut depth: usize) -> Result<DharaPage, DharaError> {
     while depth < DHARA_RADIX_DEPTH {
         meta_set_alt(new_meta, depth, DHARA_SECTOR_NONE);
@@ -540,7 +1981,136 @@ fn trace_not_found(new_meta: &mut [u8], mut depth: usize) -> Result<DharaPage, D
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn filler_meta() {
+        // What the C code's push_meta() leaves for a page enqueued with no
+        // metadata: memset to 0xff.
+        let c_filler = [0xffu8; DHARA_META_SIZE];
+        assert!(is_filler_meta(&c_filler));
+
+        let mut meta = [0xffu8; DHARA_META_SIZE];
+        meta_set_id(&mut meta, 0);
+        assert!(!is_filler_meta(&meta));
+        meta_set_id(&mut meta, DHARA_SECTOR_NONE - 1);
+        assert!(!is_filler_meta(&meta));
+    }
+
+    #[test]
+    fn meta_levels() {
+        // Every level holds its own value, and setting it leaves the id
+        // and all the other levels as they were.
+        let mut meta = [0u8; DHARA_META_SIZE];
+        meta_set_id(&mut meta, 0xdead_beef);
+        for level in 0..DHARA_RADIX_DEPTH {
+            meta_set_alt(&mut meta, level, 0x0101_0101 * level as u32);
+        }
+
+        for level in 0..DHARA_RADIX_DEPTH {
+            let before = meta;
+            meta_set_alt(&mut meta, level, 0xa5c3_5a3c);
+            assert_eq!(meta_get_alt(&meta, level), 0xa5c3_5a3c);
+            assert_eq!(meta_get_id(&meta), 0xdead_beef);
+            for other in (0..DHARA_RADIX_DEPTH).filter(|&o| o != level) {
+                assert_eq!(meta_get_alt(&meta, other), meta_get_alt(&before, other));
+            }
+
+            // Exactly the level's four bytes changed.
+            let changed: Vec<usize> = (0..DHARA_META_SIZE).filter(|&i| meta[i] != before[i]).collect();
+            assert!(changed.iter().all(|&i| i >= 4 + level * 4 && i < 8 + level * 4));
+            meta_set_alt(&mut meta, level, 0x0101_0101 * level as u32);
+        }
+    }
+
+    #[test]
+    fn meta_boundaries() {
+        // The id is the first four bytes, little-endian, and the levels
+        // follow it with nothing spare at the end.
+        let mut meta = [0u8; DHARA_META_SIZE];
+        meta_set_id(&mut meta, 0x0403_0201);
+        assert_eq!(meta[..5], [1, 2, 3, 4, 0]);
+        assert_eq!(meta_get_alt(&meta, 0), 0);
+
+        let mut meta = [0u8; DHARA_META_SIZE];
+        meta_set_alt(&mut meta, 0, DHARA_PAGE_NONE);
+        assert_eq!(meta_get_id(&meta), 0);
+        assert_eq!(meta[4..8], [0xff; 4]);
+
+        let mut meta = [0u8; DHARA_META_SIZE];
+        meta_set_alt(&mut meta, DHARA_RADIX_DEPTH - 1, 0x0807_0605);
+        assert_eq!(meta[DHARA_META_SIZE - 4..], [5, 6, 7, 8]);
+        assert_eq!(meta_get_alt(&meta, DHARA_RADIX_DEPTH - 2), 0);
+        assert_eq!(4 + DHARA_RADIX_DEPTH * 4, DHARA_META_SIZE);
+
+        meta_set_id(&mut meta, DHARA_SECTOR_NONE);
+        assert_eq!(meta_get_id(&meta), DHARA_SECTOR_NONE);
+        assert_eq!(meta_get_alt(&meta, DHARA_RADIX_DEPTH - 1), 0x0807_0605);
+    }
+
+    #[test]
+    fn meta_roundtrip() {
+        use rand::{Rng, SeedableRng};
+        use rand::rngs::SmallRng;
+
+        // Random ids and paths, written in a random order, all read back.
+        let mut rng = SmallRng::seed_from_u64(1386);
+        for _ in 0..200 {
+            let id: DharaSector = rng.gen();
+            let mut alts = [0u32; DHARA_RADIX_DEPTH];
+            rng.fill(&mut alts[..]);
+
+            let mut meta = [0u8; DHARA_META_SIZE];
+            rng.fill(&mut meta[..]);
+            let first = rng.gen_range(0..DHARA_RADIX_DEPTH);
+            for i in 0..DHARA_RADIX_DEPTH {
+                let level = (first + i * 7) % DHARA_RADIX_DEPTH;
+                meta_set_alt(&mut meta, level, alts[level]);
+            }
+            meta_set_id(&mut meta, id);
+
+            assert_eq!(meta_get_id(&meta), id);
+            for (level, &alt) in alts.iter().enumerate() {
+                assert_eq!(meta_get_alt(&meta, level), alt);
+            }
+        }
+    }
+
+    #[test]
+    fn error_strings() {
+        let all = [
+            DharaError::BadBlock, DharaError::ECC, DharaError::TooBad,
+            DharaError::Recover, DharaError::JournalFull, DharaError::NotFound,
+            DharaError::MapFull, DharaError::CorruptMap, DharaError::Blank,
+            DharaError::BadLength, DharaError::BadGeometry,
+            DharaError::BadSector, DharaError::BadConfig, DharaError::Cancelled,
+            DharaError::VersionMismatch, DharaError::Max,
+        ];
+
+        for (i, a) in all.iter().enumerate() {
+            assert!(!a.as_str().is_empty());
+            for b in &all[i + 1..] {
+                assert_ne!(a.as_str(), b.as_str());
+            }
+        }
+    }
+
+    #[test]
+    fn capacity_extremes() {
+        // A margin bigger than anything leaves nothing, and the parts
+        // still add up.
+        for (cap, safety) in [(0, 0), (100, 0), (100, u32::MAX), (u32::MAX, u32::MAX), (u32::MAX, 1 << 29)] {
+            for ratio in [0, 1, 255] {
+                let b = capacity_breakdown(cap, ratio, safety, 3);
+                assert_eq!(b.gc_reserve + b.safety_margin + b.usable, cap);
+                if safety == u32::MAX {
+                    assert_eq!(b.usable, 0);
+                }
+            }
+        }
+        // 2**29 blocks of 8 pages would wrap to no margin at all.
+        assert_eq!(map_capacity(u32::MAX, 255, 1 << 29, 3), 0);
+    }
 
     #[test]
     fn it_works() {
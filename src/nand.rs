@@ -17,7 +17,7 @@ pub trait DharaNand {
     /// Get the base-2 logarithm of the page size. If your device supports
     /// partial programming, you may want to subdivide the actual
     /// pages into separate ECC-correctable regions and present those
-    /// as pages.
+    /// as pages.  SubdividedNand does this for you.
     fn get_log2_page_size(&self) -> u8;
 
     /// Get the base-2 logarithm of the number of pages within an erase block.
@@ -26,6 +26,31 @@ pub trait DharaNand {
     /// Get the total number of erase blocks.
     fn get_num_blocks(&self) -> u32;  // TODO: change to usize?
 
+    /// The page size in bytes. Derived from get_log2_page_size(), so there's
+    /// normally no need to implement it.
+    fn page_size(&self) -> usize {
+        1usize << self.get_log2_page_size()
+    }
+
+    /// The number of pages in an erase block.
+    fn pages_per_block(&self) -> u32 {
+        1u32 << self.get_log2_ppb()
+    }
+
+    /// The erase block size in bytes.
+    fn block_size(&self) -> usize {
+        self.page_size() << self.get_log2_ppb()
+    }
+
+    /// The number of pages on the chip. Page numbers run from 0 up to,
+    /// but not including, this.
+    fn total_pages(&self) -> DharaPage {
+        let log2_ppb = self.get_log2_ppb();
+        debug_assert!(self.get_num_blocks().leading_zeros() >= log2_ppb as u32,
+            "more pages than a DharaPage can number");
+        self.get_num_blocks() << log2_ppb
+    }
+
     /// Is the given block bad?
     /// TODO: In some ways, it seems like this shouldn't be &mut,
     /// since we are just looking up a value.  But maybe the implementer
@@ -43,6 +68,59 @@ pub trait DharaNand {
     /// Err(BadBlock).
     fn erase(&mut self, blk: DharaBlock) -> Result<(),DharaError>;
 
+    /// Erase the given block, then check that it really did erase: every
+    /// page reports free and reads back as all 0xFF. A block that fails
+    /// the check, including by failing to read, gives Err(BadBlock), as a
+    /// failed erase would. This catches erases that report success
+    /// without clearing the block, at the cost of reading it all back.
+    /// DharaJournal::set_erase_verify() turns on its use. Override it if
+    /// the chip can verify more cheaply itself.
+    fn erase_verify(&mut self, blk: DharaBlock) -> Result<(),DharaError> {
+        self.erase(blk)?;
+
+        // Read back in small pieces, so the check needs no page buffer.
+        let mut buf = [0u8; 32];
+        let page_size = self.page_size();
+        let first: DharaPage = blk << self.get_log2_ppb();
+        for page in first..first + self.pages_per_block() {
+            if !self.is_free(page) {
+                return Err(DharaError::BadBlock);
+            }
+
+            let mut offset = 0;
+            while offset < page_size {
+                let len = buf.len().min(page_size - offset);
+                if self.read(page, offset, len, &mut buf[..len]).is_err()
+                        || buf[..len].iter().any(|&b| b != 0xff) {
+                    return Err(DharaError::BadBlock);
+                }
+                offset += len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Erase every block not marked bad, marking bad any that fail to
+    /// erase, for provisioning a chip; DharaJournal::journal_format() uses
+    /// it. Errors other than BadBlock are returned, perhaps with only some
+    /// blocks erased. This does one block at a time; override it if the
+    /// chip can erase many at once. An override must still mark failed
+    /// blocks bad, since is_bad() is how the caller learns of them.
+    fn erase_all(&mut self) -> Result<(),DharaError> {
+        for blk in 0..self.get_num_blocks() {
+            if self.is_bad(blk) {
+                continue;
+            }
+
+            match self.erase(blk) {
+                Ok(_) => (),
+                Err(DharaError::BadBlock) => self.mark_bad(blk),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
     /// Program the given page.  
     /// The data pointer is *** TODO figure this out.
     /// The operation status should be checked.  If the operation fails,
@@ -51,16 +129,59 @@ pub trait DharaNand {
     /// not be reprogrammed.
     fn prog(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError>;
 
+    /// Program the given page, then read it back and check it holds what
+    /// was written. A mismatch, or a failed read, gives Err(BadBlock), as
+    /// a failed program would. This catches controllers that report
+    /// success having written garbage. DharaJournal::set_verify_checkpoints()
+    /// turns on its use for checkpoint pages. Override it if the chip can
+    /// verify more cheaply itself.
+    fn prog_verify(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError> {
+        self.prog(page, data)?;
+
+        // Read back in small pieces, so the check needs no page buffer.
+        let mut buf = [0u8; 32];
+        let chunk = buf.len();
+        for (i, expected) in data.chunks(chunk).enumerate() {
+            let got = &mut buf[..expected.len()];
+            if self.read(page, i * chunk, expected.len(), got).is_err() || got != expected {
+                return Err(DharaError::BadBlock);
+            }
+        }
+        Ok(())
+    }
+
     /// Check the the given page is erased.
     fn is_free(&mut self, page: DharaPage) -> bool;
 
     /// Read a portion of a page. ECC must be handled by the NAND 
     /// implementation. Returns Ok(0) on sucess or Err(e) if an error occurs. 
     /// If an uncorrectable ECC error occurs, return Err(ECC).
+    /// dhara always passes a data slice exactly length bytes long, having
+    /// checked the caller's buffer first (DharaMap::read() gives
+    /// DharaError::BadLength otherwise). A driver called some other way
+    /// should return Err(BadLength) on a mismatch rather than panic;
+    /// read_exact() does the check for it.
     // TODO: is this the right way to handle errors?  The u8 isn't really used.
-    // TODO: is this the right way to deal with data? Check this reads into an external slice.
     fn read(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError>;
 
+    /// read(), after checking that data is exactly length bytes long, for
+    /// callers that can't vouch for their buffers. Err(BadLength) if not.
+    fn read_exact(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        if data.len() != length {
+            return Err(DharaError::BadLength);
+        }
+        self.read(page, offset, length, data)
+    }
+
+    /// Like read(), but also report how many bit errors ECC had to correct,
+    /// so that pages which are starting to wear out can be rewritten before
+    /// they become unreadable (see DharaMap::scrub()). The default suits
+    /// chips that can't report this: it reads and reports 0.
+    fn read_ecc(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<u32, DharaError> {
+        self.read(page, offset, length, data)?;
+        Ok(0)
+    }
+
     /// Read a page from one location and reprogram it in another location.
     /// This might be done using the chip's internal buffers, but it must use
     /// ECC.
@@ -71,4 +192,39 @@ pub trait DharaNand {
     // fn freeze(&mut self) -> ();
     // #[cfg(test)]
     // fn thaw(&mut self) -> ();
-}
\ No newline at end of file
+}
+
+/// The read-only half of DharaNand: just enough to look sectors up and
+/// read them, through a map that's only borrowed immutably (see
+/// DharaMap::read_only()). Every DharaNand already has it. Implement it
+/// directly for a handle that can read the chip but not change it, such
+/// as a second driver instance for the same device.
+pub trait DharaNandRead {
+    /// As DharaNand::read(). Named apart from it so that a driver with
+    /// both traits in scope can still call nand.read() unqualified.
+    fn read_page(&mut self, page: DharaPage, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError>;
+}
+
+impl<T: DharaNand> DharaNandRead for T {
+    fn read_page(&mut self, page: DharaPage, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        self.read(page, offset, length, data)
+    }
+}
+
+/// Optional extension for chips that support partial-page programming.
+/// SubdividedNand needs this to present each ECC-correctable region of a
+/// physical page as its own page.
+pub trait DharaNandPartial: DharaNand {
+    /// Program a region of a page, leaving the rest of it untouched.
+    /// Regions of one page will be programmed in ascending order, and no
+    /// region will be reprogrammed.  If the operation fails, return
+    /// Err(BadBlock).
+    fn prog_partial(&mut self, page: DharaPage, offset: usize, data: &[u8]) -> Result<(),DharaError>;
+
+    /// Check that a region of the given page is erased.
+    fn is_free_partial(&mut self, page: DharaPage, offset: usize, length: usize) -> bool;
+
+    /// Copy a region of one page into a region of another, with ECC.
+    fn copy_partial(&mut self, src: DharaPage, src_offset: usize,
+        dst: DharaPage, dst_offset: usize, length: usize) -> Result<(),DharaError>;
+}
@@ -0,0 +1,110 @@
+// Recording map operations so that a failing sequence, e.g. from a fuzz
+// run, can be played back as a regression test.
+
+use alloc::vec::Vec;
+use core::borrow::BorrowMut;
+use crate::nand::DharaNand;
+use crate::{DharaError, DharaMap, DharaSector};
+
+/// One call on the map, with its arguments.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MapOp {
+    Write(DharaSector, Vec<u8>),
+    Trim(DharaSector),
+    Read(DharaSector),
+    Sync,
+    Gc,
+    Resume,
+}
+
+/// Wraps a map, and logs each operation made through it before carrying
+/// it out, so a failed operation is the last one logged. Print the log
+/// with {:?} and paste it into a test that calls replay().
+pub struct OpRecorder<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]> = [u8; N]> {
+    map: DharaMap<N,T,B>,
+    ops: Vec<MapOp>,
+}
+
+impl<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> OpRecorder<N,T,B> {
+    pub fn new(map: DharaMap<N,T,B>) -> Self {
+        OpRecorder {
+            map: map,
+            ops: Vec::new(),
+        }
+    }
+
+    /// The operations so far, oldest first.
+    pub fn ops(&self) -> &[MapOp] {
+        &self.ops
+    }
+
+    /// Take the log, leaving it empty.
+    pub fn take_ops(&mut self) -> Vec<MapOp> {
+        core::mem::take(&mut self.ops)
+    }
+
+    /// Get the wrapped map back.
+    pub fn into_inner(self) -> DharaMap<N,T,B> {
+        self.map
+    }
+
+    pub fn inner(&self) -> &DharaMap<N,T,B> {
+        &self.map
+    }
+
+    /// Operations made directly on the map aren't logged.
+    pub fn inner_mut(&mut self) -> &mut DharaMap<N,T,B> {
+        &mut self.map
+    }
+
+    pub fn write(&mut self, sector: DharaSector, data: &[u8]) -> Result<(), DharaError> {
+        self.ops.push(MapOp::Write(sector, data.to_vec()));
+        self.map.write(sector, data)
+    }
+
+    pub fn trim(&mut self, sector: DharaSector) -> Result<(), DharaError> {
+        self.ops.push(MapOp::Trim(sector));
+        self.map.trim(sector)
+    }
+
+    pub fn read(&mut self, sector: DharaSector, data: &mut [u8]) -> Result<(), DharaError> {
+        self.ops.push(MapOp::Read(sector));
+        self.map.read(sector, data)
+    }
+
+    pub fn sync(&mut self) -> Result<(), DharaError> {
+        self.ops.push(MapOp::Sync);
+        self.map.sync()
+    }
+
+    pub fn gc(&mut self) -> Result<(), DharaError> {
+        self.ops.push(MapOp::Gc);
+        self.map.gc()
+    }
+
+    pub fn resume(&mut self) -> Result<(), DharaError> {
+        self.ops.push(MapOp::Resume);
+        self.map.resume()
+    }
+}
+
+/// Carry out logged operations on a map, in order. Stops at the first
+/// that fails, returning its index in ops along with the error, so a
+/// sequence that's expected to fail can check where. Reads are carried
+/// out (they can fail, or fill the find cache) but the data is dropped.
+pub fn replay<const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>>(map: &mut DharaMap<N,T,B>, ops: &[MapOp]) -> Result<(), (usize, DharaError)> {
+    let mut buf: [u8; N] = [0u8; N];
+
+    for (i, op) in ops.iter().enumerate() {
+        let result = match op {
+            MapOp::Write(sector, data) => map.write(*sector, data),
+            MapOp::Trim(sector) => map.trim(*sector),
+            MapOp::Read(sector) => map.read(*sector, &mut buf),
+            MapOp::Sync => map.sync(),
+            MapOp::Gc => map.gc(),
+            MapOp::Resume => map.resume(),
+        };
+        result.map_err(|e| (i, e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+// Adapter that splits each physical page into independent ECC regions.
+
+use crate::nand::{DharaBlock, DharaNand, DharaNandPartial, DharaPage};
+use crate::DharaError;
+
+/// Presents each physical page of the wrapped chip as DIV smaller pages.
+/// This is useful for chips with large pages but ECC that only corrects
+/// a few bits per region: every region becomes its own dhara page, so an
+/// uncorrectable error costs one small page rather than the whole
+/// physical page.
+///
+/// The wrapped chip must support partial-page programming (see
+/// DharaNandPartial) with at least DIV programs per page. Dhara programs
+/// pages in order, so the regions of a physical page are always programmed
+/// in ascending order.
+///
+/// Generics:
+/// T: The physical NAND driver.
+/// DIV: The number of regions per physical page. Must be a power of two.
+pub struct SubdividedNand<T: DharaNandPartial, const DIV: u32> {
+    nand: T,
+}
+
+impl<T: DharaNandPartial, const DIV: u32> SubdividedNand<T, DIV> {
+    // Evaluated at compile time, so a bad DIV is a build error.
+    const LOG2_DIV: u8 = {
+        assert!(DIV.is_power_of_two(), "DIV must be a power of two");
+        DIV.trailing_zeros() as u8
+    };
+
+    pub fn new(nand: T) -> Self {
+        SubdividedNand { nand: nand }
+    }
+
+    /// Get the wrapped driver back.
+    pub fn into_inner(self) -> T {
+        self.nand
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.nand
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.nand
+    }
+
+    // Split a logical page into its physical page and the byte offset
+    // of its region within that page.
+    fn locate(&self, page: DharaPage) -> (DharaPage, usize) {
+        let region = (page & (DIV - 1)) as usize;
+        (page >> Self::LOG2_DIV, region << self.get_log2_page_size())
+    }
+}
+
+impl<T: DharaNandPartial, const DIV: u32> DharaNand for SubdividedNand<T, DIV> {
+    fn get_log2_page_size(&self) -> u8 {
+        self.nand.get_log2_page_size() - Self::LOG2_DIV
+    }
+
+    fn get_log2_ppb(&self) -> u8 {
+        self.nand.get_log2_ppb() + Self::LOG2_DIV
+    }
+
+    // Blocks are the same on both sides of the adapter.
+    fn get_num_blocks(&self) -> u32 {self.nand.get_num_blocks()}
+    fn is_bad(&mut self, blk: DharaBlock) -> bool {self.nand.is_bad(blk)}
+    fn mark_bad(&mut self, blk: DharaBlock) -> () {self.nand.mark_bad(blk)}
+    fn erase(&mut self, blk: DharaBlock) -> Result<(),DharaError> {self.nand.erase(blk)}
+    fn erase_verify(&mut self, blk: DharaBlock) -> Result<(),DharaError> {self.nand.erase_verify(blk)}
+    fn erase_all(&mut self) -> Result<(),DharaError> {self.nand.erase_all()}
+
+    fn prog(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError> {
+        let (phys, offset) = self.locate(page);
+        self.nand.prog_partial(phys, offset, data)
+    }
+
+    fn is_free(&mut self, page: DharaPage) -> bool {
+        let (phys, offset) = self.locate(page);
+        let length = self.page_size();
+        self.nand.is_free_partial(phys, offset, length)
+    }
+
+    fn read(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        let (phys, base) = self.locate(page);
+        self.nand.read(phys, base + offset, length, data)
+    }
+
+    fn read_ecc(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<u32, DharaError> {
+        let (phys, base) = self.locate(page);
+        self.nand.read_ecc(phys, base + offset, length, data)
+    }
+
+    fn copy(&mut self, src: DharaPage, dst: DharaPage) -> Result<(),DharaError> {
+        let (src_phys, src_offset) = self.locate(src);
+        let (dst_phys, dst_offset) = self.locate(dst);
+        let length = self.page_size();
+        self.nand.copy_partial(src_phys, src_offset, dst_phys, dst_offset, length)
+    }
+}
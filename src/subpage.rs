@@ -0,0 +1,514 @@
+// Sectors smaller than a page, packed several to a page.
+
+use core::borrow::BorrowMut;
+use crate::bytes::{dhara_r32, dhara_w32};
+use crate::nand::{DharaNand, DharaPage};
+use crate::{DharaError, DharaMap, DharaSector, DHARA_SECTOR_NONE};
+
+// How many packed pages one round of garbage collection merges, at most.
+const GC_VICTIMS: usize = 8;
+
+/// Presents a map of N-byte pages as a map of S-byte sectors, for
+/// workloads with records much smaller than a page.
+///
+/// Sectors are packed into pages in the order they're written, whatever
+/// their numbers: writes collect in a page held in RAM, which goes to the
+/// map once full, or at flush() or sync(). Each packed page ends with a
+/// footer naming the sector in each slot, so a page holds N / (S + 4)
+/// sectors. Where each sector is, as (packed page, slot), is kept in index
+/// pages of N / 4 entries each, stored in the same map under keys of their
+/// own. One index page is cached, so writes to nearby sectors share its
+/// updates.
+///
+/// Rewriting or trimming a sector leaves its old slot dead. When space runs
+/// short, the oldest packed pages have their live slots moved into a new
+/// one, much as the map's own garbage collection moves pages, and are then
+/// trimmed. A page is only trimmed once the index says where its sectors
+/// went, so a checkpoint in between can't lose what was synced.
+///
+/// Nothing is durable until sync(), and a power failure before then may
+/// keep some later writes but not earlier ones.
+///
+/// get_capacity() assumes sectors are numbered from 0 without large gaps;
+/// every N / 4 of the numbering in use costs an index page.
+///
+/// Generics:
+/// N: The page size, as for DharaMap.
+/// S: The sector size. At most N - 4.
+pub struct SubpageMap<const N: usize,const S: usize,T: DharaNand,B: BorrowMut<[u8; N]> = [u8; N]> {
+    map: DharaMap<N,T,B>,
+    // The packed page being filled: slots, then the footer.
+    pending: [u8; N],
+    pending_len: usize,
+    // One index page, which may have changes not yet in the map.
+    index: [u8; N],
+    index_key: Option<DharaSector>,
+    index_dirty: bool,
+    // Where the next packed page is allocated. Those at and after it, round
+    // the ring, are the oldest, and are collected first.
+    next_id: DharaSector,
+    // Mapped sectors, counting those only in the pending page.
+    count: DharaSector,
+}
+
+impl<const N: usize,const S: usize,T: DharaNand,B: BorrowMut<[u8; N]>> SubpageMap<N,S,T,B> {
+    // Evaluated at compile time, so a bad S is a build error.
+    const SLOTS: usize = {
+        assert!(S > 0 && S + 4 <= N && N >= 8, "S must be from 1 to N - 4");
+        N / (S + 4)
+    };
+    // Where a packed page's footer starts.
+    const FOOTER: usize = Self::SLOTS * S;
+    // Index entries to a page.
+    const ENTRIES: usize = N / 4;
+    // Packed pages are keyed from here up, index pages below.
+    const DATA_BASE: DharaSector = 1 << 31;
+    // How many packed page keys there are. An index entry is
+    // id * SLOTS + slot, which must stay below DHARA_SECTOR_NONE.
+    const RING: DharaSector = {
+        let fit = (DHARA_SECTOR_NONE - 1) / Self::SLOTS as DharaSector;
+        if fit < Self::DATA_BASE - 1 { fit } else { Self::DATA_BASE - 1 }
+    };
+    // Keys kept free: one for the pending page, one for garbage collection
+    // to move slots into, and one for each index page the pending page's
+    // sectors might need.
+    const RESERVE: DharaSector = Self::SLOTS as DharaSector + 2;
+
+    /// Wrap a map, normally one that has just been resumed. The index is
+    /// read to count the sectors in it, and the packed pages are looked up
+    /// to carry on allocating after the newest, so that garbage collection
+    /// still takes the oldest first.
+    pub fn new(map: DharaMap<N,T,B>) -> Result<Self, DharaError> {
+        let mut m = SubpageMap {
+            map: map,
+            pending: [0xffu8; N],
+            pending_len: 0,
+            index: [0xffu8; N],
+            index_key: None,
+            index_dirty: false,
+            next_id: 0,
+            count: 0,
+        };
+        m.recount()?;
+        Ok(m)
+    }
+
+    /// Get the wrapped map back. Call flush() first, or the last writes
+    /// are lost.
+    pub fn into_inner(self) -> DharaMap<N,T,B> {
+        self.map
+    }
+
+    pub fn inner(&self) -> &DharaMap<N,T,B> {
+        &self.map
+    }
+
+    /// Its sectors are this map's packed and index pages, so writing or
+    /// trimming them directly breaks it; flush() first for anything else.
+    pub fn inner_mut(&mut self) -> &mut DharaMap<N,T,B> {
+        &mut self.map
+    }
+
+    /// How many sectors share a page.
+    pub fn sectors_per_page(&self) -> usize {
+        Self::SLOTS
+    }
+
+    /// The number of sectors the map has room for. Beyond the index pages,
+    /// this leaves room for dead slots in the ratio the map was created
+    /// with, so that garbage collection can make progress.
+    pub fn get_capacity(&self) -> DharaSector {
+        let pages = self.map.get_capacity().saturating_sub(Self::RESERVE) as u64;
+        let ratio = self.map.get_gc_ratio() as u64;
+        let slots = Self::SLOTS as u64;
+        let entries = Self::ENTRIES as u64;
+
+        // Each sector takes 1 / slots of a packed page, plus dead slots in
+        // the ratio 1 : ratio, and 1 / entries of an index page.
+        let cap = pages * ratio * slots * entries / ((ratio + 1) * entries + ratio * slots);
+        cap.min(DHARA_SECTOR_NONE as u64) as DharaSector
+    }
+
+    /// The number of sectors mapped.
+    pub fn get_size(&self) -> DharaSector {
+        self.count
+    }
+
+    /// The physical page holding a sector, and its slot within it, as
+    /// (page, byte offset). Pending writes are flushed first, so that the
+    /// answer is on flash. DharaError::NotFound if the sector isn't mapped.
+    pub fn find(&mut self, sector: DharaSector) -> Result<(DharaPage, usize), DharaError> {
+        self.flush()?;
+        let entry = self.entry(sector)?;
+        if entry == DHARA_SECTOR_NONE {
+            return Err(DharaError::NotFound);
+        }
+        let (id, slot) = Self::split(entry);
+        let page = self.map.find(Self::DATA_BASE + id)?;
+        Ok((page, slot * S))
+    }
+
+    /// Read a sector. data must be exactly S bytes long. An unmapped one
+    /// reads as blank (0xff).
+    pub fn read(&mut self, sector: DharaSector, data: &mut [u8]) -> Result<(), DharaError> {
+        if data.len() != S {
+            return Err(DharaError::BadLength);
+        }
+
+        if let Some(slot) = self.pending_slot(sector) {
+            data.copy_from_slice(&self.pending[slot * S..(slot + 1) * S]);
+            return Ok(());
+        }
+
+        let entry = self.entry(sector)?;
+        if entry == DHARA_SECTOR_NONE {
+            data.fill(0xFF);
+            return Ok(());
+        }
+
+        // Only the slot is read, not the whole page.
+        let (id, slot) = Self::split(entry);
+        let page = self.map.find(Self::DATA_BASE + id)?;
+        self.map.journal.nand.read(page, slot * S, S, data)
+    }
+
+    /// Write a sector. data must be exactly S bytes long.
+    pub fn write(&mut self, sector: DharaSector, data: &[u8]) -> Result<(), DharaError> {
+        if data.len() != S {
+            return Err(DharaError::BadLength);
+        }
+        if sector == DHARA_SECTOR_NONE {
+            return Err(DharaError::BadSector);
+        }
+
+        // Rewritten before it went out: no need for another slot.
+        if let Some(slot) = self.pending_slot(sector) {
+            self.pending[slot * S..(slot + 1) * S].copy_from_slice(data);
+            return Ok(());
+        }
+
+        let mapped = self.entry(sector)? != DHARA_SECTOR_NONE;
+        if !mapped && self.count >= self.get_capacity() {
+            return Err(DharaError::MapFull);
+        }
+
+        if self.pending_len == Self::SLOTS {
+            self.flush_pending()?;
+        }
+        if self.pending_len == 0 {
+            self.make_room()?;
+        }
+
+        let slot = self.pending_len;
+        self.pending[slot * S..(slot + 1) * S].copy_from_slice(data);
+        self.set_footer(slot, sector);
+        self.pending_len += 1;
+        if !mapped {
+            self.count += 1;
+        }
+        Ok(())
+    }
+
+    /// Make a sector read as blank again. Its slot's space is given back
+    /// by garbage collection.
+    pub fn trim(&mut self, sector: DharaSector) -> Result<(), DharaError> {
+        let mut trimmed = false;
+
+        if let Some(slot) = self.pending_slot(sector) {
+            self.set_footer(slot, DHARA_SECTOR_NONE);
+            trimmed = true;
+        }
+        if self.entry(sector)? != DHARA_SECTOR_NONE {
+            self.set_entry(sector, DHARA_SECTOR_NONE)?;
+            trimmed = true;
+        }
+
+        if trimmed {
+            self.count -= 1;
+        }
+        Ok(())
+    }
+
+    /// Write the pending page and index changes, if any, to the map. They
+    /// aren't durable until the map is synced.
+    pub fn flush(&mut self) -> Result<(), DharaError> {
+        self.flush_pending()?;
+        self.write_back()
+    }
+
+    /// flush(), then sync the map. Once this returns successfully, all
+    /// writes so far are durable.
+    pub fn sync(&mut self) -> Result<(), DharaError> {
+        self.flush()?;
+        self.map.sync()
+    }
+
+    /// Resume the map, dropping any pending writes.
+    pub fn resume(&mut self) -> Result<(), DharaError> {
+        self.pending.fill(0xFF);
+        self.pending_len = 0;
+        self.index_key = None;
+        self.index_dirty = false;
+        self.map.resume()?;
+        self.recount()
+    }
+
+    // An index entry's packed page id and slot.
+    fn split(entry: DharaSector) -> (DharaSector, usize) {
+        let slots = Self::SLOTS as DharaSector;
+        (entry / slots, (entry % slots) as usize)
+    }
+
+    // A sector's index page key, and the byte offset of its entry.
+    fn locate(sector: DharaSector) -> (DharaSector, usize) {
+        let entries = Self::ENTRIES as DharaSector;
+        (sector / entries, (sector % entries) as usize * 4)
+    }
+
+    fn footer(&self, slot: usize) -> DharaSector {
+        let at = Self::FOOTER + slot * 4;
+        dhara_r32(&self.pending[at..at + 4])
+    }
+
+    fn set_footer(&mut self, slot: usize, sector: DharaSector) -> () {
+        let at = Self::FOOTER + slot * 4;
+        dhara_w32(&mut self.pending[at..at + 4], sector);
+    }
+
+    // The pending page's slot holding the sector, if any.
+    fn pending_slot(&self, sector: DharaSector) -> Option<usize> {
+        (0..self.pending_len).find(|&slot| self.footer(slot) == sector)
+    }
+
+    // The index entry for a sector, or DHARA_SECTOR_NONE. Outside the
+    // cached index page, only the entry itself is read.
+    fn entry(&mut self, sector: DharaSector) -> Result<DharaSector, DharaError> {
+        let (key, offset) = Self::locate(sector);
+        if self.index_key == Some(key) {
+            return Ok(dhara_r32(&self.index[offset..offset + 4]));
+        }
+
+        match self.map.find(key) {
+            Err(DharaError::NotFound) => Ok(DHARA_SECTOR_NONE),
+            Err(e) => Err(e),
+            Ok(page) => {
+                let mut buf = [0u8; 4];
+                self.map.journal.nand.read(page, offset, 4, &mut buf)?;
+                Ok(dhara_r32(&buf))
+            },
+        }
+    }
+
+    fn set_entry(&mut self, sector: DharaSector, entry: DharaSector) -> Result<(), DharaError> {
+        let (key, offset) = Self::locate(sector);
+        if self.index_key != Some(key) {
+            self.write_back()?;
+            // Unmapped keys read as blank, i.e. with every entry NONE.
+            self.index_key = None;
+            self.map.read(key, &mut self.index)?;
+            self.index_key = Some(key);
+        }
+        dhara_w32(&mut self.index[offset..offset + 4], entry);
+        self.index_dirty = true;
+        Ok(())
+    }
+
+    // Write the cached index page to the map, if it has changed. One with
+    // no entries left is trimmed instead.
+    fn write_back(&mut self) -> Result<(), DharaError> {
+        let key = match self.index_key {
+            Some(key) if self.index_dirty => key,
+            _ => return Ok(()),
+        };
+
+        if self.index.iter().all(|&b| b == 0xFF) {
+            self.map.trim(key)?;
+        } else {
+            self.map.write(key, &self.index)?;
+        }
+        self.index_dirty = false;
+        Ok(())
+    }
+
+    // Write the pending page out as a new packed page, and point the index
+    // at it. On failure it stays pending, to be written again; entries
+    // already pointing at the copy written are pointed at the next one.
+    fn flush_pending(&mut self) -> Result<(), DharaError> {
+        if (0..self.pending_len).any(|slot| self.footer(slot) != DHARA_SECTOR_NONE) {
+            let id = self.alloc()?;
+            self.map.write(Self::DATA_BASE + id, &self.pending)?;
+
+            for slot in 0..self.pending_len {
+                let sector = self.footer(slot);
+                if sector != DHARA_SECTOR_NONE {
+                    self.set_entry(sector, id * Self::SLOTS as DharaSector + slot as DharaSector)?;
+                }
+            }
+        }
+
+        self.pending.fill(0xFF);
+        self.pending_len = 0;
+        Ok(())
+    }
+
+    // The next unused packed page id.
+    fn alloc(&mut self) -> Result<DharaSector, DharaError> {
+        // There are far fewer packed pages than ids, so this ends.
+        loop {
+            let id = self.next_id;
+            self.next_id = (id + 1) % Self::RING;
+            match self.map.find(Self::DATA_BASE + id) {
+                Err(DharaError::NotFound) => return Ok(id),
+                Err(e) => return Err(e),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    // Collect garbage until there are RESERVE keys free. Only done with
+    // nothing pending, since collection fills the pending page itself.
+    fn make_room(&mut self) -> Result<(), DharaError> {
+        let mut rounds: DharaSector = 0;
+        while self.map.get_capacity().saturating_sub(self.map.get_size()) < Self::RESERVE {
+            // Each round either frees a page or moves the oldest one to
+            // the front, so once round them all, nothing more will come.
+            if rounds > self.map.get_size() || !self.gc_round()? {
+                return Err(DharaError::MapFull);
+            }
+            rounds += 1;
+        }
+        Ok(())
+    }
+
+    // Move the live slots of the oldest packed pages into the pending page
+    // until it's full, then write it and trim the pages left with none.
+    // A page only partly moved is collected first next time. Returns false
+    // if there was nothing to collect.
+    fn gc_round(&mut self) -> Result<bool, DharaError> {
+        let mut victims = [0 as DharaSector; GC_VICTIMS];
+        let mut n = 0;
+        let mut from = self.next_id;
+
+        while n < GC_VICTIMS && self.pending_len < Self::SLOTS {
+            let id = match self.oldest_from(from)? {
+                Some(id) if !victims[..n].contains(&id) => id,
+                _ => break,
+            };
+            let page = self.map.find(Self::DATA_BASE + id)?;
+
+            let mut drained = true;
+            for slot in 0..Self::SLOTS {
+                if let Some(sector) = self.live_sector(page, id, slot)? {
+                    if self.pending_len == Self::SLOTS {
+                        drained = false;
+                        break;
+                    }
+                    let at = self.pending_len * S;
+                    self.map.journal.nand.read(page, slot * S, S, &mut self.pending[at..at + S])?;
+                    self.set_footer(self.pending_len, sector);
+                    self.pending_len += 1;
+                }
+            }
+            if !drained {
+                break;
+            }
+            victims[n] = id;
+            n += 1;
+            from = (id + 1) % Self::RING;
+        }
+
+        if n == 0 && self.pending_len == 0 {
+            return Ok(false);
+        }
+
+        // The moved sectors' index entries must be in the map before the
+        // pages they were in are gone.
+        self.flush_pending()?;
+        self.write_back()?;
+        for &id in victims[..n].iter() {
+            self.map.trim(Self::DATA_BASE + id)?;
+        }
+        Ok(true)
+    }
+
+    // The first packed page id at or after from, round the ring.
+    fn oldest_from(&mut self, from: DharaSector) -> Result<Option<DharaSector>, DharaError> {
+        let key = match self.map.find_nearest(Self::DATA_BASE + from)? {
+            Some(key) => Some(key),
+            None => self.map.find_nearest(Self::DATA_BASE)?,
+        };
+        Ok(key.map(|key| key - Self::DATA_BASE))
+    }
+
+    // The sector in a packed page's slot, if it's still the live copy.
+    fn live_sector(&mut self, page: DharaPage, id: DharaSector, slot: usize) -> Result<Option<DharaSector>, DharaError> {
+        let mut buf = [0u8; 4];
+        self.map.journal.nand.read(page, Self::FOOTER + slot * 4, 4, &mut buf)?;
+        let sector = dhara_r32(&buf);
+
+        if sector == DHARA_SECTOR_NONE || self.pending_slot(sector).is_some() {
+            return Ok(None);
+        }
+        let here = id * Self::SLOTS as DharaSector + slot as DharaSector;
+        if self.entry(sector)? == here {
+            Ok(Some(sector))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Count the sectors in the index, and find where allocation left off.
+    // This uses the index page buffer, so the cache must hold nothing
+    // unwritten.
+    fn recount(&mut self) -> Result<(), DharaError> {
+        self.count = 0;
+        self.index_key = None;
+
+        let mut key = 0;
+        while let Some(found) = self.map.find_nearest(key)? {
+            if found >= Self::DATA_BASE {
+                break;
+            }
+            self.map.read(found, &mut self.index)?;
+            self.count += self.index.chunks(4)
+                .filter(|entry| dhara_r32(entry) != DHARA_SECTOR_NONE)
+                .count() as DharaSector;
+            key = found + 1;
+        }
+        self.index.fill(0xFF);
+        self.next_id = self.newest_end()?;
+        Ok(())
+    }
+
+    // The id after the newest packed page, or 0 if there are none. Ids are
+    // handed out in order round the ring, and there are far fewer packed
+    // pages than ids, so the longest run of free ids is the one between
+    // the newest page and the oldest. This costs a find for every packed
+    // page.
+    fn newest_end(&mut self) -> Result<DharaSector, DharaError> {
+        let mut first: Option<DharaSector> = None;
+        let mut prev: DharaSector = 0;
+        let mut longest: DharaSector = 0;
+        let mut end: DharaSector = 0;
+
+        let mut key = Self::DATA_BASE;
+        while let Some(found) = self.map.find_nearest(key)? {
+            let id = found - Self::DATA_BASE;
+            if first.is_some() && id - prev > longest {
+                longest = id - prev;
+                end = prev + 1;
+            }
+            first.get_or_insert(id);
+            prev = id;
+            key = found + 1;
+        }
+
+        // The run that wraps from the last id round to the first.
+        if let Some(first) = first {
+            if first + Self::RING - prev > longest {
+                end = (prev + 1) % Self::RING;
+            }
+        }
+        Ok(end)
+    }
+}
@@ -0,0 +1,83 @@
+// Looking sectors up through a map that's only borrowed immutably.
+
+use core::borrow::BorrowMut;
+use crate::journal::{DHARA_META_SIZE, DHARA_PAGE_NONE};
+use crate::nand::{DharaNand, DharaNandRead, DharaPage};
+use crate::{d_bit, is_filler_meta, meta_get_alt, meta_get_id, DharaError, DharaMap, DharaSector, DHARA_RADIX_DEPTH};
+
+/// A read-only view of a map, from DharaMap::read_only(). NAND reads go
+/// through a separate handle, so the map itself is only borrowed
+/// immutably, and several views can share it. The map mustn't be written
+/// (or resumed) while a view is in use; the borrow checker sees to that.
+///
+/// The find cache is neither used nor filled.
+pub struct ReadOnlyMap<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>,R: DharaNandRead> {
+    map: &'a DharaMap<N,T,B>,
+    nand: &'a mut R,
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>,R: DharaNandRead> ReadOnlyMap<'a,N,T,B,R> {
+    pub(crate) fn new(map: &'a DharaMap<N,T,B>, nand: &'a mut R) -> Self {
+        ReadOnlyMap {
+            map: map,
+            nand: nand,
+        }
+    }
+
+    /// As DharaMap::find().
+    pub fn find(&mut self, target: DharaSector) -> Result<DharaPage, DharaError> {
+        let journal = &self.map.journal;
+        journal.checked()?;
+
+        // The same walk as the map's trace_path(), without building a new
+        // path on the way.
+        let mut meta: [u8; DHARA_META_SIZE] = [0u8; DHARA_META_SIZE];
+        let mut p = journal.get_root();
+        if p == DHARA_PAGE_NONE {
+            return Err(DharaError::NotFound);
+        }
+        journal.read_meta_with(self.nand, p, &mut meta)?;
+
+        for depth in 0..DHARA_RADIX_DEPTH {
+            if is_filler_meta(&meta) {
+                return Err(DharaError::NotFound);
+            }
+            let id = meta_get_id(&meta);
+
+            if (target ^ id) & d_bit(depth) != 0 {
+                p = meta_get_alt(&meta, depth);
+                if p == DHARA_PAGE_NONE {
+                    return Err(DharaError::NotFound);
+                }
+                journal.read_meta_with(self.nand, p, &mut meta)?;
+            }
+        }
+        Ok(p)
+    }
+
+    /// Whether the sector is mapped.
+    pub fn exists(&mut self, sector: DharaSector) -> Result<bool, DharaError> {
+        match self.find(sector) {
+            Ok(_) => Ok(true),
+            Err(DharaError::NotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// As DharaMap::read(): an unmapped sector reads as blank (0xff), and
+    /// data that isn't one page long gives DharaError::BadLength.
+    pub fn read(&mut self, sector: DharaSector, data: &mut [u8]) -> Result<(), DharaError> {
+        if data.len() != self.map.journal.nand.page_size() {
+            return Err(DharaError::BadLength);
+        }
+
+        match self.find(sector) {
+            Err(DharaError::NotFound) => {
+                data.fill(0xFF);
+                Ok(())
+            },
+            Err(e) => Err(e),
+            Ok(page) => self.nand.read_page(page, 0, self.map.journal.nand.page_size(), data),
+        }
+    }
+}
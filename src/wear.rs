@@ -0,0 +1,138 @@
+// NAND wrapper that counts erases per block, for wear diagnostics.
+
+use crate::nand::{DharaBlock, DharaNand, DharaNandPartial, DharaPage};
+use crate::DharaError;
+
+/// Wraps a NAND driver and keeps an erase count for each block, which is
+/// the figure that wears a chip out. Dhara's log already spreads erases
+/// evenly over the good blocks, so this is for observing that in the
+/// field rather than for acting on it.
+///
+/// Every erase dhara asks for is counted, including ones that fail, since
+/// an attempt wears the block either way. The counts live in RAM and
+/// start from zero; persisting them is up to the caller.
+///
+/// Generics:
+/// BLOCKS: Room for this many counters. Blocks at or past it aren't
+/// counted, so make it at least the chip's block count.
+pub struct WearNand<T: DharaNand, const BLOCKS: usize> {
+    nand: T,
+    erases: [u32; BLOCKS],
+}
+
+impl<T: DharaNand, const BLOCKS: usize> WearNand<T, BLOCKS> {
+    pub fn new(nand: T) -> Self {
+        WearNand {
+            nand: nand,
+            erases: [0u32; BLOCKS],
+        }
+    }
+
+    /// The erase count of each block, indexed by block number.
+    pub fn erase_counts(&self) -> &[u32] {
+        let blocks = (self.nand.get_num_blocks() as usize).min(BLOCKS);
+        &self.erases[..blocks]
+    }
+
+    /// The lowest and highest erase counts over the blocks that aren't
+    /// bad, or None if every block is. A wide gap means wear is uneven.
+    pub fn erase_spread(&mut self) -> Option<(u32, u32)> {
+        let blocks = (self.nand.get_num_blocks() as usize).min(BLOCKS);
+        let mut spread: Option<(u32, u32)> = None;
+        for blk in 0..blocks {
+            if self.nand.is_bad(blk as DharaBlock) {
+                continue;
+            }
+            let count = self.erases[blk];
+            spread = match spread {
+                None => Some((count, count)),
+                Some((lo, hi)) => Some((lo.min(count), hi.max(count))),
+            };
+        }
+        return spread;
+    }
+
+    /// Start counting again from zero, e.g. before restoring counts saved
+    /// elsewhere with set_erase_count().
+    pub fn reset_erase_counts(&mut self) -> () {
+        self.erases = [0u32; BLOCKS];
+    }
+
+    /// Set one block's count. Out of range blocks are ignored.
+    pub fn set_erase_count(&mut self, blk: DharaBlock, count: u32) -> () {
+        if let Some(c) = self.erases.get_mut(blk as usize) {
+            *c = count;
+        }
+    }
+
+    /// Get the wrapped driver back.
+    pub fn into_inner(self) -> T {
+        self.nand
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.nand
+    }
+
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.nand
+    }
+
+    fn tally(&mut self, blk: DharaBlock) -> () {
+        if let Some(c) = self.erases.get_mut(blk as usize) {
+            *c = c.saturating_add(1);
+        }
+    }
+}
+
+impl<T: DharaNand, const BLOCKS: usize> DharaNand for WearNand<T, BLOCKS> {
+    fn get_log2_page_size(&self) -> u8 {self.nand.get_log2_page_size()}
+    fn get_log2_ppb(&self) -> u8 {self.nand.get_log2_ppb()}
+    fn get_num_blocks(&self) -> u32 {self.nand.get_num_blocks()}
+    fn is_bad(&mut self, blk: DharaBlock) -> bool {self.nand.is_bad(blk)}
+    fn mark_bad(&mut self, blk: DharaBlock) -> () {self.nand.mark_bad(blk)}
+
+    fn erase(&mut self, blk: DharaBlock) -> Result<(),DharaError> {
+        self.tally(blk);
+        self.nand.erase(blk)
+    }
+
+    // Forwarded, so a driver's own verify is still used.
+    fn erase_verify(&mut self, blk: DharaBlock) -> Result<(),DharaError> {
+        self.tally(blk);
+        self.nand.erase_verify(blk)
+    }
+
+    // erase_all() isn't forwarded: the default goes through erase(), so
+    // each block is tallied, where a driver's bulk erase would hide which
+    // blocks it erased.
+
+    fn prog(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError> {self.nand.prog(page, data)}
+    fn is_free(&mut self, page: DharaPage) -> bool {self.nand.is_free(page)}
+
+    fn read(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        self.nand.read(page, offset, length, data)
+    }
+
+    fn read_ecc(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<u32, DharaError> {
+        self.nand.read_ecc(page, offset, length, data)
+    }
+
+    fn copy(&mut self, src: DharaPage, dst: DharaPage) -> Result<(),DharaError> {self.nand.copy(src, dst)}
+}
+
+// So that wear tracking can also sit underneath a SubdividedNand.
+impl<T: DharaNandPartial, const BLOCKS: usize> DharaNandPartial for WearNand<T, BLOCKS> {
+    fn prog_partial(&mut self, page: DharaPage, offset: usize, data: &[u8]) -> Result<(),DharaError> {
+        self.nand.prog_partial(page, offset, data)
+    }
+
+    fn is_free_partial(&mut self, page: DharaPage, offset: usize, length: usize) -> bool {
+        self.nand.is_free_partial(page, offset, length)
+    }
+
+    fn copy_partial(&mut self, src: DharaPage, src_offset: usize,
+            dst: DharaPage, dst_offset: usize, length: usize) -> Result<(),DharaError> {
+        self.nand.copy_partial(src, src_offset, dst, dst_offset, length)
+    }
+}
@@ -0,0 +1,93 @@
+// Writing sectors from data that arrives a little at a time.
+
+use core::borrow::BorrowMut;
+use crate::nand::DharaNand;
+use crate::{DharaError, DharaMap, DharaSector};
+
+/// What finish() pads a last, partial page out with: the value unwritten
+/// sectors read as, as write_all() does.
+pub const SECTOR_WRITER_PAD: u8 = 0xFF;
+
+/// Collects data pushed in pieces of any size into a page buffer, and
+/// writes each page to the map as it fills: the first to the starting
+/// sector, the next to the one after, and so on, as write_all() would.
+/// finish() writes the last, partial page. Get one with
+/// DharaMap::sector_writer().
+///
+/// Like write(), nothing is durable until the map is synced. Data pushed
+/// since the last full page is only held here, so dropping the writer
+/// without finish() loses it.
+pub struct SectorWriter<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> {
+    map: &'a mut DharaMap<N,T,B>,
+    sector: DharaSector,
+    buf: [u8; N],
+    len: usize,
+    pushed: usize,
+    written: DharaSector,
+}
+
+impl<'a,const N: usize,T: DharaNand,B: BorrowMut<[u8; N]>> SectorWriter<'a,N,T,B> {
+    pub fn new(map: &'a mut DharaMap<N,T,B>, start: DharaSector) -> Self {
+        SectorWriter {
+            map: map,
+            sector: start,
+            buf: [SECTOR_WRITER_PAD; N],
+            len: 0,
+            pushed: 0,
+            written: 0,
+        }
+    }
+
+    /// Add data, writing out each page as it fills. On failure, the page
+    /// that couldn't be written stays buffered, to be tried again by the
+    /// next push() or finish(), and the data after it isn't taken:
+    /// bytes_pushed() says how much was.
+    pub fn push(&mut self, mut data: &[u8]) -> Result<(), DharaError> {
+        while !data.is_empty() {
+            if self.len == N {
+                self.commit()?;
+            }
+
+            let n = data.len().min(N - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            self.pushed += n;
+            data = &data[n..];
+        }
+
+        // A full page goes out now, so finish() has nothing to pad.
+        if self.len == N {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Write out whatever is buffered, padded with SECTOR_WRITER_PAD, and
+    /// return the number of sectors written in all.
+    pub fn finish(mut self) -> Result<DharaSector, DharaError> {
+        if self.len > 0 {
+            self.buf[self.len..].fill(SECTOR_WRITER_PAD);
+            self.commit()?;
+        }
+        Ok(self.written)
+    }
+
+    /// The sector the buffered data will be written to.
+    pub fn sector(&self) -> DharaSector {
+        self.sector
+    }
+
+    /// How many bytes push() has taken, including any still buffered.
+    pub fn bytes_pushed(&self) -> usize {
+        self.pushed
+    }
+
+    fn commit(&mut self) -> Result<(), DharaError> {
+        self.map.write(self.sector, &self.buf)?;
+        self.len = 0;
+        self.written += 1;
+        // Written, so below DHARA_SECTOR_NONE; this can't overflow.
+        self.sector += 1;
+        Ok(())
+    }
+}
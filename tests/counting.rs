@@ -0,0 +1,371 @@
+mod sim;
+
+use dhara_rs::counting::{CountingNand, OpCounts};
+use dhara_rs::nand::{DharaBlock, DharaNand, DharaPage};
+use dhara_rs::wear::WearNand;
+use dhara_rs::{DharaError, DharaMap, DharaSector};
+use sim::{seq_assert, seq_gen, SimNand, NUM_BLOCKS, PAGE_SIZE};
+
+type CountingMap = DharaMap::<512, CountingNand<SimNand>>;
+
+#[test]
+fn counts_map_writes() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    map.journal.nand.reset_counts();
+    assert_eq!(map.journal.nand.counts(), OpCounts::default());
+
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(7, &mut buf);
+    map.write(7, &buf).expect("write");
+    let first = map.journal.nand.counts();
+    // The first write lands at the start of a block.
+    assert_eq!(first.erase, 1);
+    assert_eq!(first.prog, 1);
+
+    for s in 0..20 {
+        map.write(s, &buf).expect("write");
+    }
+    map.sync().expect("sync");
+    map.read(7, &mut buf).expect("read");
+
+    let after = map.journal.nand.counts();
+    assert!(after.prog > first.prog + 20); // Data plus checkpoints.
+    assert!(after.read > first.read);
+    assert!(after.read_bytes >= PAGE_SIZE);
+    assert_eq!(after.prog_fail, 0);
+}
+
+#[test]
+fn sync_if_dirty() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(3, &mut buf);
+    map.write(3, &buf).expect("write");
+
+    assert_eq!(map.sync_if_dirty(), Ok(true));
+    map.journal.nand.reset_counts();
+    assert_eq!(map.sync_if_dirty(), Ok(false));
+    assert_eq!(map.journal.nand.counts(), OpCounts::default());
+}
+
+#[test]
+fn write_amplification() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    map.journal.nand.reset_counts();
+    map.reset_user_writes();
+    assert_eq!(map.write_amplification(), 0.0);
+
+    // Cold data written once, then hot sectors rewritten until the
+    // journal has come round, so garbage collection has live data to move.
+    let mut buf = [0u8; PAGE_SIZE];
+    // Capacity grows once a full pass replaces the initial bad block
+    // guess, so take it once.
+    let cap = map.get_capacity();
+    let cold = cap / 2;
+    for s in 0..cold {
+        seq_gen(s as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    for i in 0..cap * 2 {
+        seq_gen(i as u64, &mut buf);
+        map.write(cold + i % 10, &buf).expect("write");
+    }
+    assert_eq!(map.get_user_writes(), (cold + cap * 2) as usize);
+    assert!(map.journal.nand.counts().copy > 0);
+    assert!(map.write_amplification() > 1.0);
+}
+
+#[test]
+fn find_cache() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    let mut buf = [0u8; PAGE_SIZE];
+    for s in 0..100 {
+        seq_gen(s as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    map.sync().expect("sync");
+    map.set_find_cache(true);
+
+    map.journal.nand.reset_counts();
+    let page = map.find(42).expect("find");
+    let first = map.journal.nand.counts().read;
+    assert!(first > 0);
+
+    map.journal.nand.reset_counts();
+    assert_eq!(map.find(42), Ok(page));
+    assert!(map.journal.nand.counts().read < first);
+
+    // A write in between must not leave the old location behind.
+    seq_gen(1042, &mut buf);
+    map.write(42, &buf).expect("write");
+    let moved = map.find(42).expect("find");
+    assert_ne!(moved, page);
+    assert_eq!(map.find(42), Ok(moved));
+    map.trim(42).expect("trim");
+    assert!(map.find(42).is_err());
+
+    // Nor a resume to an older state.
+    map.sync().expect("sync");
+    seq_gen(2042, &mut buf);
+    map.write(42, &buf).expect("write");
+    map.find(42).expect("find");
+    map.resume().expect("resume");
+    assert!(map.find(42).is_err());
+}
+
+#[test]
+fn find_cache_wrap() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    // A few hot sectors keep the journal a steady size, so that after a
+    // lap of the chip the same root, head and tail come round again.
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    let mut buf = [0u8; PAGE_SIZE];
+    let mut seeds = [0u64; 3];
+    let mut write = |map: &mut CountingMap, s: usize, seed: u64| {
+        seeds[s] = seed;
+        seq_gen(seed, &mut buf);
+        map.write(s as DharaSector, &buf).expect("write");
+        seeds
+    };
+    for i in 0..1000 {
+        write(&mut map, (i % 3) as usize, i);
+    }
+
+    map.set_find_cache(true);
+    map.find(0).expect("find");
+    let journal = |map: &CountingMap| (map.journal.get_root(), map.journal.get_head(), map.journal.get_tail());
+    let start = journal(&map);
+    let epoch = map.journal.get_epoch();
+
+    // Sector 0 is now only moved by garbage collection, so by the time
+    // the journal looks the same again, it's somewhere else.
+    let mut repeats = 0;
+    for i in 1000..3000 {
+        let seeds = write(&mut map, 1 + (i % 2) as usize, i);
+        if journal(&map) == start {
+            repeats += 1;
+            let mut data = [0u8; PAGE_SIZE];
+            map.read(0, &mut data).expect("read");
+            seq_assert(seeds[0], &data);
+        }
+    }
+    assert_ne!(map.journal.get_epoch(), epoch);
+    assert!(repeats > 0);
+}
+
+#[test]
+fn root_meta_cache() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    let mut buf = [0u8; PAGE_SIZE];
+    for s in 0..100 {
+        seq_gen(s as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+
+    map.journal.nand.reset_counts();
+    let page = map.find(42).expect("find");
+    let first = map.journal.nand.counts().read;
+
+    map.journal.nand.reset_counts();
+    assert_eq!(map.find(42), Ok(page));
+    assert_eq!(map.journal.nand.counts().read, first - 1);
+
+    // A new root has to be read afresh, and is then correct.
+    seq_gen(1042, &mut buf);
+    map.write(42, &buf).expect("write");
+    map.sync().expect("sync");
+    map.journal.nand.reset_counts();
+    let moved = map.find(42).expect("find");
+    let first = map.journal.nand.counts().read;
+    assert_ne!(moved, page);
+
+    map.journal.nand.reset_counts();
+    assert_eq!(map.find(42), Ok(moved));
+    assert_eq!(map.journal.nand.counts().read, first - 1);
+}
+
+#[test]
+fn find_root() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    let mut buf = [0u8; PAGE_SIZE];
+    for s in 0..100 {
+        seq_gen(s as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    map.sync().expect("sync");
+    seq_gen(1007, &mut buf);
+    map.write(7, &buf).expect("write");
+
+    // The sector just written is the root: at most its own metadata. Not
+    // synced, since sync() can move other sectors up past it.
+    map.journal.nand.reset_counts();
+    let hot = map.find(7).expect("find");
+    let hot_reads = map.journal.nand.counts().read;
+    assert!(hot_reads <= 1);
+
+    map.journal.nand.reset_counts();
+    map.find(42).expect("find");
+    assert!(map.journal.nand.counts().read > hot_reads);
+
+    // The path built for a rewrite of the root is the same either way.
+    map.write(7, &buf).expect("write");
+    assert_ne!(map.find(7), Ok(hot));
+    for s in 0..100 {
+        let mut out = [0u8; PAGE_SIZE];
+        map.read(s, &mut out).expect("read");
+        seq_assert(if s == 7 { 1007 } else { s as u64 }, &out);
+    }
+}
+
+#[test]
+fn find_and_read() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    let mut buf = [0u8; PAGE_SIZE];
+    for s in 0..50 {
+        seq_gen(s as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    map.sync().expect("sync");
+
+    // One walk instead of two: fewer reads than find() then read().
+    map.journal.nand.reset_counts();
+    let page = map.find_and_read(17, &mut buf).expect("find_and_read");
+    let combined = map.journal.nand.counts().read;
+    seq_assert(17, &buf);
+
+    map.journal.nand.reset_counts();
+    assert_eq!(map.find(17).ok(), page);
+    let mut out = [0u8; PAGE_SIZE];
+    map.read(17, &mut out).expect("read");
+    assert!(map.journal.nand.counts().read > combined);
+    assert_eq!(out, buf);
+
+    assert_eq!(map.find_and_read(99, &mut buf), Ok(None));
+    assert_eq!(buf, [0xffu8; PAGE_SIZE]);
+}
+
+// A driver with a bulk erase, which it counts. The sim has none, so it's
+// done a block at a time underneath.
+struct BulkNand {
+    nand: SimNand,
+    bulk_erases: usize,
+}
+
+impl DharaNand for BulkNand {
+    fn get_log2_page_size(&self) -> u8 {self.nand.get_log2_page_size()}
+    fn get_log2_ppb(&self) -> u8 {self.nand.get_log2_ppb()}
+    fn get_num_blocks(&self) -> u32 {self.nand.get_num_blocks()}
+    fn is_bad(&mut self, blk: DharaBlock) -> bool {self.nand.is_bad(blk)}
+    fn mark_bad(&mut self, blk: DharaBlock) -> () {self.nand.mark_bad(blk)}
+    fn erase(&mut self, blk: DharaBlock) -> Result<(),DharaError> {self.nand.erase(blk)}
+    fn prog(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError> {self.nand.prog(page, data)}
+    fn is_free(&mut self, page: DharaPage) -> bool {self.nand.is_free(page)}
+    fn read(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        self.nand.read(page, offset, length, data)
+    }
+    fn copy(&mut self, src: DharaPage, dst: DharaPage) -> Result<(),DharaError> {self.nand.copy(src, dst)}
+
+    fn erase_all(&mut self) -> Result<(),DharaError> {
+        self.bulk_erases += 1;
+        self.nand.erase_all()
+    }
+}
+
+#[test]
+fn format_erase_all() -> () {
+    // A driver's bulk erase is used, in place of erasing block by block.
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    nand.sim_set_failed(3);
+    let bulk = BulkNand { nand: nand, bulk_erases: 0 };
+    let mut map = DharaMap::<512, CountingNand<BulkNand>>::new(CountingNand::new(bulk), [0u8; PAGE_SIZE], 4);
+    assert_eq!(map.format(), Ok(1));
+    let counts = map.journal.nand.counts();
+    assert_eq!(counts.erase_all, 1);
+    assert_eq!(counts.erase, 0);
+    assert_eq!(map.journal.nand.into_inner().bulk_erases, 1);
+
+    // Without one, each good block is erased once.
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    nand.sim_set_failed(3);
+    let mut map = DharaMap::<512, WearNand<SimNand, NUM_BLOCKS>>::new(WearNand::new(nand), [0u8; PAGE_SIZE], 4);
+    assert_eq!(map.format(), Ok(1));
+    assert!(map.journal.nand.erase_counts().iter().all(|&c| c == 1));
+
+    // Verifying goes block by block even so.
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    let bulk = BulkNand { nand: nand, bulk_erases: 0 };
+    let mut map = DharaMap::<512, CountingNand<BulkNand>>::new(CountingNand::new(bulk), [0u8; PAGE_SIZE], 4);
+    map.journal.set_erase_verify(true);
+    assert_eq!(map.format(), Ok(0));
+    assert_eq!(map.journal.nand.counts().erase_all, 0);
+    assert_eq!(map.journal.nand.counts().erase, NUM_BLOCKS);
+}
+
+#[test]
+fn with_nand() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = CountingMap::new(CountingNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(5, &mut buf);
+    map.write(5, &buf).expect("write");
+    map.sync().expect("sync");
+
+    let progs = map.with_nand(|nand| nand.counts().prog);
+    assert!(progs > 0);
+
+    // A read on the side, and the map carries on as before.
+    let page = map.find(5).expect("find");
+    let mut side = [0u8; PAGE_SIZE];
+    map.with_nand(|nand| nand.read(page, 0, PAGE_SIZE, &mut side)).expect("read");
+    seq_assert(5, &side);
+
+    seq_gen(6, &mut buf);
+    map.write(6, &buf).expect("write");
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    map.read(5, &mut buf).expect("read");
+    seq_assert(5, &buf);
+    map.read(6, &mut buf).expect("read");
+    seq_assert(6, &buf);
+    assert!(map.with_nand(|nand| nand.counts().prog) > progs);
+}
@@ -4,8 +4,8 @@ mod sim;
 use sim::{SimJournal, SimNand};
 use jtutil::{Pages, jt_enqueue_sequence, jt_dequeue_sequence};
 
-fn fill() -> () {
-    let mut nand: SimNand = SimNand::new();
+fn fill(seed: u64) -> () {
+    let mut nand: SimNand = SimNand::new_seeded(seed);
     nand.sim_reset();
     nand.sim_inject_bad(10);
     nand.sim_inject_failed(10);
@@ -40,10 +40,11 @@ fn fill() -> () {
 
 #[test]
 fn main_jfill() -> () {
-    for _ in 0..100 {
-        // The C code seeds the random number generator with loop variable i, 
-        // but thread_rng() gets seeded by the system.
+    for i in 0..100 {
+        // Seeded with the loop variable, as the C code does, so a failure
+        // can be replayed.
         println!("-------------------------------------------------------");
-        fill();
+        println!("Seed: {}", i);
+        fill(i);
     }
 }
\ No newline at end of file
@@ -0,0 +1,716 @@
+mod jtutil;
+mod sim;
+
+use std::cell::RefCell;
+use dhara_rs::bytes::dhara_r32;
+use dhara_rs::journal::{CheckpointHeader, DharaJournal, JournalState, DHARA_META_SIZE, DHARA_PAGE_NONE};
+use dhara_rs::nand::{DharaNand, DharaPage};
+use dhara_rs::DharaError;
+use jtutil::{Pages, jt_check, jt_enqueue_sequence};
+use sim::{seq_assert, SimJournal, SimNand, LOG2_PAGES_PER_BLOCK, PAGE_SIZE};
+
+// A journal over a good chip, with nothing on it.
+fn new_journal() -> SimJournal {
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+
+    let mut j = SimJournal::new(nand, [0u8; PAGE_SIZE]);
+    let _ = j.journal_resume();
+    j
+}
+
+#[test]
+fn pending_meta() -> () {
+    let mut j = new_journal();
+    let data = [0x42u8; PAGE_SIZE];
+    let mut meta = [0xffu8; DHARA_META_SIZE];
+    meta[0..4].copy_from_slice(&1234u32.to_le_bytes());
+
+    // The first page of a group never completes it, so its metadata stays
+    // buffered.
+    let page = j.get_head();
+    j.journal_enqueue(Some(&data), Some(&meta)).expect("enqueue");
+    assert_eq!(j.get_head(), page + 1);
+
+    let pending = j.pending_meta(page).expect("pending");
+    assert_eq!(pending, &meta[..]);
+
+    // Pages outside the group at the head are never pending.
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    assert_eq!(j.pending_meta(page + ppc), None);
+}
+
+#[test]
+fn epoch_wrap() -> () {
+    let mut j = new_journal();
+    let mut wraps = 0;
+    let mut epoch = j.get_epoch();
+
+    // Keep a single live page in the journal so the head can lap the chip
+    // freely, and resume at every wrap, including 255 -> 0.
+    j.journal_enqueue(None, None).expect("enqueue");
+    while wraps < 260 {
+        j.journal_dequeue();
+        j.journal_enqueue(None, None).expect("enqueue");
+
+        if j.get_epoch() != epoch {
+            epoch = j.get_epoch();
+            wraps += 1;
+
+            // Checkpoint so there is something on the chip to find.
+            while !j.journal_is_clean() {
+                j.journal_dequeue();
+                j.journal_enqueue(None, None).expect("enqueue");
+            }
+            let root = j.journal_root();
+            let head = j.get_head();
+            j.journal_resume().expect("resume");
+            assert_eq!(j.get_epoch(), epoch);
+            assert_eq!(j.journal_root(), root);
+            assert_eq!(j.get_head(), head);
+        }
+    }
+}
+
+#[test]
+fn borrowed_buffer() -> () {
+    let buf: &'static mut [u8; PAGE_SIZE] = Box::leak(Box::new([0u8; PAGE_SIZE]));
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+
+    let mut j = DharaJournal::<PAGE_SIZE, SimNand, &mut [u8; PAGE_SIZE]>::new(nand, buf);
+    let _ = j.journal_resume();
+
+    let data = [0x42u8; PAGE_SIZE];
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    for _ in 0..ppc - 1 {
+        j.journal_enqueue(Some(&data), None).expect("enqueue");
+    }
+    assert!(j.journal_is_clean());
+
+    j.journal_resume().expect("resume");
+    assert_eq!(j.journal_root(), ppc - 2);
+}
+
+#[test]
+fn drain() -> () {
+    let mut j = new_journal();
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::Count(20));
+    assert_eq!(count, 20);
+    // Filler at the head end, which shouldn't come out.
+    j.journal_enqueue(None, None).expect("enqueue");
+
+    let mut next = 0;
+    for item in j.journal_drain() {
+        let (_, meta) = item.expect("drain");
+        assert_eq!(dhara_r32(&meta[0..4]), next);
+        next += 1;
+    }
+    assert_eq!(next, 20);
+    assert_eq!(j.journal_size(), 0);
+    assert!(j.journal_drain().next().is_none());
+
+    // Pages are still readable after being drained, until overwritten.
+    // The iterator borrows the journal, so check the data afterwards.
+    let mut r = [0u8; PAGE_SIZE];
+    let count = jt_enqueue_sequence(&mut j, 100, Pages::Count(5));
+    assert_eq!(count, 5);
+    let drained: Vec<_> = j.journal_drain().map(|item| item.expect("drain")).collect();
+    assert_eq!(drained.len(), 5);
+    for (i, (page, meta)) in drained.iter().enumerate() {
+        let id = dhara_r32(&meta[0..4]);
+        assert_eq!(id, 100 + i as u32);
+        j.nand.read(*page, 0, PAGE_SIZE, &mut r).expect("read");
+        seq_assert(id as u64, &r);
+    }
+}
+
+thread_local! {
+    static STATES: RefCell<Vec<(JournalState, u32)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn record_state(state: JournalState, root: u32) -> () {
+    STATES.with(|s| s.borrow_mut().push((state, root)));
+}
+
+#[test]
+fn state_callback() -> () {
+    let mut j = new_journal();
+    j.set_state_callback(Some(record_state));
+    let data = [0x42u8; PAGE_SIZE];
+    let ppc: u32 = 1 << j.get_log2_ppc();
+
+    // The first enqueue dirties the journal; further ones don't repeat it.
+    j.journal_enqueue(Some(&data), None).expect("enqueue");
+    j.journal_enqueue(Some(&data), None).expect("enqueue");
+    STATES.with(|s| assert_eq!(*s.borrow(), vec![(JournalState::Dirty, DHARA_PAGE_NONE)]));
+
+    // Filling the group writes a checkpoint, reported with its root.
+    for _ in 2..ppc - 1 {
+        j.journal_enqueue(Some(&data), None).expect("enqueue");
+    }
+    assert!(j.journal_is_clean());
+    STATES.with(|s| assert_eq!(s.borrow().last(), Some(&(JournalState::Clean, j.journal_root()))));
+    STATES.with(|s| assert_eq!(s.borrow().len(), 2));
+
+    j.set_state_callback(None);
+    j.journal_enqueue(Some(&data), None).expect("enqueue");
+    STATES.with(|s| assert_eq!(s.borrow().len(), 2));
+}
+
+#[test]
+fn try_new() -> () {
+    assert!(SimJournal::try_new(SimNand::new(), [0u8; PAGE_SIZE]).is_ok());
+
+    let j = DharaJournal::<500, SimNand>::try_new(SimNand::new(), [0u8; 500]);
+    assert_eq!(j.err(), Some(DharaError::BadGeometry));
+    let j = DharaJournal::<256, SimNand>::try_new(SimNand::new(), [0u8; 256]);
+    assert_eq!(j.err(), Some(DharaError::BadGeometry));
+}
+
+#[test]
+fn page_buf_size() -> () {
+    use dhara_rs::journal::{page_buf_size, PageBufCheck};
+    use sim::LOG2_PAGE_SIZE;
+
+    const _: () = PageBufCheck::<PAGE_SIZE, LOG2_PAGE_SIZE>::OK;
+    let buf = [0u8; page_buf_size(LOG2_PAGE_SIZE)];
+    assert!(SimJournal::try_new(SimNand::new(), buf).is_ok());
+}
+
+#[test]
+fn blocks_in_use() -> () {
+    let mut j = new_journal();
+    let log2_ppb = j.get_log2_ppb();
+
+    // Only the block the head is about to write.
+    let used: Vec<u32> = j.blocks_in_use().filter(|&(_, u)| u).map(|(b, _)| b).collect();
+    assert_eq!(used, vec![j.get_head() >> log2_ppb]);
+
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::Count(20));
+    assert_eq!(count, 20);
+    let first = j.get_tail_sync() >> log2_ppb;
+    let last = j.get_head() >> log2_ppb;
+    assert!(last > first + 1);
+    for (blk, used) in j.blocks_in_use() {
+        assert_eq!(used, blk >= first && blk <= last, "block {}", blk);
+    }
+
+    // Once drained and checkpointed, the old blocks are free again.
+    j.journal_drain().for_each(|item| { item.expect("drain"); });
+    while !j.journal_is_clean() {
+        j.journal_enqueue(None, None).expect("enqueue");
+        j.journal_dequeue();
+    }
+    let head_blk = j.get_head() >> log2_ppb;
+    for (blk, used) in j.blocks_in_use() {
+        assert_eq!(used, blk == head_blk, "block {}", blk);
+    }
+}
+
+#[test]
+fn next_write_page() -> () {
+    let mut j = new_journal();
+    let data = [0x42u8; PAGE_SIZE];
+    let mut meta = [0xffu8; DHARA_META_SIZE];
+
+    // Across enough writes to cross checkpoints and a block boundary,
+    // with a bad block to skip.
+    j.nand.sim_set_failed(2);
+    for id in 0..30u32 {
+        let page = j.next_write_page().expect("next_write_page");
+        assert_eq!(j.next_write_page(), Ok(page));
+
+        meta[0..4].copy_from_slice(&id.to_le_bytes());
+        j.journal_enqueue(Some(&data), Some(&meta)).expect("enqueue");
+        assert_eq!(j.journal_root(), page);
+    }
+}
+
+#[test]
+fn self_check() -> () {
+    let mut j = new_journal();
+    assert_eq!(j.self_check(), Ok(()));
+
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::Count(20));
+    assert_eq!(count, 20);
+    assert_eq!(j.self_check(), Ok(()));
+    j.journal_resume().expect("resume");
+    assert_eq!(j.self_check(), Ok(()));
+
+    // A tail pointing at a group's metadata page.
+    let good = j.get_tail_sync();
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    j.set_tail_sync(ppc - 1);
+    assert_eq!(j.self_check(), Err(DharaError::CorruptMap));
+
+    // A synchronized tail ahead of the head in the same block.
+    assert!((j.get_head() + 1) & (ppc - 1) != ppc - 1);
+    j.set_tail_sync(j.get_head() + 1);
+    assert_eq!(j.self_check(), Err(DharaError::CorruptMap));
+
+    j.set_tail_sync(good);
+    assert_eq!(j.self_check(), Ok(()));
+}
+
+#[test]
+fn raw_read_meta() -> () {
+    let mut j = new_journal();
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::Count(20));
+    assert_eq!(count, 20);
+
+    // Remember where each id went, then drop them all from the journal.
+    let pages: Vec<(u32, u32)> = j.journal_drain()
+        .map(|item| item.expect("drain"))
+        .map(|(page, meta)| (page, dhara_r32(&meta[0..4])))
+        .collect();
+    while !j.journal_is_clean() {
+        j.journal_enqueue(None, None).expect("enqueue");
+        j.journal_dequeue();
+    }
+
+    assert_eq!(j.journal_size(), 0);
+
+    let mut meta = [0u8; DHARA_META_SIZE];
+    for &(page, id) in pages.iter() {
+        j.raw_read_meta(page, &mut meta).expect("raw_read_meta");
+        assert_eq!(dhara_r32(&meta[0..4]), id);
+    }
+
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    assert_eq!(j.raw_read_meta(ppc - 1, &mut meta), Err(DharaError::NotFound));
+}
+
+#[test]
+fn reclaim_tail() -> () {
+    let mut j = new_journal();
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::Count(20));
+    assert_eq!(count, 20);
+
+    // Dequeued while dirty: not reusable until the next checkpoint, which
+    // there's room to write, so no reclaiming early.
+    for _ in 0..10 {
+        j.journal_dequeue();
+    }
+    assert!(!j.journal_is_clean());
+    let lag = j.tail_lag();
+    assert!(lag >= 10);
+    assert!(!j.reclaim_tail());
+    assert_eq!(j.tail_lag(), lag);
+
+    // A checkpoint catches the synchronized tail up.
+    while !j.journal_is_clean() {
+        j.journal_enqueue(None, None).expect("enqueue");
+    }
+    assert_eq!(j.tail_lag(), 0);
+
+    // Filled to the brim and emptied, it can't checkpoint, but the last
+    // checkpoint still needs those blocks, so that's no reason either.
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::All);
+    assert!(count > 0);
+    while j.journal_peek() != DHARA_PAGE_NONE {
+        j.journal_dequeue();
+    }
+    assert_eq!(j.journal_enqueue(None, None), Err(DharaError::JournalFull));
+    let lag = j.tail_lag();
+    assert!(lag > 0);
+    assert!(!j.reclaim_tail());
+    assert_eq!(j.tail_lag(), lag);
+}
+
+#[test]
+fn reclaim_tail_clean() -> () {
+    let mut j = new_journal();
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    jt_enqueue_sequence(&mut j, 0, Pages::Count(ppc * 3));
+    while !j.journal_is_clean() {
+        j.journal_enqueue(None, None).expect("enqueue");
+    }
+
+    // Pages dequeued from a clean journal are never counted, and there's
+    // nothing to reclaim.
+    for _ in 0..ppc {
+        j.journal_dequeue();
+    }
+    assert_eq!(j.tail_lag(), 0);
+    assert!(j.reclaim_tail());
+    assert_eq!(j.tail_lag(), 0);
+    assert_eq!(j.get_tail_sync(), j.get_tail());
+}
+
+#[test]
+fn journal_size() -> () {
+    let mut j = new_journal();
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    let ppb: u32 = 1 << j.get_log2_ppb();
+    let data = [0x42u8; PAGE_SIZE];
+    assert_eq!(j.journal_size(), 0);
+
+    // Clean: a full group, checkpoint page not counted.
+    for _ in 0..ppc - 1 {
+        j.journal_enqueue(Some(&data), None).expect("enqueue");
+    }
+    assert!(j.journal_is_clean());
+    assert_eq!(j.journal_size(), ppc - 1);
+
+    // Dirty: a programmed page counts while its metadata is still buffered.
+    j.journal_enqueue(Some(&data), None).expect("enqueue");
+    assert!(!j.journal_is_clean());
+    assert_eq!(j.journal_size(), ppc);
+
+    // Mid-recovery: the whole failed block counts, since none of it can
+    // be reused until recovery is done and checkpointed.
+    let block = j.get_head() >> j.get_log2_ppb();
+    assert_eq!(j.get_head() >> j.get_log2_ppb(), (j.get_head() + 1) >> j.get_log2_ppb());
+    j.nand.sim_set_failed(block as usize);
+    assert_eq!(j.journal_enqueue(Some(&data), None), Err(DharaError::Recover));
+    assert!(j.journal_in_recovery());
+    let mid = j.journal_size();
+    assert!(mid >= ppb - ppb / ppc);
+
+    // Each page recovery writes adds one.
+    let mut written = 0;
+    while j.journal_in_recovery() {
+        let page = j.journal_next_recoverable();
+        if page == DHARA_PAGE_NONE {
+            j.journal_enqueue(None, None).expect("pad");
+        } else {
+            let mut meta = [0u8; DHARA_META_SIZE];
+            j.journal_read_meta(page, &mut meta).expect("read_meta");
+            j.journal_copy(page, Some(&meta)).expect("copy");
+        }
+        written += 1;
+    }
+    assert_eq!(j.journal_size(), mid + written);
+}
+
+#[test]
+fn erase_verify() -> () {
+    let mut j = new_journal();
+    j.set_erase_verify(true);
+
+    // The head starts in block 0, so these are erased on the way through.
+    j.nand.sim_set_stuck(1);
+    j.nand.sim_set_stuck(3);
+    let count = jt_enqueue_sequence(&mut j, 0, Pages::Count(30));
+    assert_eq!(count, 30);
+    assert!(j.nand.is_bad(1));
+    assert!(j.nand.is_bad(3));
+    assert!(!j.nand.is_bad(2));
+
+    // Everything is still there, having avoided the stuck blocks.
+    let drained: Vec<_> = j.journal_drain().map(|item| item.expect("drain")).collect();
+    assert_eq!(drained.len(), 30);
+    for (i, (page, meta)) in drained.iter().enumerate() {
+        assert_eq!(dhara_r32(&meta[0..4]), i as u32);
+        assert!(page >> j.get_log2_ppb() != 1 && page >> j.get_log2_ppb() != 3);
+    }
+}
+
+#[test]
+fn paranoid_reads() -> () {
+    let ppc: u32 = 1 << new_journal().get_log2_ppc();
+    let data = [0x42u8; PAGE_SIZE];
+
+    // Two checkpoint groups in the first block, so a bad read of the
+    // second one's header looks like a journal that stopped after the
+    // first.
+    let setup = || {
+        let mut j = new_journal();
+        for _ in 0..2 * (ppc - 1) {
+            j.journal_enqueue(Some(&data), None).expect("enqueue");
+        }
+        assert!(j.journal_is_clean());
+        assert_eq!(j.journal_root(), 2 * ppc - 2);
+        j.nand.sim_set_flaky_read(2 * ppc - 1);
+        j
+    };
+
+    let mut j = setup();
+    j.journal_resume().expect("resume");
+    assert_eq!(j.journal_root(), ppc - 2);
+
+    let mut j = setup();
+    j.set_paranoid_reads(true);
+    j.journal_resume().expect("resume");
+    assert_eq!(j.journal_root(), 2 * ppc - 2);
+}
+
+#[test]
+fn head_meets_tail() -> () {
+    let mut j = new_journal();
+    let ppb: u32 = 1 << j.get_log2_ppb();
+    let last: u32 = j.get_num_blocks() - 1;
+
+    // Filled to the brim, the head stops one page short of the last
+    // block's checkpoint with the tail on block 0. Dequeue into block 1
+    // and reclaim, so that the last page and its checkpoint can go.
+    jt_enqueue_sequence(&mut j, 0, Pages::All);
+    for _ in 0..ppb {
+        j.journal_dequeue();
+    }
+    j.set_tail_sync(j.get_tail());
+    j.journal_enqueue(None, None).expect("enqueue");
+    assert!(j.journal_is_clean());
+    assert_eq!(j.get_root(), ((last + 1) << j.get_log2_ppb()) - 2);
+    assert_eq!(j.get_head(), 0);
+    let tail = j.get_tail();
+
+    // Rewrite the last block so its checkpoint records a tail on block 0,
+    // as an interrupted reclaim might leave it. Resume then starts the
+    // head right on the tail's block.
+    let mut pages = [[0u8; PAGE_SIZE]; 8];
+    for (i, buf) in pages.iter_mut().enumerate() {
+        j.nand.read((last << j.get_log2_ppb()) + i as u32, 0, PAGE_SIZE, buf).expect("read");
+    }
+    pages[7][4..8].copy_from_slice(&(tail - ppb).to_le_bytes());
+    j.nand.erase(last).expect("erase");
+    for (i, buf) in pages.iter().enumerate() {
+        j.nand.prog((last << j.get_log2_ppb()) + i as u32, buf).expect("prog");
+    }
+
+    j.journal_resume().expect("resume");
+    assert_eq!(j.self_check(), Ok(()));
+    assert_eq!(j.get_head(), 0);
+    assert_eq!(j.get_tail(), ppb);
+    assert_eq!(j.get_tail_sync(), ppb);
+    assert_eq!(j.get_root(), ((last + 1) << j.get_log2_ppb()) - 2);
+}
+
+// Only with the checked feature: cargo test --features checked
+#[cfg(feature = "checked")]
+#[test]
+fn checked() -> () {
+    let mut j = new_journal();
+    assert!(jt_enqueue_sequence(&mut j, 0, Pages::Count(5)) == 5);
+
+    // A synchronized tail off the end of the chip is refused before
+    // anything is programmed.
+    let chip_size = j.nand.total_pages();
+    let head = j.get_head();
+    j.set_tail_sync(chip_size + 1);
+    assert_eq!(j.journal_enqueue(None, None), Err(DharaError::CorruptMap));
+    assert_eq!(j.journal_copy(0, None), Err(DharaError::CorruptMap));
+    assert_eq!(j.get_head(), head);
+
+    j.set_tail_sync(0);
+    assert_eq!(j.journal_enqueue(None, None), Ok(()));
+}
+
+#[test]
+fn peek_readable() -> () {
+    let mut j = new_journal();
+    let ppb: u32 = 1 << j.get_log2_ppb();
+    assert_eq!(j.peek_readable(), DHARA_PAGE_NONE);
+
+    // Three blocks' worth, then the middle one goes bad under the log.
+    assert_eq!(jt_enqueue_sequence(&mut j, 0, Pages::Count(ppb * 2)), ppb * 2);
+    assert!(j.get_head() > ppb * 2);
+    j.nand.mark_bad(1);
+
+    while j.get_tail() < ppb {
+        j.journal_dequeue();
+    }
+    assert_eq!(j.get_tail(), ppb);
+
+    // Peeking speculatively sees past the bad block, but stays put.
+    let root = j.get_root();
+    assert_eq!(j.peek_readable(), ppb * 2);
+    assert_eq!(j.peek_readable(), ppb * 2);
+    assert_eq!(j.get_tail(), ppb);
+    assert_eq!(j.get_root(), root);
+
+    // The real thing moves the tail, once.
+    assert_eq!(j.journal_peek(), ppb * 2);
+    assert_eq!(j.get_tail(), ppb * 2);
+    assert_eq!(j.journal_peek(), ppb * 2);
+    assert_eq!(j.get_tail(), ppb * 2);
+}
+
+#[test]
+fn read_checkpoint_header() -> () {
+    let mut j = new_journal();
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    let ppb: u32 = 1 << j.get_log2_ppb();
+
+    // Nothing written yet.
+    assert_eq!(j.read_checkpoint_header(0), Ok(None));
+    assert_eq!(j.read_checkpoint_header(j.nand.get_num_blocks()), Err(DharaError::NotFound));
+
+    j.set_cookie(0x1234_5678);
+    assert_eq!(jt_enqueue_sequence(&mut j, 0, Pages::Count(ppb)), ppb);
+    while !j.journal_is_clean() {
+        j.journal_enqueue(None, None).expect("enqueue");
+    }
+
+    let hdr = j.read_checkpoint_header(0).expect("read").expect("header");
+    assert_eq!(hdr, CheckpointHeader {
+        page: ppc - 1,
+        epoch: j.get_epoch(),
+        tail: j.get_tail_sync(),
+        bb_current: 0,
+        bb_last: j.get_bb_last(),
+        cookie: 0x1234_5678,
+    });
+
+    // The head's block hasn't had its first checkpoint yet.
+    let head_block = j.get_head() >> j.get_log2_ppb();
+    if j.get_head() & (ppb - 1) < ppc {
+        assert_eq!(j.read_checkpoint_header(head_block), Ok(None));
+    }
+}
+
+#[cfg(feature = "format-version")]
+#[test]
+fn format_version() -> () {
+    use dhara_rs::journal::DHARA_FORMAT_VERSION;
+
+    let mut j = new_journal();
+    assert_eq!(j.get_format_version(), DHARA_FORMAT_VERSION);
+    assert!(jt_enqueue_sequence(&mut j, 0, Pages::Count(20)) == 20);
+    while !j.journal_is_clean() {
+        j.journal_enqueue(None, None).expect("enqueue");
+    }
+    let head = j.get_head();
+
+    // A later version of the code must refuse it...
+    let mut newer = SimJournal::new(j.nand, [0u8; PAGE_SIZE]);
+    newer.set_format_version(DHARA_FORMAT_VERSION + 1);
+    assert_eq!(newer.journal_resume(), Err(DharaError::VersionMismatch));
+
+    // ...while this one still reads it.
+    let mut same = SimJournal::new(newer.nand, [0u8; PAGE_SIZE]);
+    same.journal_resume().expect("resume");
+    assert_eq!(same.get_head(), head);
+}
+
+#[test]
+fn resume_blank() -> () {
+    // Fresh from the factory: every block erased, so no magic anywhere.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut j = SimJournal::new(nand, [0u8; PAGE_SIZE]);
+    assert_eq!(j.journal_resume(), Err(DharaError::Blank));
+    assert_eq!(j.journal_root(), DHARA_PAGE_NONE);
+
+    // Bad blocks at the start of the chip are skipped over, not taken
+    // for damage.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    nand.mark_bad(0);
+    nand.mark_bad(1);
+    let mut j = SimJournal::new(nand, [0u8; PAGE_SIZE]);
+    assert_eq!(j.journal_resume(), Err(DharaError::Blank));
+
+    // Once written, the same chip resumes.
+    jt_enqueue_sequence(&mut j, 0, Pages::Count(10));
+    assert_eq!(j.journal_resume(), Ok(()));
+
+    // Programmed, but not with a journal: not blank, so not to be
+    // formatted over.
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    let mut j = SimJournal::new(nand, [0u8; PAGE_SIZE]);
+    assert_eq!(j.journal_resume(), Err(DharaError::TooBad));
+
+    // Nor is one erased only as far as the blocks checked for a
+    // checkpoint: data further on, or a first group written only in part,
+    // is still something that formatting would destroy.
+    let data = [0x55u8; PAGE_SIZE];
+    let ppb: u32 = 1 << LOG2_PAGES_PER_BLOCK;
+    for page in [20 * ppb + 3, 0] {
+        let mut nand = SimNand::new();
+        nand.sim_erase_all();
+        nand.prog(page, &data).expect("prog");
+        let mut j = SimJournal::new(nand, [0u8; PAGE_SIZE]);
+        assert_eq!(j.journal_resume(), Err(DharaError::TooBad), "page {}", page);
+    }
+
+    // A journal whose only checkpoint can't be read isn't blank either,
+    // and is still there once the read succeeds.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut j = SimJournal::new(nand, [0u8; PAGE_SIZE]);
+    let _ = j.journal_resume();
+    let ppc: u32 = 1 << j.get_log2_ppc();
+    jt_enqueue_sequence(&mut j, 0, Pages::Count(ppc - 1));
+    let root = j.journal_root();
+    assert_eq!(root, ppc - 2);
+
+    j.nand.sim_set_ecc_error(Some(ppc - 1));
+    assert_eq!(j.journal_resume(), Err(DharaError::TooBad));
+    j.nand.sim_set_ecc_error(None);
+    assert_eq!(j.journal_resume(), Ok(()));
+    assert_eq!(j.journal_root(), root);
+}
+
+// Write a page and drop everything before it. The tail walks through bad
+// blocks page by page, as it does through anything else, so dequeueing
+// just one page per enqueue would fall further behind every lap.
+fn lap_step(j: &mut SimJournal) -> () {
+    j.journal_enqueue(None, None).expect("enqueue");
+    while j.get_tail() != j.journal_root() {
+        j.journal_dequeue();
+    }
+}
+
+#[test]
+fn resume_across_epoch_boundary() -> () {
+    let mut j = new_journal();
+    let blocks = j.nand.get_num_blocks();
+
+    // Bad blocks on both sides of the wrap, and a run in the middle, so
+    // that the binary search and its skip-ahead both have to step over
+    // them right where the epoch changes.
+    let bad = [0, 1, blocks / 2, blocks / 2 + 1, blocks / 2 + 2, blocks - 2, blocks - 1];
+    for blk in bad {
+        j.nand.mark_bad(blk);
+    }
+    j.journal_resume().expect_err("nothing written yet");
+
+    // Lap the chip with a single live page until just short of the u8
+    // wrap, which takes a while but has nothing to check.
+    while j.get_epoch() != 254 {
+        lap_step(&mut j);
+    }
+
+    // Then resume at every checkpoint for two laps, through 255 -> 0.
+    // Each one puts the boundary between this epoch's blocks and the
+    // last one's somewhere different, and resume must always land on
+    // the newest checkpoint: never one from the previous lap, and never
+    // an older one of this lap.
+    //
+    // After each, resume once more with pages written past it, which must
+    // lose them and go back to the same checkpoint. Just after the wrap,
+    // that's with this epoch's block 0 not yet holding a checkpoint, so
+    // the epoch must come out as the last one. (Only once per checkpoint,
+    // since the pages lost each time never complete a group.)
+    let mut resumes = 0;
+    let mut last: Option<(DharaPage, u8)> = None;
+    while j.get_epoch() != 1 {
+        lap_step(&mut j);
+
+        if !j.journal_is_clean() {
+            if let Some((root, epoch)) = last.take() {
+                j.journal_resume().expect("resume");
+                assert_eq!(j.journal_root(), root);
+                assert_eq!(j.get_epoch(), epoch);
+                jt_check(&j);
+            }
+            continue;
+        }
+
+        let root = j.journal_root();
+        let head = j.get_head();
+        let epoch = j.get_epoch();
+        j.journal_resume().expect("resume");
+        assert_eq!(j.journal_root(), root);
+        assert_eq!(j.get_head(), head);
+        assert_eq!(j.get_epoch(), epoch);
+        jt_check(&j);
+        last = Some((root, epoch));
+        resumes += 1;
+    }
+    // At least once in every good block, on each of the two laps.
+    assert!(resumes >= 2 * (blocks - bad.len() as u32));
+}
@@ -1,4 +1,5 @@
 use dhara_rs::bytes::{dhara_r32, dhara_w32};
+use dhara_rs::geometry::align_eq;
 use dhara_rs::journal::{DHARA_PAGE_NONE, DHARA_META_SIZE, DHARA_MAX_RETRIES};
 // use dhara_rs::nand::DharaPage;
 use dhara_rs::nand::{DharaNand, DharaPage};
@@ -13,9 +14,8 @@ pub enum Pages {
 }
 
 fn check_upage(j: &SimJournal, page: DharaPage) -> () {
-    let mask: DharaPage = (1 << j.get_log2_ppc()) - 1;
-    assert!((!page) & mask != 0);
-    assert!(page < (j.get_num_blocks() << j.get_log2_ppb()));
+    assert!(j.is_user_page(page));
+    assert!(page < j.nand.total_pages());
 }
 
 pub fn jt_check(j: &SimJournal) -> () {
@@ -27,7 +27,7 @@ pub fn jt_check(j: &SimJournal) -> () {
 
     // The head never advances forward onto the same block
     // as the tail.
-    if (j.get_head() ^ j.get_tail_sync()) >> j.get_log2_ppb() == 0 {
+    if align_eq(j.get_head(), j.get_tail_sync(), j.get_log2_ppb()) {
 		assert!(j.get_head() >= j.get_tail_sync());
 	}
 
@@ -44,6 +44,9 @@ pub fn jt_check(j: &SimJournal) -> () {
         check_upage(j, j.get_root());
         assert!(root_offset < raw_size);//
     }
+
+    // The library's own version of these checks must agree.
+    j.self_check().expect("self_check");
 }
 
 fn recover(j: &mut SimJournal) -> () {
@@ -105,7 +108,7 @@ fn enqueue(j: &mut SimJournal, id: u32) -> Result<(),DharaError> {
 /// count: Count(number of pages to enqueue).  All => all pages in the NAND.
 pub fn jt_enqueue_sequence(j: &mut SimJournal, start: u32, count: Pages) -> u32 {
     let count:u32 = match count {
-        Pages::All => j.get_num_blocks() << j.get_log2_ppb(),
+        Pages::All => j.nand.total_pages(),
         Pages::Count(count) => count,
     };
 
@@ -127,6 +130,7 @@ pub fn jt_enqueue_sequence(j: &mut SimJournal, start: u32, count: Pages) -> u32
     count
 }
 
+#[allow(dead_code)] // Used across test modules but not detected by dead code analysis
 pub fn jt_dequeue_sequence(j: &mut SimJournal, next: u32, count: u32) -> () {
     // Shadow them to make them mutable.
     let mut count = count;
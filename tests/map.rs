@@ -124,12 +124,12 @@ fn mt_assert_blank(m: &mut SimMap, s: DharaSector) -> () {
     }
 }
 
-fn mt_test() -> () {
+fn mt_test(seed: u64) -> () {
     // List of sectors for tests.
     let mut sector_list = SectorList::new();
 
     // Set up the NAND first.
-    let mut nand: SimNand = SimNand::new();
+    let mut nand: SimNand = SimNand::new_seeded(seed);
     nand.sim_reset();
     nand.sim_inject_bad(10);
     nand.sim_inject_timebombs(30, 20);
@@ -209,9 +209,11 @@ fn mt_test() -> () {
 
 #[test]
 fn main_map() -> () {
-    for _i in 0..1000 {
-        // Each iteration should inject different bad blocks and timebombs.
-        mt_test();
+    for i in 0..1000 {
+        // Each iteration injects different bad blocks and timebombs, but
+        // the same ones every run.
+        println!("Seed: {}", i);
+        mt_test(i);
     }
 
     // This doesn't exactly recreate the C code, because there the sim 
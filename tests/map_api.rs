@@ -0,0 +1,1793 @@
+mod sim;
+
+use dhara_rs::{DharaError, DharaMap, DharaSector, RecoverState, ResumeOutcome, ScrubReport, WriteAllError, DHARA_SECTOR_NONE};
+use dhara_rs::builder::MapBuilder;
+use dhara_rs::counting::CountingNand;
+use dhara_rs::journal::{DHARA_META_SIZE, DHARA_PAGE_NONE};
+use dhara_rs::nand::{DharaNand, DharaPage};
+use sim::{seq_assert, seq_gen, SimNand, PAGE_SIZE};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Reduce typing for this specific test map.
+type SimMap = DharaMap::<512, SimNand>;
+
+const GC_RATIO: u8 = 4;
+
+// A map over a good chip, with nothing on it.
+fn new_map() -> SimMap {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+    map
+}
+
+fn write_seq(m: &mut SimMap, s: DharaSector, seed: u64) -> () {
+    let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+    seq_gen(seed, &mut buf);
+    m.write(s, &buf).expect("map_write");
+}
+
+fn assert_seq(m: &mut SimMap, s: DharaSector, seed: u64) -> () {
+    let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+    m.read(s, &mut buf).expect("map_read");
+    seq_assert(seed, &buf);
+}
+
+#[test]
+fn compact() -> () {
+    let mut map = new_map();
+
+    // Lots of rewrites and trims leave the journal mostly garbage.
+    for rep in 0..5u64 {
+        for s in 0..100 {
+            write_seq(&mut map, s, rep * 1000 + s as u64);
+        }
+    }
+    for s in 10..100 {
+        map.trim(s).expect("trim");
+    }
+    map.sync().expect("sync");
+    assert_eq!(map.get_size(), 10);
+    let before = map.journal.journal_size();
+
+    map.compact().expect("compact");
+
+    // Live pages, plus at most a checkpoint group of padding.
+    let after = map.journal.journal_size();
+    let ppc: u32 = 1 << map.journal.get_log2_ppc();
+    assert!(after < before);
+    assert!(after <= map.get_size() + ppc);
+
+    map.resume().expect("resume");
+    for s in 0..10 {
+        assert_seq(&mut map, s, 4000 + s as u64);
+    }
+}
+
+#[test]
+fn resume_or_format() -> () {
+    // new_map() checks the blank chip case.
+    let mut map = new_map();
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+
+    write_seq(&mut map, 3, 3);
+    map.sync().expect("sync");
+
+    // Same chip, fresh map.
+    let mut map = SimMap::new(map.journal.nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Resumed));
+    assert_eq!(map.get_size(), 1);
+    assert_seq(&mut map, 3, 3);
+}
+
+#[test]
+fn resume_blank() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume(), Err(DharaError::Blank));
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+
+    // A chip that isn't erased isn't blank, even with no map found on it.
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume_or_format(), Err(DharaError::TooBad));
+
+    // Nor is one with data only past the first few blocks, which is left
+    // alone rather than formatted over.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let page: DharaPage = 50 << map.journal.get_log2_ppb();
+    let data = [0x55u8; PAGE_SIZE];
+    nand.prog(page, &data).expect("prog");
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume_or_format(), Err(DharaError::TooBad));
+    assert!(!map.journal.nand.is_free(page));
+}
+
+#[test]
+fn resume_corrupt() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    let ppc: u32 = 1 << map.journal.get_log2_ppc();
+
+    // A checkpoint with the right magic and epoch, but a tail that points
+    // well past the end of the chip.
+    let mut page = [0xffu8; PAGE_SIZE];
+    page[0..4].copy_from_slice(b"Dha\0");
+    page[4..8].copy_from_slice(&0xfffffff0u32.to_le_bytes());
+    page[8..16].fill(0);
+    #[cfg(feature = "format-version")]
+    {
+        page[16] = dhara_rs::journal::DHARA_FORMAT_VERSION;
+    }
+    map.journal.nand.prog(ppc - 1, &page).expect("prog");
+
+    assert_eq!(map.resume(), Err(DharaError::CorruptMap));
+    assert_eq!(map.resume_or_format(), Err(DharaError::CorruptMap));
+}
+
+#[test]
+fn trim_all() -> () {
+    let mut map = new_map();
+    for s in 0..50 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+
+    // clear() alone leaves the old blocks pinned behind the last
+    // checkpoint, and a reboot brings the sectors back.
+    map.clear();
+    assert_eq!(map.get_size(), 0);
+    assert_ne!(map.journal.get_tail_sync(), map.journal.get_tail());
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 50);
+
+    // trim_all() releases them at once, and for good.
+    map.trim_all().expect("trim_all");
+    assert_eq!(map.get_size(), 0);
+    assert_eq!(map.journal.get_tail_sync(), map.journal.get_tail());
+    assert!(map.journal.journal_is_clean());
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 0);
+    for s in 0..50 {
+        assert_eq!(map.find(s), Err(DharaError::NotFound));
+    }
+
+    // The whole capacity is usable straight away.
+    let cap = map.get_capacity();
+    for s in 0..cap {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    assert_eq!(map.get_size(), cap);
+}
+
+#[cfg(feature = "alloc")]
+#[test]
+fn read_vec() -> () {
+    let mut map = new_map();
+    write_seq(&mut map, 7, 7);
+
+    let mut expect = [0u8; PAGE_SIZE];
+    seq_gen(7, &mut expect);
+    let data = map.read_vec(7).expect("read_vec");
+    assert_eq!(data.len(), PAGE_SIZE);
+    assert_eq!(&data[..], &expect[..]);
+
+    // Unmapped sectors read as blank, like read().
+    let data = map.read_vec(8).expect("read_vec");
+    assert_eq!(data.len(), PAGE_SIZE);
+    assert!(data.iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn find_nearest() -> () {
+    let mut map = new_map();
+    assert_eq!(map.find_nearest(0), Ok(None));
+
+    let sectors: [DharaSector; 7] = [0, 5, 6, 100, 1000, 70000, 0x8000_0001];
+    for (i, &s) in sectors.iter().rev().enumerate() {
+        write_seq(&mut map, s, i as u64);
+    }
+
+    for (i, &s) in sectors.iter().enumerate() {
+        assert_eq!(map.find_nearest(s), Ok(Some(s)));
+        if let Some(&next) = sectors.get(i + 1) {
+            for t in [s + 1, (s + next) / 2, next - 1] {
+                if t > s {
+                    assert_eq!(map.find_nearest(t), Ok(Some(next)), "target {}", t);
+                }
+            }
+        }
+    }
+    assert_eq!(map.find_nearest(0x8000_0002), Ok(None));
+    assert_eq!(map.find_nearest(0xffff_fffe), Ok(None));
+
+    // Trimmed sectors are skipped.
+    map.trim(100).expect("trim");
+    assert_eq!(map.find_nearest(7), Ok(Some(1000)));
+}
+
+#[test]
+fn trim_top_level_cousin() -> () {
+    // These two differ in the top bit, so each is the other's only cousin,
+    // found at level 0 of the radix tree.
+    // Trimming either must leave the other, through a resume too.
+    for (gone, kept, seed) in [(0x8000_0001, 1, 1), (1, 0x8000_0001, 2)] {
+        let mut map = new_map();
+        write_seq(&mut map, 1, 1);
+        write_seq(&mut map, 0x8000_0001, 2);
+
+        map.trim(gone).expect("trim");
+        assert_eq!(map.get_size(), 1);
+        assert_eq!(map.find(gone), Err(DharaError::NotFound));
+        assert_seq(&mut map, kept, seed);
+
+        map.sync().expect("sync");
+        map.resume().expect("resume");
+        assert_eq!(map.get_size(), 1);
+        assert_eq!(map.find(gone), Err(DharaError::NotFound));
+        assert_seq(&mut map, kept, seed);
+    }
+}
+
+#[test]
+fn recover_step() -> () {
+    let mut map = new_map();
+    assert_eq!(map.recover_step(), Ok(RecoverState::Done));
+
+    write_seq(&mut map, 0, 0);
+    write_seq(&mut map, 1, 1);
+
+    // Fail the block partway through a checkpoint group, underneath the
+    // map, so that nothing has recovered it yet.
+    map.journal.nand.sim_set_failed(0);
+    let filler = [0xffu8; PAGE_SIZE];
+    assert_eq!(map.journal.journal_enqueue(Some(&filler), None), Err(DharaError::Recover));
+
+    let mut steps = 0;
+    while map.recover_step().expect("recover_step") == RecoverState::InProgress {
+        assert!(map.journal.journal_in_recovery());
+        steps += 1;
+    }
+    assert!(steps > 0);
+    assert!(!map.journal.journal_in_recovery());
+
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 2);
+    assert_seq(&mut map, 0, 0);
+    assert_seq(&mut map, 1, 1);
+}
+
+#[test]
+fn safety_margin() -> () {
+    let mut map = new_map();
+    let ppb: u32 = 1 << map.journal.get_log2_ppb();
+    let default = map.get_safety_margin();
+    let cap = map.get_capacity();
+
+    map.set_safety_margin(default + 2);
+    assert_eq!(map.get_capacity(), cap - 2 * ppb);
+
+    map.set_safety_margin(0);
+    assert_eq!(map.get_capacity(), cap + default * ppb);
+    let cap = map.get_capacity();
+
+    // A zero-margin map still takes a full load of writes.
+    for s in 0..cap {
+        write_seq(&mut map, s, s as u64);
+    }
+    write_seq(&mut map, 0, 1000);
+    map.sync().expect("sync");
+    assert_seq(&mut map, 0, 1000);
+    assert_seq(&mut map, cap - 1, (cap - 1) as u64);
+}
+
+#[test]
+fn write_page_direct() -> () {
+    let mut map = new_map();
+    let mut buf = [0u8; PAGE_SIZE + 1];
+    seq_gen(9, &mut buf[..PAGE_SIZE]);
+
+    assert_eq!(map.write_page_direct(9, &buf[..PAGE_SIZE - 1]), Err(DharaError::BadLength));
+    assert_eq!(map.write_page_direct(9, &buf), Err(DharaError::BadLength));
+    assert_eq!(map.write_page_direct(9, &[]), Err(DharaError::BadLength));
+    assert_eq!(map.get_size(), 0);
+
+    map.write_page_direct(9, &buf[..PAGE_SIZE]).expect("write_page_direct");
+    assert_eq!(map.get_size(), 1);
+    assert_seq(&mut map, 9, 9);
+}
+
+#[test]
+fn stat() -> () {
+    let mut map = new_map();
+    assert_eq!(map.stat(4), Ok(None));
+
+    for s in 0..20 {
+        write_seq(&mut map, s, s as u64);
+    }
+
+    let st = map.stat(17).expect("stat").expect("mapped");
+    assert_eq!(Ok(st.page), map.find(17));
+    assert_eq!(st.block, st.page >> map.journal.get_log2_ppb());
+    assert_eq!(st.checkpoint_group, st.page >> map.journal.get_log2_ppc());
+
+    map.trim(17).expect("trim");
+    assert_eq!(map.stat(17), Ok(None));
+}
+
+#[test]
+fn try_new() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    let map = SimMap::try_new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert!(map.is_ok());
+
+    // Not a power of two.
+    let nand = SimNand::new();
+    let map = DharaMap::<500, SimNand>::try_new(nand, [0u8; 500], GC_RATIO);
+    assert_eq!(map.err(), Some(DharaError::BadGeometry));
+
+    // A power of two, but not the NAND's page size.
+    let nand = SimNand::new();
+    let map = DharaMap::<1024, SimNand>::try_new(nand, [0u8; 1024], GC_RATIO);
+    assert_eq!(map.err(), Some(DharaError::BadGeometry));
+}
+
+#[test]
+fn scrub() -> () {
+    let mut map = new_map();
+    for s in 0..30 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    assert_eq!(map.scrub(2), Ok(ScrubReport { refreshed: 0, retired: 0 }));
+
+    let worn = [3, 7, 22];
+    let mut pages = Vec::new();
+    for &s in worn.iter() {
+        let page = map.find(s).expect("find");
+        map.journal.nand.sim_set_corrections(page, 5);
+        pages.push(page);
+    }
+    // At the threshold isn't over it.
+    let ok_page = map.find(10).expect("find");
+    map.journal.nand.sim_set_corrections(ok_page, 2);
+
+    assert_eq!(map.scrub(2), Ok(ScrubReport { refreshed: worn.len(), retired: 0 }));
+    for (&s, &page) in worn.iter().zip(pages.iter()) {
+        assert_ne!(map.find(s), Ok(page));
+    }
+    assert_eq!(map.find(10), Ok(ok_page));
+
+    // The fresh copies read cleanly, so a second pass finds nothing.
+    assert_eq!(map.scrub(2), Ok(ScrubReport { refreshed: 0, retired: 0 }));
+    assert_eq!(map.get_size(), 30);
+    map.sync().expect("sync");
+    for s in 0..30 {
+        assert_seq(&mut map, s, s as u64);
+    }
+}
+
+#[test]
+fn reserved_sector() -> () {
+    let mut map = new_map();
+    write_seq(&mut map, 1, 1);
+    write_seq(&mut map, DHARA_SECTOR_NONE - 1, 2);
+
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(3, &mut buf);
+    assert_eq!(map.write(DHARA_SECTOR_NONE, &buf), Err(DharaError::BadSector));
+    let page = map.find(1).expect("find");
+    assert_eq!(map.copy_page(page, DHARA_SECTOR_NONE), Err(DharaError::BadSector));
+
+    // Nothing changed, and the tree is intact.
+    assert_eq!(map.get_size(), 2);
+    assert_eq!(map.find(DHARA_SECTOR_NONE), Err(DharaError::NotFound));
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_seq(&mut map, 1, 1);
+    assert_seq(&mut map, DHARA_SECTOR_NONE - 1, 2);
+}
+
+#[test]
+fn iter_pairs() -> () {
+    let mut map = new_map();
+    assert_eq!(map.iter_pairs().count(), 0);
+
+    let sectors: [DharaSector; 6] = [0, 3, 64, 65, 9000, DHARA_SECTOR_NONE - 1];
+    for &s in sectors.iter().rev() {
+        write_seq(&mut map, s, s as u64);
+    }
+    // Overwritten and trimmed sectors show up as they are now.
+    write_seq(&mut map, 64, 640);
+    write_seq(&mut map, 70, 70);
+    map.trim(70).expect("trim");
+
+    let found: Vec<DharaSector> = map.sectors().map(|s| s.expect("sectors")).collect();
+    assert_eq!(found, sectors.to_vec());
+
+    let mut n = 0;
+    for (item, &s) in map.iter_pairs().zip(sectors.iter()) {
+        let (sector, data) = item.expect("iter_pairs");
+        assert_eq!(sector, s);
+        seq_assert(if s == 64 { 640 } else { s as u64 }, &data);
+        n += 1;
+    }
+    assert_eq!(n, sectors.len());
+    assert_eq!(map.iter_pairs().count(), sectors.len());
+}
+
+#[test]
+fn restore() -> () {
+    let mut map = new_map();
+    for s in 0..40 {
+        write_seq(&mut map, s * 3, s as u64);
+    }
+
+    let image: Vec<(DharaSector, [u8; PAGE_SIZE])> =
+        map.iter_pairs().map(|item| item.expect("iter_pairs")).collect();
+    assert_eq!(image.len(), 40);
+
+    map.trim_all().expect("trim_all");
+    map.restore(image.iter().cloned()).expect("restore");
+    assert!(map.journal.journal_is_clean());
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 40);
+    for s in 0..40 {
+        assert_seq(&mut map, s * 3, s as u64);
+    }
+
+    // Too much to fit is refused up front.
+    map.trim_all().expect("trim_all");
+    let cap = map.get_capacity() as usize;
+    let too_many = (0..cap as DharaSector + 1).map(|s| (s, [0u8; PAGE_SIZE]));
+    assert_eq!(map.restore(too_many), Err(DharaError::MapFull));
+    assert_eq!(map.get_size(), 0);
+
+    // And so is a filtered source, once collected so its length is known.
+    let filtered: Vec<(DharaSector, [u8; PAGE_SIZE])> = (0..2 * cap as DharaSector)
+        .filter(|s| s % 2 == 0)
+        .chain(core::iter::once(1))
+        .map(|s| (s, [0u8; PAGE_SIZE]))
+        .collect();
+    assert_eq!(map.restore(filtered), Err(DharaError::MapFull));
+    assert_eq!(map.get_size(), 0);
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 0);
+}
+
+#[test]
+fn full_errors() -> () {
+    // Out of logical sectors: MapFull, and rewrites still work.
+    let mut map = new_map();
+    let cap = map.get_capacity();
+    for s in 0..cap {
+        write_seq(&mut map, s, s as u64);
+    }
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(0, &mut buf);
+    assert_eq!(map.write(cap, &buf), Err(DharaError::MapFull));
+    write_seq(&mut map, 0, 1);
+
+    // Out of physical space. With a huge GC ratio and no safety margin,
+    // a full map leaves only a block or so free; once that fails, there's
+    // nowhere left for the journal to go.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], 255);
+    map.resume_or_format().expect("resume_or_format");
+    map.set_safety_margin(0);
+    let cap = map.get_capacity();
+    for s in 0..cap {
+        write_seq(&mut map, s, s as u64);
+    }
+    let free: Vec<u32> = map.journal.blocks_in_use().filter(|&(_, u)| !u).map(|(b, _)| b).collect();
+    assert!(!free.is_empty());
+    for b in free {
+        map.journal.nand.sim_set_failed(b as usize);
+    }
+
+    let mut result = Ok(());
+    for s in 0..cap {
+        seq_gen(s as u64 + 1000, &mut buf);
+        result = map.write(s, &buf);
+        if result.is_err() {
+            break;
+        }
+    }
+    assert_eq!(result, Err(DharaError::TooBad));
+}
+
+#[test]
+fn needs_gc() -> () {
+    let mut map = new_map();
+    let cap = map.get_capacity();
+    assert!(!map.needs_gc());
+
+    // Rewriting one sector fills the journal with garbage until the
+    // next write would have to collect it.
+    let mut writes = 0;
+    while !map.needs_gc() {
+        assert!(map.journal.journal_size() < cap);
+        write_seq(&mut map, 0, writes);
+        writes += 1;
+    }
+    assert!(map.journal.journal_size() >= cap);
+
+    // Collecting by hand clears it, and the data survives.
+    while map.needs_gc() {
+        map.gc().expect("gc");
+    }
+    assert!(map.journal.journal_size() < cap);
+    assert_seq(&mut map, 0, writes - 1);
+}
+
+#[test]
+fn copy_page_sync() -> () {
+    let mut map = new_map();
+    write_seq(&mut map, 1, 1);
+    map.sync().expect("sync");
+
+    let page = map.find(1).expect("find");
+    map.copy_page_sync(page, 2).expect("copy_page_sync");
+    assert!(map.journal.journal_is_clean());
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 2);
+    assert_seq(&mut map, 1, 1);
+    assert_seq(&mut map, 2, 1);
+}
+
+#[test]
+fn builder() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut map = MapBuilder::<PAGE_SIZE, _>::new(nand, [0u8; PAGE_SIZE])
+        .gc_ratio(GC_RATIO)
+        .max_retries(3)
+        .safety_margin_blocks(0)
+        .find_cache(true)
+        .build()
+        .expect("build");
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+    assert_eq!(map.get_safety_margin(), 0);
+    assert!(map.get_find_cache());
+
+    // Same as new(), apart from the capacity handed back by the margin.
+    let reference = new_map();
+    let margin = reference.get_safety_margin() << reference.journal.get_log2_ppb();
+    assert_eq!(map.get_capacity(), reference.get_capacity() + margin);
+
+    let built = MapBuilder::<PAGE_SIZE, _>::new(SimNand::new(), [0u8; PAGE_SIZE]).build();
+    assert_eq!(built.err(), Some(DharaError::BadConfig));
+    let built = MapBuilder::<PAGE_SIZE, _>::new(SimNand::new(), [0u8; PAGE_SIZE])
+        .gc_ratio(GC_RATIO)
+        .max_retries(0)
+        .build();
+    assert_eq!(built.err(), Some(DharaError::BadConfig));
+    let built = MapBuilder::<PAGE_SIZE, _>::new(SimNand::new(), [0u8; PAGE_SIZE])
+        .gc_ratio(GC_RATIO)
+        .safety_margin_blocks(SimNand::new().get_num_blocks())
+        .build();
+    assert_eq!(built.err(), Some(DharaError::BadConfig));
+    let built = MapBuilder::<256, _>::new(SimNand::new(), [0u8; 256])
+        .gc_ratio(GC_RATIO)
+        .build();
+    assert_eq!(built.err(), Some(DharaError::BadGeometry));
+}
+
+#[test]
+fn read_many() -> () {
+    let mut map = new_map();
+    for s in [3, 7, 8, 100] {
+        write_seq(&mut map, s, s as u64);
+    }
+
+    let requests = [100, 4, 7, 3, 3, 9999, 8];
+    let mut out = [[0u8; PAGE_SIZE]; 7];
+    map.read_many(&requests, &mut out).expect("read_many");
+
+    let mut single = [0u8; PAGE_SIZE];
+    for (s, data) in requests.iter().zip(out.iter()) {
+        map.read(*s, &mut single).expect("read");
+        assert_eq!(&single[..], &data[..], "sector {}", s);
+    }
+    assert!(out[1].iter().all(|&b| b == 0xff));
+
+    assert_eq!(map.read_many(&requests, &mut out[..6]), Err(DharaError::BadLength));
+}
+
+#[test]
+fn format() -> () {
+    let mut map = new_map();
+    for s in 0..50 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+
+    // Some blocks that will fail to erase, and one already marked bad.
+    let failed = [0, 3, 40, 112];
+    for &b in failed.iter() {
+        map.journal.nand.sim_set_failed(b);
+    }
+    map.journal.nand.mark_bad(20);
+
+    assert_eq!(map.format(), Ok(failed.len() as u32 + 1));
+    for &b in failed.iter() {
+        assert!(map.journal.nand.is_bad(b as u32), "block {}", b);
+    }
+    assert_eq!(map.get_size(), 0);
+    assert_eq!(map.journal.journal_size(), 0);
+    assert!(map.get_capacity() > 0);
+    assert_eq!(map.find(0), Err(DharaError::NotFound));
+
+    // Blank until something is written, then usable as normal.
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+    for s in 0..50 {
+        write_seq(&mut map, s, s as u64 + 100);
+    }
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    for s in 0..50 {
+        assert_seq(&mut map, s, s as u64 + 100);
+    }
+
+    // A chip with almost nothing left.
+    let mut nand = SimNand::new();
+    nand.sim_reset();
+    for b in 1..nand.get_num_blocks() {
+        nand.sim_set_failed(b as usize);
+    }
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.format(), Err(DharaError::TooBad));
+}
+
+#[test]
+fn transaction() -> () {
+    let mut map = new_map();
+    for s in 0..10 {
+        write_seq(&mut map, s, s as u64);
+    }
+    let max_pages = (1 << map.journal.get_log2_ppc()) - 2;
+    assert!(max_pages >= 2);
+
+    // Fails part way: the write that did happen is undone.
+    let result = map.transaction(|m| {
+        write_seq(m, 1, 101);
+        m.trim(2)?;
+        Err(DharaError::NotFound)
+    });
+    assert_eq!(result, Err(DharaError::NotFound));
+    assert_eq!(map.get_size(), 10);
+    assert_seq(&mut map, 1, 1);
+    assert_seq(&mut map, 2, 2);
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 10);
+    assert_seq(&mut map, 1, 1);
+    assert_seq(&mut map, 2, 2);
+
+    // Succeeds: durable without a separate sync.
+    map.transaction(|m| {
+        write_seq(m, 1, 201);
+        m.trim(2)
+    }).expect("transaction");
+    assert!(map.journal.journal_is_clean());
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 9);
+    assert_seq(&mut map, 1, 201);
+    assert_eq!(map.find(2), Err(DharaError::NotFound));
+}
+
+#[test]
+fn user_cookie() -> () {
+    let mut map = new_map();
+    let size = map.journal.user_cookie_size();
+    assert!(size >= 16);
+    assert!(map.get_user_cookie().iter().all(|&b| b == 0xff));
+
+    map.set_user_cookie(b"settings v1").expect("set_user_cookie");
+    for s in 0..20 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 20);
+    assert_eq!(&map.get_user_cookie()[..11], b"settings v1");
+    assert!(map.get_user_cookie()[11..].iter().all(|&b| b == 0xff));
+
+    // On its own, a new cookie still needs a sync, and then sticks.
+    map.set_user_cookie(b"settings v2").expect("set_user_cookie");
+    assert!(!map.journal.journal_is_clean());
+    map.sync().expect("sync");
+    for s in 20..40 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 40);
+    assert_eq!(&map.get_user_cookie()[..11], b"settings v2");
+    for s in 0..40 {
+        assert_seq(&mut map, s, s as u64);
+    }
+
+    let too_long = vec![0u8; size + 1];
+    assert_eq!(map.set_user_cookie(&too_long), Err(DharaError::BadLength));
+}
+
+#[test]
+fn debug() -> () {
+    let mut map = new_map();
+    for s in 0..10 {
+        write_seq(&mut map, s, s as u64);
+    }
+    let head = map.journal.get_head();
+    let root = map.journal.get_root();
+
+    let text = format!("{:?}", map);
+    assert!(text.starts_with("DharaMap {"));
+    assert!(text.contains("count: 10"));
+    assert!(text.contains(&format!("head: {}", head)));
+    assert!(text.contains(&format!("root: {}", root)));
+
+    let text = format!("{:?}", map.journal);
+    assert!(text.starts_with("DharaJournal {"));
+    assert!(text.contains(&format!("head: {}", head)));
+}
+
+#[test]
+fn gc_steps() -> () {
+    let mut map = new_map();
+
+    // 19 stale copies of one sector, then the live one.
+    for rep in 0..20 {
+        write_seq(&mut map, 0, rep);
+    }
+    let size = map.journal.journal_size();
+    assert_eq!(map.gc_steps(0), Ok(0));
+    assert_eq!(map.journal.journal_size(), size);
+
+    assert_eq!(map.gc_steps(5), Ok(5));
+
+    // Only 14 stale pages are left. After that, each step just moves the
+    // live page along, which doesn't count.
+    assert_eq!(map.gc_steps(100), Ok(14));
+    assert_eq!(map.gc_steps(10), Ok(0));
+    assert_seq(&mut map, 0, 19);
+
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 1);
+    assert_seq(&mut map, 0, 19);
+}
+
+// The tree walks share the map's metadata buffers. Interleave every kind
+// of walk, with enough rewrites that garbage collection runs inside
+// writes, and check nothing leaks from one into the next.
+#[test]
+fn shared_meta() -> () {
+    let mut map = new_map();
+    let mut seeds: [Option<u64>; 64] = [None; 64];
+
+    for i in 0..2000u64 {
+        let s = ((i * 37) % 64) as DharaSector;
+        if i % 7 == 3 {
+            map.trim(s).expect("trim");
+            seeds[s as usize] = None;
+        } else {
+            write_seq(&mut map, s, i);
+            seeds[s as usize] = Some(i);
+        }
+
+        let probe = ((i * 11) % 64) as DharaSector;
+        let nearest = seeds[probe as usize..].iter().position(|x| x.is_some())
+            .map(|k| probe + k as DharaSector);
+        assert_eq!(map.find_nearest(probe), Ok(nearest));
+        if i % 50 == 0 {
+            map.gc().expect("gc");
+        }
+    }
+
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    for (s, seed) in seeds.iter().enumerate() {
+        match seed {
+            Some(seed) => assert_seq(&mut map, s as DharaSector, *seed),
+            None => assert_eq!(map.find(s as DharaSector), Err(DharaError::NotFound)),
+        }
+    }
+    assert_eq!(map.get_size(), seeds.iter().filter(|x| x.is_some()).count() as DharaSector);
+}
+
+// Each sync() here writes exactly one checkpoint, since no write fills a
+// group by itself.
+#[test]
+fn rewind() -> () {
+    let mut map = new_map();
+
+    write_seq(&mut map, 0, 1);
+    write_seq(&mut map, 1, 1);
+    map.sync().expect("sync");
+    write_seq(&mut map, 0, 2);
+    map.sync().expect("sync");
+    write_seq(&mut map, 2, 2);
+    map.sync().expect("sync");
+
+    // Refused, with an unsynced write pending, which must survive it.
+    write_seq(&mut map, 3, 3);
+    assert_eq!(map.rewind(1000), Err(DharaError::NotFound));
+    assert_eq!(map.journal.self_check(), Ok(()));
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 4);
+    assert_seq(&mut map, 3, 3);
+
+    // Back to the last checkpoint drops what hasn't been synced.
+    write_seq(&mut map, 4, 4);
+    map.rewind(0).expect("rewind");
+    assert_eq!(map.find(4), Err(DharaError::NotFound));
+
+    // Back past the write of sector 3 and then of sector 2. The old copy
+    // of sector 0 is still second, so stays.
+    map.rewind(2).expect("rewind");
+    assert_eq!(map.get_size(), 2);
+    assert_eq!(map.journal.self_check(), Ok(()));
+    assert!(!map.journal.journal_is_clean());
+    assert_seq(&mut map, 0, 2);
+    assert_eq!(map.find(2), Err(DharaError::NotFound));
+
+    // Durable once synced.
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 2);
+    assert_seq(&mut map, 0, 2);
+    assert_seq(&mut map, 1, 1);
+    assert_eq!(map.find(2), Err(DharaError::NotFound));
+    assert_eq!(map.find(3), Err(DharaError::NotFound));
+
+    // Writing on from there works as usual.
+    write_seq(&mut map, 5, 5);
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 3);
+    assert_seq(&mut map, 5, 5);
+}
+
+#[test]
+fn scrub_unreadable() -> () {
+    let mut map = new_map();
+    for s in 0..30 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+
+    let lost: DharaSector = 5;
+    let page = map.find(lost).expect("find");
+    let blk = page >> map.journal.get_log2_ppb();
+    map.journal.nand.sim_set_ecc_error(Some(page));
+
+    assert_eq!(map.scrub(2), Ok(ScrubReport { refreshed: 0, retired: 1 }));
+    assert!(map.journal.nand.is_bad(blk));
+
+    // Rescued, apart from the page that was already lost, which is still
+    // reported rather than reading as blank.
+    let check = |map: &mut SimMap| {
+        for s in 0..30 {
+            if s == lost {
+                let mut buf = [0u8; PAGE_SIZE];
+                assert_eq!(map.read(s, &mut buf), Err(DharaError::ECC));
+            } else {
+                assert_ne!(map.stat(s).expect("stat").expect("mapped").block, blk);
+                assert_seq(map, s, s as u64);
+            }
+        }
+    };
+    check(&mut map);
+
+    // The block is already retired, so there's nothing more to do.
+    assert_eq!(map.scrub(2), Ok(ScrubReport { refreshed: 0, retired: 0 }));
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 30);
+    check(&mut map);
+}
+
+#[test]
+fn retire_block() -> () {
+    let mut map = new_map();
+    assert_eq!(map.retire_block(map.journal.get_num_blocks()), Err(DharaError::NotFound));
+
+    // Enough to fill block 0 and start on block 1. Not synced, since that
+    // would move some along.
+    for s in 0..8 {
+        write_seq(&mut map, s, s as u64);
+    }
+    let sector_block = |map: &mut SimMap, s| map.stat(s).expect("stat").expect("mapped").block;
+    assert_eq!(sector_block(&mut map, 0), 0);
+
+    let retire = |map: &mut SimMap, blk| {
+        let bad = map.journal.get_bb_current();
+        map.retire_block(blk).expect("retire");
+        assert!(map.journal.nand.is_bad(blk));
+        assert!(map.journal.journal_is_clean());
+        assert_eq!(map.journal.get_bb_current(), bad + 1);
+        for s in 0..8 {
+            assert_ne!(sector_block(map, s), blk);
+            assert_seq(map, s, s as u64);
+        }
+    };
+    retire(&mut map, 0);
+
+    // Then the block the head has moved on to, which is partly written.
+    let head = map.journal.get_head();
+    let log2_ppb = map.journal.get_log2_ppb();
+    assert!(head & ((1 << log2_ppb) - 1) != 0);
+    retire(&mut map, head >> log2_ppb);
+
+    // Doing it again changes nothing.
+    let head = map.journal.get_head();
+    map.retire_block(0).expect("retire");
+    assert_eq!(map.journal.get_head(), head);
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 8);
+    assert_eq!(map.journal.get_bb_current(), 2);
+    for s in 0..8 {
+        assert_seq(&mut map, s, s as u64);
+    }
+}
+
+#[test]
+fn write_zeros() -> () {
+    let mut map = new_map();
+    let mut buf = [0x55u8; PAGE_SIZE];
+
+    write_seq(&mut map, 3, 3);
+    map.write_zeros(3).expect("write_zeros");
+    map.write_zeros(4).expect("write_zeros");
+    assert_eq!(map.get_size(), 2);
+    assert_eq!(map.write_zeros(DHARA_SECTOR_NONE), Err(DharaError::BadSector));
+
+    // Unlike a trimmed sector, which reads as blank.
+    map.trim(4).expect("trim");
+    map.read(4, &mut buf).expect("read");
+    assert_eq!(buf, [0xffu8; PAGE_SIZE]);
+
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    map.read(3, &mut buf).expect("read");
+    assert_eq!(buf, [0u8; PAGE_SIZE]);
+}
+
+#[test]
+fn estimate_capacity() -> () {
+    let nand = SimNand::new();
+    let (blocks, log2_ppb, log2_psize) = (nand.get_num_blocks(), nand.get_log2_ppb(), nand.get_log2_page_size());
+
+    for ratio in [0, 1, GC_RATIO, 255] {
+        let mut nand = SimNand::new();
+        nand.sim_erase_all();
+        let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], ratio);
+        assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+
+        assert_eq!(SimMap::estimate_capacity(blocks, log2_ppb, log2_psize, ratio), map.get_capacity());
+    }
+}
+
+// Capacity allows for the larger of the last pass's bad block count and
+// this pass's, as the C code does. On a formatted chip that means the
+// initial guess until a full pass has counted, and never more than was
+// found on the way.
+#[test]
+fn capacity_formatted_chip() -> () {
+    let mut map = new_map();
+    let (blocks, log2_ppb) = (map.journal.get_num_blocks(), map.journal.get_log2_ppb());
+    let log2_psize = map.journal.nand.get_log2_page_size();
+    let guessed = map.get_capacity();
+    assert_eq!(SimMap::estimate_capacity(blocks, log2_ppb, log2_psize, GC_RATIO), guessed);
+
+    // Resuming an existing chip gives the same answer as before.
+    for s in 0..20 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_capacity(), guessed);
+
+    // A full pass with no bad blocks replaces the guess, giving a block
+    // back, and a resume keeps it.
+    let epoch = map.journal.get_epoch();
+    let mut i = 0;
+    while map.journal.get_epoch() == epoch || map.journal.get_head() < 8 << log2_ppb {
+        write_seq(&mut map, 20 + i % 5, i as u64);
+        i += 1;
+    }
+    map.sync().expect("sync");
+    assert_eq!(map.journal.get_bb_last(), 0);
+    let counted = map.get_capacity();
+    assert!(counted > guessed);
+    map.resume().expect("resume");
+    assert_eq!(map.get_capacity(), counted);
+
+    // A block going bad on this pass counts straight away, before the
+    // pass finishes, and across a resume.
+    let head_blk = map.journal.get_head() >> log2_ppb;
+    map.retire_block(head_blk - 2).expect("retire");
+    assert_eq!(map.journal.get_bb_current(), 1);
+    let worn = map.get_capacity();
+    assert!(worn < counted);
+    map.resume().expect("resume");
+    assert_eq!(map.get_capacity(), worn);
+    for s in 0..20 {
+        assert_seq(&mut map, s, s as u64);
+    }
+}
+
+// A page enqueued with all-0xff metadata, as the C code writes filler, is
+// garbage to GC, whoever wrote it.
+#[test]
+fn c_filler() -> () {
+    let mut map = new_map();
+    let data = [0x42u8; PAGE_SIZE];
+    map.journal.journal_enqueue(Some(&data), Some(&[0xffu8; DHARA_META_SIZE])).expect("enqueue");
+    write_seq(&mut map, 0, 0);
+    assert_eq!(map.get_size(), 1);
+
+    assert_eq!(map.gc_steps(1), Ok(1));
+    assert_eq!(map.get_size(), 1);
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 1);
+    assert_seq(&mut map, 0, 0);
+}
+
+#[test]
+fn clear_cookie() -> () {
+    let mut map = new_map();
+    for s in 0..5 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    assert_eq!(map.journal.get_cookie(), 5);
+
+    // Zero straight away, not only once the map writes the next
+    // checkpoint.
+    map.clear();
+    assert_eq!(map.journal.get_cookie(), 0);
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 0);
+    assert_eq!(map.journal.get_cookie(), 0);
+
+    // Likewise when the last sector is trimmed.
+    write_seq(&mut map, 9, 9);
+    map.sync().expect("sync");
+    map.trim(9).expect("trim");
+    assert_eq!(map.journal.get_cookie(), 0);
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 0);
+}
+
+#[test]
+fn write_all() -> () {
+    let mut map = new_map();
+
+    // Three and a half pages.
+    let mut object = [0u8; PAGE_SIZE * 7 / 2];
+    seq_gen(9, &mut object);
+    map.write_all(10, &object).expect("write_all");
+    assert_eq!(map.get_size(), 4);
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+
+    let mut buf = [0u8; PAGE_SIZE];
+    for (i, chunk) in object.chunks(PAGE_SIZE).enumerate() {
+        map.read(10 + i as DharaSector, &mut buf).expect("read");
+        assert_eq!(&buf[..chunk.len()], chunk);
+    }
+    // The short last chunk is padded as blank.
+    assert!(buf[PAGE_SIZE / 2..].iter().all(|&b| b == 0xff));
+    assert_eq!(map.find(14), Err(DharaError::NotFound));
+
+    assert_eq!(map.write_all(20, &[]), Ok(()));
+    assert_eq!(map.get_size(), 4);
+
+    // Running into the reserved sector writes nothing.
+    let err = WriteAllError { sector: DHARA_SECTOR_NONE, error: DharaError::BadSector };
+    assert_eq!(map.write_all(DHARA_SECTOR_NONE - 2, &object), Err(err));
+    assert_eq!(map.get_size(), 4);
+    map.write_all(DHARA_SECTOR_NONE - 1, &object[..PAGE_SIZE]).expect("write_all");
+    assert_eq!(map.get_size(), 5);
+}
+
+#[test]
+fn zero_capacity() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let blocks = nand.get_num_blocks();
+
+    // A margin as big as the chip leaves no room for any sector.
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    map.set_safety_margin(blocks);
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+    assert_eq!(map.get_capacity(), 0);
+    assert!(!map.is_usable());
+
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(1, &mut buf);
+    for s in 0..3 {
+        assert_eq!(map.write(s, &buf), Err(DharaError::MapFull));
+    }
+    assert_eq!(map.get_size(), 0);
+
+    map.read(0, &mut buf).expect("read");
+    assert_eq!(buf, [0xffu8; PAGE_SIZE]);
+    assert_eq!(map.find(0), Err(DharaError::NotFound));
+    assert_eq!(map.find_nearest(0), Ok(None));
+    map.trim(0).expect("trim");
+    map.gc().expect("gc");
+    map.compact().expect("compact");
+    map.sync().expect("sync");
+    // Nothing was ever written, so there's no checkpoint to resume from.
+    assert_eq!(map.resume_or_format(), Ok(ResumeOutcome::Formatted));
+    assert_eq!(map.get_size(), 0);
+    assert!(!map.is_usable());
+
+    // Giving the margin back makes it usable.
+    map.set_safety_margin(0);
+    assert!(map.is_usable());
+    write_seq(&mut map, 0, 0);
+    assert_seq(&mut map, 0, 0);
+}
+
+#[test]
+fn hint_block() -> () {
+    let mut map = new_map();
+    assert_eq!(map.hint_block(4), Ok(None));
+
+    // Enough to span several blocks, some rewritten.
+    for s in 0..60 {
+        write_seq(&mut map, s % 40, s as u64);
+    }
+
+    for cache in [false, true] {
+        map.set_find_cache(cache);
+        for s in 0..45 {
+            let block = map.stat(s).expect("stat").map(|st| st.block);
+            assert_eq!(map.hint_block(s), Ok(block));
+        }
+    }
+    assert_eq!(map.hint_block(44), Ok(None));
+}
+
+#[test]
+fn shrink_to() -> () {
+    let mut map = new_map();
+    for s in 0..200 {
+        write_seq(&mut map, s, s as u64);
+    }
+    for s in 0..200 {
+        if s % 10 != 0 {
+            map.trim(s).expect("trim");
+        }
+    }
+    map.sync().expect("sync");
+    let before = map.journal.journal_size();
+
+    let freed = map.shrink_to().expect("shrink_to");
+    let after = map.journal.journal_size();
+    assert_eq!(freed, before - after);
+    assert!(after < before / 4, "{} -> {}", before, after);
+    assert_eq!(map.journal.tail_lag(), 0);
+    assert!(map.journal.journal_is_clean());
+
+    // Nothing left to give up a second time round, beyond what its own
+    // checkpoint costs.
+    assert!(map.shrink_to().expect("shrink_to") <= 4);
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 20);
+    for s in (0..200).step_by(10) {
+        assert_seq(&mut map, s, s as u64);
+    }
+}
+
+#[test]
+fn cancel() -> () {
+    let mut map = new_map();
+    for s in 0..100 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    for s in 0..50 {
+        map.trim(s).expect("trim");
+    }
+
+    // Part way into collecting the garbage.
+    let flag: &'static AtomicBool = Box::leak(Box::new(AtomicBool::new(false)));
+    map.set_cancel_check(Some(flag));
+    map.gc_steps(3).expect("gc_steps");
+    flag.store(true, Ordering::Relaxed);
+    assert_eq!(map.compact(), Err(DharaError::Cancelled));
+    assert_eq!(map.gc_steps(10), Err(DharaError::Cancelled));
+    assert_eq!(map.sync(), Err(DharaError::Cancelled));
+    let before = map.get_size();
+    let mut buf = [0u8; PAGE_SIZE];
+    assert_eq!(map.write(200, &buf), Err(DharaError::Cancelled));
+    assert_eq!(map.get_size(), before);
+    map.journal.self_check().expect("self_check");
+
+    // Still usable once no longer cancelled, and what was synced survives.
+    flag.store(false, Ordering::Relaxed);
+    map.gc_steps(1).expect("gc_steps");
+    map.set_cancel_check(None);
+    map.resume().expect("resume");
+    map.journal.self_check().expect("self_check");
+    // Some of the trims reached a checkpoint as they filled groups.
+    let size = map.get_size();
+    assert!((50..=100).contains(&size));
+    for s in 0..50 {
+        if map.find(s).is_ok() {
+            assert_seq(&mut map, s, s as u64);
+        }
+    }
+    for s in 50..100 {
+        assert_seq(&mut map, s, s as u64);
+    }
+    seq_gen(200, &mut buf);
+    map.write(200, &buf).expect("write");
+    map.compact().expect("compact");
+}
+
+#[test]
+fn capacity_breakdown() -> () {
+    let mut map = new_map();
+    let ppb: u32 = 1 << map.journal.get_log2_ppb();
+
+    let b = map.capacity_breakdown();
+    assert_eq!(b.raw, map.journal.journal_capacity());
+    assert_eq!(b.gc_reserve + b.safety_margin + b.usable, b.raw);
+    assert_eq!(b.usable, map.get_capacity());
+    assert_eq!(b.gc_reserve, b.raw / (GC_RATIO as u32 + 1));
+    assert_eq!(b.safety_margin, map.get_safety_margin() * ppb);
+
+    // A margin too big to fit takes the rest.
+    map.set_safety_margin(map.journal.nand.get_num_blocks());
+    let b = map.capacity_breakdown();
+    assert_eq!(b.usable, 0);
+    assert_eq!(b.gc_reserve + b.safety_margin, b.raw);
+    assert_eq!(map.get_capacity(), 0);
+}
+
+#[test]
+fn read_bad_length() -> () {
+    let mut map = new_map();
+    write_seq(&mut map, 1, 1);
+
+    // Mapped or not, the buffer is checked before anything is read.
+    let mut short = [0x55u8; PAGE_SIZE - 1];
+    assert_eq!(map.read(1, &mut short), Err(DharaError::BadLength));
+    assert_eq!(map.read(2, &mut short), Err(DharaError::BadLength));
+    assert!(short.iter().all(|&b| b == 0x55));
+    let mut long = [0u8; PAGE_SIZE + 1];
+    assert_eq!(map.read(1, &mut long), Err(DharaError::BadLength));
+    assert_seq(&mut map, 1, 1);
+}
+
+#[test]
+fn write_bad_length() -> () {
+    let mut map = new_map();
+    write_seq(&mut map, 1, 1);
+
+    // Refused before the map or the journal changes.
+    let head = map.journal.get_head();
+    let buf = [0x55u8; PAGE_SIZE + 1];
+    assert_eq!(map.write(1, &buf[..PAGE_SIZE - 1]), Err(DharaError::BadLength));
+    assert_eq!(map.write(2, &buf), Err(DharaError::BadLength));
+    assert_eq!(map.write(3, &[]), Err(DharaError::BadLength));
+    assert_eq!(map.journal.get_head(), head);
+    assert_eq!(map.get_size(), 1);
+    assert_seq(&mut map, 1, 1);
+}
+
+#[test]
+fn foreach_in_block() -> () {
+    let mut map = new_map();
+    for s in 0..40 {
+        write_seq(&mut map, s, s as u64);
+    }
+    // Some superseded and some trimmed, so not every page is live.
+    for s in 0..5 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.trim(20).expect("trim");
+    map.sync().expect("sync");
+
+    // Every live sector turns up exactly once, in its own block.
+    let blocks = map.journal.nand.get_num_blocks();
+    let mut seen = [0u32; 40];
+    for blk in 0..blocks {
+        let mut visited = Vec::new();
+        map.foreach_in_block(blk, |s, page| {
+            visited.push((s, page));
+            Ok(())
+        }).expect("foreach_in_block");
+
+        for (s, page) in visited {
+            assert_eq!(map.hint_block(s), Ok(Some(blk)));
+            assert_eq!(map.find(s), Ok(page));
+            seen[s as usize] += 1;
+        }
+    }
+    for (s, &n) in seen.iter().enumerate() {
+        assert_eq!(n, if s == 20 { 0 } else { 1 }, "sector {}", s);
+    }
+
+    // An error from the callback stops the walk.
+    let blk = map.hint_block(30).expect("hint").expect("mapped");
+    let mut calls = 0;
+    let r = map.foreach_in_block(blk, |_, _| {
+        calls += 1;
+        Err(DharaError::MapFull)
+    });
+    assert_eq!(r, Err(DharaError::MapFull));
+    assert_eq!(calls, 1);
+    assert_eq!(map.foreach_in_block(blocks, |_, _| Ok(())), Err(DharaError::NotFound));
+}
+
+#[test]
+fn recover_bounded() -> () {
+    // Every block fails soon after use, so each recovery attempt is cut
+    // short by the next failure. Whatever the order, the map gives up
+    // with TooBad in a bounded number of writes, rather than spinning.
+    for seed in 0..8 {
+        let mut nand = SimNand::new();
+        nand.sim_erase_all();
+        let mut map = MapBuilder::<PAGE_SIZE, _>::new(nand, [0u8; PAGE_SIZE])
+            .gc_ratio(GC_RATIO)
+            .max_retries(1)
+            .build()
+            .expect("build");
+        map.resume_or_format().expect("resume_or_format");
+        for s in 0..20 {
+            write_seq(&mut map, s, s as u64);
+        }
+        map.sync().expect("sync");
+
+        let blocks = map.journal.nand.get_num_blocks() as usize;
+        for b in 0..blocks {
+            map.journal.nand.sim_set_timebomb(b, 1 + (b + seed) % 3);
+        }
+
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut result = Ok(());
+        for i in 0..blocks << map.journal.get_log2_ppb() {
+            seq_gen(i as u64, &mut buf);
+            result = map.write(i as DharaSector % 20, &buf);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert_eq!(result, Err(DharaError::TooBad));
+        assert_eq!(map.recover_step(), Err(DharaError::TooBad));
+    }
+}
+
+#[test]
+fn size_on_flash() -> () {
+    let mut map = new_map();
+    assert_eq!(map.size_on_flash(), 0);
+
+    // Rewrites of one sector pile up as garbage on flash, until GC
+    // collects them.
+    let mut last = 0;
+    let mut i = 0;
+    while !map.needs_gc() {
+        write_seq(&mut map, 0, i);
+        assert_eq!(map.get_size(), 1);
+        assert!(map.size_on_flash() > last);
+        last = map.size_on_flash();
+        i += 1;
+    }
+    assert!(last >= map.get_capacity());
+
+    // The space only shows as free once a checkpoint follows, so collect
+    // until that has happened.
+    while map.needs_gc() {
+        map.gc().expect("gc");
+    }
+    assert_eq!(map.get_size(), 1);
+    assert!(map.size_on_flash() < last);
+    assert_seq(&mut map, 0, i - 1);
+}
+
+#[test]
+fn resume_verify_count() -> () {
+    let mut map = new_map();
+    for s in 0..10 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    assert_eq!(map.count_exact(), Ok(10));
+    assert_eq!(map.resume_verify_count(), Ok(false));
+    assert_eq!(map.get_size(), 10);
+
+    // Checkpoint a wrong cookie, the way pad_queue() would if it weren't
+    // kept in step with the count.
+    write_seq(&mut map, 10, 10);
+    map.journal.set_cookie(3);
+    let mut meta = [0u8; DHARA_META_SIZE];
+    while !map.journal.journal_is_clean() {
+        let root = map.journal.get_root();
+        map.journal.journal_read_meta(root, &mut meta).expect("read_meta");
+        map.journal.journal_copy(root, Some(&meta)).expect("copy");
+    }
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 3);
+    assert_eq!(map.resume_verify_count(), Ok(true));
+    assert_eq!(map.get_size(), 11);
+    assert_seq(&mut map, 10, 10);
+
+    // Once synced, the correction sticks.
+    assert!(!map.journal.journal_is_clean());
+    map.sync().expect("sync");
+    assert_eq!(map.resume_verify_count(), Ok(false));
+    assert_eq!(map.get_size(), 11);
+}
+
+#[test]
+fn verify_checkpoints() -> () {
+    let mut map = new_map();
+    map.journal.set_verify_checkpoints(true);
+    for s in 0..5 {
+        write_seq(&mut map, s, s as u64);
+    }
+
+    // Garble the header of the group holding sector 5. Unchecked, the map
+    // would go on to read garbage metadata from it. Checked, it's caught
+    // when written: the block is given up, and everything survives.
+    let header = map.journal.get_head() | ((1 << map.journal.get_log2_ppc()) - 1);
+    map.journal.nand.sim_set_garbled_prog(header);
+    for s in 5..20 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    assert!(map.journal.nand.is_bad(header >> map.journal.get_log2_ppb()));
+
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 20);
+    for s in 0..20 {
+        assert_seq(&mut map, s, s as u64);
+    }
+}
+
+#[test]
+fn defragment() -> () {
+    let mut map = new_map();
+
+    // Sectors 100..140 (bar 120) written out of order, interleaved with
+    // others.
+    for i in 0..40 {
+        let s = 100 + (i * 7) % 40;
+        if s != 120 {
+            write_seq(&mut map, s, s as u64);
+        }
+        write_seq(&mut map, i, i as u64);
+    }
+    map.sync().expect("sync");
+
+    assert_eq!(map.defragment(100, 40), Ok(39));
+    let pages: Vec<DharaPage> = (100..140).filter(|&s| s != 120)
+        .map(|s| map.find(s).expect("find"))
+        .collect();
+    assert!(pages.windows(2).all(|w| w[0] < w[1]));
+    // Contiguous, apart from the checkpoint pages in between.
+    let ppc = 1 << map.journal.get_log2_ppc();
+    assert!(pages[38] - pages[0] < 39 + 39 / (ppc - 1) + 1);
+
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 79);
+    for s in (0..40).chain(100..140).filter(|&s| s != 120) {
+        assert_seq(&mut map, s, s as u64);
+    }
+
+    assert_eq!(map.defragment(200, 0), Ok(0));
+    assert_eq!(map.defragment(200, 10), Ok(0));
+    assert_eq!(map.defragment(DHARA_SECTOR_NONE - 1, 2), Err(DharaError::BadSector));
+}
+
+#[test]
+fn resume_with_bbt() -> () {
+    let mut guess = new_map();
+    let guessed = guess.journal.get_bb_last();
+    let guessed_cap = guess.get_capacity();
+
+    // More bad blocks than the guess allows for, with a duplicate and one
+    // out of range, which don't count.
+    let bbt: [u32; 6] = [5, 9, 40, 41, 9, 1000];
+    assert!(guessed < 4);
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume_with_bbt(&bbt), Err(DharaError::Blank));
+    assert_eq!(map.journal.get_bb_last(), 4);
+    let seeded = map.get_capacity();
+    assert!(seeded < guessed_cap);
+
+    // A table with none bad replaces the guess too, giving capacity back.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    assert_eq!(map.resume_with_bbt(&[]), Err(DharaError::Blank));
+    assert_eq!(map.journal.get_bb_last(), 0);
+    assert!(map.get_capacity() > guessed_cap);
+
+    // After a resume, the table only raises the checkpoint's count.
+    write_seq(&mut guess, 0, 0);
+    guess.sync().expect("sync");
+    guess.resume_with_bbt(&[]).expect("resume");
+    assert_eq!(guess.journal.get_bb_last(), guessed);
+    assert_eq!(guess.get_capacity(), guessed_cap);
+    guess.resume_with_bbt(&bbt).expect("resume");
+    assert_eq!(guess.journal.get_bb_last(), 4);
+    assert_eq!(guess.get_capacity(), seeded);
+    assert_seq(&mut guess, 0, 0);
+}
+
+#[test]
+fn read_filled() -> () {
+    let mut map = new_map();
+    let blank = [0xffu8; PAGE_SIZE];
+    map.write(1, &blank).expect("write");
+
+    let mut buf = [0x55u8; PAGE_SIZE];
+    map.read_filled(2, &mut buf, 0).expect("read_filled");
+    assert_eq!(buf, [0u8; PAGE_SIZE]);
+    map.read_filled(2, &mut buf, 0xa5).expect("read_filled");
+    assert_eq!(buf, [0xa5u8; PAGE_SIZE]);
+
+    // Mapped, so returned as written, even though it looks blank.
+    map.read_filled(1, &mut buf, 0).expect("read_filled");
+    assert_eq!(buf, blank);
+
+    write_seq(&mut map, 3, 3);
+    map.read_filled(3, &mut buf, 0).expect("read_filled");
+    seq_assert(3, &buf);
+    assert_eq!(map.read_filled(3, &mut buf[1..], 0), Err(DharaError::BadLength));
+}
+
+#[test]
+fn auto_sync() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut map = MapBuilder::<PAGE_SIZE, _>::new(nand, [0u8; PAGE_SIZE])
+        .gc_ratio(GC_RATIO)
+        .auto_sync(true)
+        .build()
+        .expect("build");
+    map.resume_or_format().expect("resume_or_format");
+    assert!(map.get_auto_sync());
+
+    // No sync() anywhere: each change is durable as soon as it returns.
+    write_seq(&mut map, 7, 7);
+    assert!(map.journal.journal_is_clean());
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 1);
+    assert_seq(&mut map, 7, 7);
+
+    write_seq(&mut map, 8, 8);
+    map.trim(7).expect("trim");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 1);
+    assert!(map.find(7).is_err());
+    assert_seq(&mut map, 8, 8);
+
+    // Off again, changes wait for a sync.
+    map.set_auto_sync(false);
+    write_seq(&mut map, 9, 9);
+    map.resume().expect("resume");
+    assert!(map.find(9).is_err());
+}
+
+#[test]
+fn refresh_root() -> () {
+    let mut map = new_map();
+    let head = map.journal.get_head();
+    map.refresh_root().expect("refresh_root");
+    assert_eq!(map.journal.get_head(), head);
+    assert_eq!(map.journal.journal_root(), DHARA_PAGE_NONE);
+
+    for s in 0..20 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+    let root = map.journal.journal_root();
+    let mut meta = [0u8; DHARA_META_SIZE];
+    map.journal.journal_read_meta(root, &mut meta).expect("read_meta");
+
+    map.refresh_root().expect("refresh_root");
+    let moved = map.journal.journal_root();
+    assert!(moved > root);
+    let mut moved_meta = [0u8; DHARA_META_SIZE];
+    map.journal.journal_read_meta(moved, &mut moved_meta).expect("read_meta");
+    assert_eq!(moved_meta, meta);
+
+    map.sync().expect("sync");
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 20);
+    for s in 0..20 {
+        assert_seq(&mut map, s, s as u64);
+    }
+}
+
+#[test]
+fn iter_changes_since() -> () {
+    let mut map = new_map();
+    for s in 0..30 {
+        write_seq(&mut map, s, s as u64);
+    }
+    map.sync().expect("sync");
+
+    let snap = map.snapshot();
+    assert_eq!(map.iter_changes_since(snap).count(), 0);
+    for s in [12, 5, 29, 40] {
+        write_seq(&mut map, s, s as u64 + 100);
+    }
+    let changes: Vec<DharaSector> = map.iter_changes_since(snap).map(|s| s.expect("changes")).collect();
+    assert_eq!(changes, [5, 12, 29, 40]);
+
+    // A trimmed sector is gone, but trimming moves a neighbour's page up
+    // to stand in its place.
+    map.trim(3).expect("trim");
+    let changes: Vec<DharaSector> = map.iter_changes_since(snap).map(|s| s.expect("changes")).collect();
+    assert!(!changes.contains(&3));
+    assert_eq!(changes.len(), 5);
+
+    // Across the head wrapping round the chip: listed are exactly those
+    // sectors now somewhere new, whether rewritten or moved by GC.
+    let chip = map.journal.nand.total_pages();
+    let mut i = 0;
+    while map.journal.get_head() < chip / 2 {
+        write_seq(&mut map, 50, i);
+        i += 1;
+    }
+    let snap = map.snapshot();
+    let before: Vec<DharaPage> = (0..30).map(|s| map.find(s).unwrap_or(DHARA_PAGE_NONE)).collect();
+    while map.journal.get_epoch() == snap.epoch || map.journal.get_head() < chip / 4 {
+        write_seq(&mut map, 50, i);
+        i += 1;
+    }
+    let expected: Vec<DharaSector> = (0..30)
+        .filter(|&s| s != 3 && map.find(s).expect("find") != before[s as usize])
+        .chain([40, 50])
+        .collect();
+    let changes: Vec<DharaSector> = map.iter_changes_since(snap).map(|s| s.expect("changes")).collect();
+    assert_eq!(changes, expected);
+    assert!(changes.len() > 2);
+
+    // Once the head has been right round, everything is newer.
+    while map.journal.get_head() <= snap.head {
+        write_seq(&mut map, 50, i);
+        i += 1;
+    }
+    assert_eq!(map.iter_changes_since(snap).count() as DharaSector, map.get_size());
+}
+
+#[test]
+fn existence_bitmap() -> () {
+    let mut map = new_map();
+    for s in [3, 10, 11, 17, 40, 41, 63, 64, 100] {
+        write_seq(&mut map, s, s as u64);
+    }
+
+    // Sectors 10..30: 10, 11 and 17 are bits 0, 1 and 7. The byte past
+    // the range is left alone.
+    let mut out = [0x55u8; 4];
+    map.existence_bitmap(10, 20, &mut out).expect("existence_bitmap");
+    assert_eq!(out, [0x83, 0x00, 0x00, 0x55]);
+
+    let mut out = [0xffu8; 13];
+    map.existence_bitmap(0, 101, &mut out).expect("existence_bitmap");
+    for i in 0..101 {
+        let mapped = out[i / 8] & (1 << (i % 8)) != 0;
+        assert_eq!(mapped, map.find(i as DharaSector).is_ok(), "sector {i}");
+    }
+
+    // Partial last byte: sector 64 is past the range.
+    let mut out = [0u8; 1];
+    map.existence_bitmap(60, 4, &mut out).expect("existence_bitmap");
+    assert_eq!(out, [0x08]);
+    map.existence_bitmap(200, 0, &mut []).expect("existence_bitmap");
+    assert_eq!(map.existence_bitmap(0, 9, &mut [0u8; 1]), Err(DharaError::BadLength));
+}
+
+#[test]
+fn reclaim_slack() -> () {
+    // Sectors rewritten in turn, so the oldest pages, at the tail, are
+    // all garbage.
+    let mut map = new_map();
+    for round in 0..4 {
+        for s in 0..10 {
+            write_seq(&mut map, s, round * 100 + s as u64);
+        }
+    }
+    map.sync().expect("sync");
+
+    map.resume().expect("resume");
+    assert!(!map.get_reclaim_slack());
+    let before = map.size_on_flash();
+
+    map.set_reclaim_slack(true);
+    map.resume().expect("resume");
+    let after = map.size_on_flash();
+    assert!(after < before);
+    assert!(after >= 10);
+    assert!(map.journal.journal_is_clean());
+    assert_eq!(map.get_size(), 10);
+    for s in 0..10 {
+        assert_seq(&mut map, s, 300 + s as u64);
+    }
+
+    // Nothing was written in between, so resuming again finds the same.
+    map.resume().expect("resume");
+    assert_eq!(map.size_on_flash(), after);
+
+    // And the map carries on from there.
+    write_seq(&mut map, 20, 20);
+    map.sync().expect("sync");
+    map.set_reclaim_slack(false);
+    map.resume().expect("resume");
+    assert_eq!(map.get_size(), 11);
+    assert_seq(&mut map, 0, 300);
+    assert_seq(&mut map, 20, 20);
+}
+
+#[test]
+fn copy_from() -> () {
+    let mut src = new_map();
+    for s in (0..200).step_by(3) {
+        write_seq(&mut src, s, s as u64 + 7);
+    }
+    write_seq(&mut src, 5000, 5000);
+
+    // Into a map over a different driver.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut dst = DharaMap::<PAGE_SIZE, CountingNand<SimNand>>::new(CountingNand::new(nand), [0u8; PAGE_SIZE], GC_RATIO);
+    dst.resume_or_format().expect("resume_or_format");
+    dst.copy_from(&mut src).expect("copy_from");
+    assert_eq!(dst.get_size(), src.get_size());
+    assert!(dst.journal.journal_is_clean());
+
+    dst.resume().expect("resume");
+    let sectors: Vec<DharaSector> = dst.sectors().map(|s| s.expect("sectors")).collect();
+    assert_eq!(sectors, src.sectors().map(|s| s.expect("sectors")).collect::<Vec<_>>());
+    let mut buf = [0u8; PAGE_SIZE];
+    for s in sectors {
+        dst.read(s, &mut buf).expect("read");
+        seq_assert(if s == 5000 { 5000 } else { s as u64 + 7 }, &buf);
+    }
+
+    // Too big for the destination: nothing copied.
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+    let mut small = SimMap::new(nand, [0u8; PAGE_SIZE], GC_RATIO);
+    small.resume_or_format().expect("resume_or_format");
+    let blocks = small.journal.nand.get_num_blocks();
+    small.set_safety_margin(blocks - 10);
+    assert!(small.get_capacity() < src.get_size());
+    assert_eq!(small.copy_from(&mut src), Err(DharaError::MapFull));
+    assert_eq!(small.get_size(), 0);
+}
@@ -0,0 +1,52 @@
+mod sim;
+
+use dhara_rs::nand::DharaNand;
+use dhara_rs::subdivided::SubdividedNand;
+use sim::{SimNand, LOG2_PAGES_PER_BLOCK, NUM_BLOCKS, PAGE_SIZE};
+
+#[test]
+fn sizes() -> () {
+    let nand = SimNand::new();
+    assert_eq!(nand.page_size(), 512);
+    assert_eq!(nand.page_size(), PAGE_SIZE);
+    assert_eq!(nand.pages_per_block(), 8);
+    assert_eq!(nand.block_size(), 4096);
+    assert_eq!(nand.total_pages(), (NUM_BLOCKS as u32) << LOG2_PAGES_PER_BLOCK);
+
+    // Subdividing changes the pages, but not the blocks.
+    let sub: SubdividedNand<SimNand, 4> = SubdividedNand::new(SimNand::new());
+    assert_eq!(sub.page_size(), 128);
+    assert_eq!(sub.pages_per_block(), 32);
+    assert_eq!(sub.block_size(), nand.block_size());
+    assert_eq!(sub.total_pages(), nand.total_pages() * 4);
+}
+
+#[test]
+fn seeded_sim() -> () {
+    // Failures only show up as prog/erase errors, so compare by trying
+    // every block.
+    fn failed_blocks(seed: u64) -> Vec<u32> {
+        let mut nand = SimNand::new_seeded(seed);
+        nand.sim_reset();
+        nand.sim_inject_bad(10);
+        nand.sim_inject_failed(10);
+        (0..nand.get_num_blocks())
+            .filter(|&b| nand.is_bad(b) || nand.erase(b).is_err())
+            .collect()
+    }
+
+    assert_eq!(failed_blocks(7), failed_blocks(7));
+    assert!(!failed_blocks(7).is_empty());
+    assert_ne!(failed_blocks(7), failed_blocks(8));
+}
+
+#[test]
+fn read_exact() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut short = [0u8; 16];
+    assert_eq!(nand.read_exact(0, 0, 32, &mut short), Err(dhara_rs::DharaError::BadLength));
+    assert_eq!(nand.read_exact(0, 0, 16, &mut short), Ok(()));
+    assert_eq!(short, [0xffu8; 16]);
+}
@@ -0,0 +1,74 @@
+#![cfg(feature = "alloc")]
+
+mod sim;
+
+use dhara_rs::replay::{replay, MapOp, OpRecorder};
+use dhara_rs::{DharaError, DharaMap};
+use sim::{seq_gen, SimNand, PAGE_SIZE};
+
+type SimMap = DharaMap::<512, SimNand>;
+
+fn new_map() -> SimMap {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    map
+}
+
+#[test]
+fn record_and_replay() -> () {
+    let mut rec = OpRecorder::new(new_map());
+    let mut buf = [0u8; PAGE_SIZE];
+    for s in 0..30 {
+        seq_gen(s as u64, &mut buf);
+        rec.write(s % 12, &buf).expect("write");
+        if s % 7 == 0 {
+            rec.trim(s % 5).expect("trim");
+        }
+        if s % 10 == 9 {
+            rec.sync().expect("sync");
+        }
+    }
+    rec.gc().expect("gc");
+    rec.read(3, &mut buf).expect("read");
+    rec.sync().expect("sync");
+    rec.resume().expect("resume");
+    assert_eq!(rec.ops().len(), 30 + 5 + 3 + 4);
+    assert_eq!(rec.ops()[1], MapOp::Trim(0));
+
+    let ops = rec.take_ops();
+    assert!(rec.ops().is_empty());
+    let mut original = rec.into_inner();
+
+    let mut copy = new_map();
+    replay(&mut copy, &ops).expect("replay");
+
+    // The same state, down to where each page landed.
+    assert_eq!(copy.get_size(), original.get_size());
+    assert_eq!(copy.journal.get_head(), original.journal.get_head());
+    assert_eq!(copy.journal.get_tail(), original.journal.get_tail());
+    assert_eq!(copy.journal.get_root(), original.journal.get_root());
+    let mut a = [0u8; PAGE_SIZE];
+    let mut b = [0u8; PAGE_SIZE];
+    for s in 0..12 {
+        assert_eq!(copy.find(s), original.find(s));
+        copy.read(s, &mut a).expect("read");
+        original.read(s, &mut b).expect("read");
+        assert_eq!(a, b);
+    }
+}
+
+#[test]
+fn replay_failure() -> () {
+    let ops = vec![
+        MapOp::Write(1, vec![0u8; PAGE_SIZE]),
+        MapOp::Sync,
+        MapOp::Write(dhara_rs::DHARA_SECTOR_NONE, vec![0u8; PAGE_SIZE]),
+        MapOp::Trim(1),
+    ];
+    let mut map = new_map();
+    assert_eq!(replay(&mut map, &ops), Err((2, DharaError::BadSector)));
+    assert_eq!(map.get_size(), 1);
+}
@@ -1,5 +1,5 @@
 use dhara_rs::DharaError;
-use dhara_rs::nand::{DharaBlock, DharaNand, DharaPage};
+use dhara_rs::nand::{DharaBlock, DharaNand, DharaNandPartial, DharaPage};
 use dhara_rs::journal::DharaJournal;
 
 use rand::{Rng, RngCore, SeedableRng};
@@ -23,6 +23,8 @@ const MEM_SIZE: usize       = NUM_BLOCKS * BLOCK_SIZE; // 4096 * 113 = 462_848 b
 const BLOCK_BAD_MARK: u8 = 0x01;
 const BLOCK_FAILED: u8   = 0x02;
 const BLOCK_BOTH: u8 = BLOCK_FAILED | BLOCK_BAD_MARK;
+// Erases report success but leave the block as it was.
+const BLOCK_STUCK: u8    = 0x04;
 
 // Struct used to capture call counts.
 #[derive(Default)]
@@ -62,12 +64,31 @@ pub struct SimNand {
     blocks: [BlockStatus; NUM_BLOCKS],
     // Keep track of statistics.
     stats: SimStats,
+    // Bits of ECC correction each page reports through read_ecc().
+    corrections: Vec<u32>,
+    // A page whose next read comes back with its first byte wrong.
+    flaky_page: Option<DharaPage>,
+    // A page whose next program stores garbage but reports success.
+    garbled_page: Option<DharaPage>,
+    // A page every read of which fails uncorrectably.
+    ecc_page: Option<DharaPage>,
+    // Source for the sim_inject_*() functions. Seed it with new_seeded()
+    // to replay a failing run exactly.
+    rng: SmallRng,
 }
 
 // Implementation of non-DharaNand methods.
 #[allow(dead_code)] // Methods used across test modules but not detected by dead code analysis
 impl SimNand {
     pub fn new() -> Self {
+        Self::with_rng(SmallRng::from_entropy())
+    }
+
+    pub fn new_seeded(seed: u64) -> Self {
+        Self::with_rng(SmallRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rng: SmallRng) -> Self {
         let block = BlockStatus {flags: 0, next_page: PAGES_PER_BLOCK,
             timebomb: 0};
         let blocks = [block; NUM_BLOCKS];
@@ -83,12 +104,18 @@ impl SimNand {
             blocks: blocks,
             // Keep track of statistics.
             stats: Default::default(),
+            corrections: vec![0; NUM_BLOCKS * PAGES_PER_BLOCK],
+            flaky_page: None,
+            garbled_page: None,
+            ecc_page: None,
+            rng: rng,
         }
     }
 
     pub fn sim_reset(&mut self) -> () {
         self.stats = Default::default();
         self.pages.fill(0x55);
+        self.corrections.fill(0);
         for block in self.blocks.iter_mut() {
             block.flags = 0;
             block.next_page = PAGES_PER_BLOCK;
@@ -96,6 +123,20 @@ impl SimNand {
         }
     }
 
+    // Like a chip fresh from the factory: every block erased, none bad.
+    // Unlike sim_reset(), blocks come back free.
+    #[allow(dead_code)] // Used across test modules but not detected by dead code analysis
+    pub fn sim_erase_all(&mut self) -> () {
+        self.stats = Default::default();
+        self.pages.fill(0xff);
+        self.corrections.fill(0);
+        for block in self.blocks.iter_mut() {
+            block.flags = 0;
+            block.next_page = 0;
+            block.timebomb = 0;
+        }
+    }
+
     pub fn timebomb_tick(&mut self, blkno: usize) -> () {
         if self.blocks[blkno].timebomb != 0 {
             self.blocks[blkno].timebomb -= 1;
@@ -122,37 +163,56 @@ impl SimNand {
         self.blocks[blkno].flags |= BLOCK_FAILED;
     }
 
+    // Corrupt the next read of this page, and only that one, as a
+    // controller with a transient fault might.
+    pub fn sim_set_flaky_read(&mut self, page: DharaPage) -> () {
+        self.flaky_page = Some(page);
+    }
+
+    // Make the next program of this page, and only that one, store
+    // garbage while reporting success, as a faulty controller might.
+    pub fn sim_set_garbled_prog(&mut self, page: DharaPage) -> () {
+        self.garbled_page = Some(page);
+    }
+
+    // Make every read of this page fail with an uncorrectable ECC error,
+    // until set back to None.
+    pub fn sim_set_ecc_error(&mut self, page: Option<DharaPage>) -> () {
+        self.ecc_page = page;
+    }
+
+    pub fn sim_set_stuck(&mut self, blkno: usize) -> () {
+        self.blocks[blkno].flags |= BLOCK_STUCK;
+    }
+
+    // Make read_ecc() report this many corrected bits for a page, until
+    // it's next programmed or erased.
+    pub fn sim_set_corrections(&mut self, page: DharaPage, bits: u32) -> () {
+        self.corrections[page as usize] = bits;
+    }
+
     pub fn sim_set_timebomb(&mut self, blkno: usize, ttl: usize) -> () {
         self.blocks[blkno].timebomb = ttl;
     }
 
     pub fn sim_inject_bad(&mut self, count: usize) -> () {
-        // Cache the generator for better loop performance.
-        let mut rng = rand::thread_rng();
-
         for _i in 0..count {
-            let blkno: usize = rng.gen::<usize>() % (NUM_BLOCKS);
+            let blkno: usize = self.rng.gen::<usize>() % (NUM_BLOCKS);
             self.blocks[blkno].flags |= BLOCK_BOTH;
         }
     }
 
     pub fn sim_inject_failed(&mut self, count: usize) -> () {
-        // Cache the generator for better loop performance.
-        let mut rng = rand::thread_rng();
-
         for _i in 0..count {
-            let blkno: usize = rng.gen::<usize>() % (NUM_BLOCKS);
+            let blkno: usize = self.rng.gen::<usize>() % (NUM_BLOCKS);
             self.sim_set_failed(blkno);
         }
     }
 
     pub fn sim_inject_timebombs(&mut self, count: usize, max_ttl: usize) -> () {
-        // Cache the generator for better loop performance.
-        let mut rng = rand::thread_rng();
-
         for _i in 0..count {
-            let blkno: usize = rng.gen::<usize>() % (NUM_BLOCKS);
-            let ttl: usize = rng.gen::<usize>() % max_ttl + 1;
+            let blkno: usize = self.rng.gen::<usize>() % (NUM_BLOCKS);
+            let ttl: usize = self.rng.gen::<usize>() % max_ttl + 1;
             self.sim_set_timebomb(blkno, ttl);
         }
     }
@@ -243,6 +303,10 @@ impl DharaNand for SimNand {
             self.stats.erase += 1;
         }
 
+        if self.blocks[block].flags & BLOCK_STUCK != 0 {
+            return Ok(());
+        }
+
         // Remove the PAGES_PER_BLOCK indication of full.
         self.blocks[block].next_page = 0;
 
@@ -259,6 +323,7 @@ impl DharaNand for SimNand {
         }
         
         self.pages[blk_idx..(blk_idx + BLOCK_SIZE)].fill(0xFF);
+        self.corrections[(block << LOG2_PAGES_PER_BLOCK)..((block + 1) << LOG2_PAGES_PER_BLOCK)].fill(0);
         Ok(())
     }
     
@@ -277,12 +342,25 @@ impl DharaNand for SimNand {
             self.stats.read_bytes += length;
         }
 
+        if self.ecc_page == Some(page) {
+            return Err(DharaError::ECC);
+        }
+
         let start: usize = page_idx + offset;
         let end: usize = start + length;
         data.copy_from_slice(&self.pages[start..end]);
+        if self.flaky_page == Some(page) && length > 0 {
+            self.flaky_page = None;
+            data[0] ^= 0xff;
+        }
         Ok(())
     }
 
+    fn read_ecc(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<u32, DharaError> {
+        self.read(page, offset, length, data)?;
+        Ok(self.corrections[page as usize])
+    }
+
     fn copy(&mut self, src: DharaPage, dst: DharaPage) -> Result<(),DharaError> {
         let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
         self.read(src, 0, PAGE_SIZE, &mut buf)?;
@@ -316,11 +394,79 @@ impl DharaNand for SimNand {
         }
 
         self.pages[page_idx..page_idx+PAGE_SIZE].copy_from_slice(data);
+        if self.garbled_page == Some(page) {
+            self.garbled_page = None;
+            seq_gen((page * 57 + 29) as u64, &mut self.pages[page_idx..(page_idx+PAGE_SIZE)]);
+        }
+        self.corrections[page as usize] = 0;
+        Ok(())
+    }
+
+}
+
+// Partial programming, so that SimNand can sit underneath SubdividedNand.
+impl DharaNandPartial for SimNand {
+    fn prog_partial(&mut self, page: DharaPage, offset: usize, data: &[u8]) -> Result<(),DharaError> {
+        let blkno: usize = (page >> LOG2_PAGES_PER_BLOCK) as usize;
+        let pageno: usize = (page as usize) & ((1 << LOG2_PAGES_PER_BLOCK) - 1);
+        let start: usize = ((page as usize) << LOG2_PAGE_SIZE) + offset;
+        let end: usize = start + data.len();
+        assert!(blkno < NUM_BLOCKS, "sim: prog_partial called on invalid block {blkno}");
+        assert!(offset + data.len() <= PAGE_SIZE, "sim: prog_partial called on invalid range: \
+            offset = {offset}, length = {}", data.len());
+        assert!(self.blocks[blkno].flags & BLOCK_BAD_MARK == 0, "sim: prog_partial \
+            called on block which is marked bad: {blkno}");
+        // Either a fresh page, or an unprogrammed region of the last page.
+        let in_order = pageno >= self.blocks[blkno].next_page
+            || (pageno + 1 == self.blocks[blkno].next_page
+                && self.pages[start..end].iter().all(|&b| b == 0xFF));
+        assert!(in_order, "sim: prog_partial out-of-order programming.  Block {blkno}, \
+            page {pageno}, offset {offset} (expected page {})", self.blocks[blkno].next_page);
+
+        if !self.stats.frozen {
+            self.stats.prog += 1;
+        }
+        self.blocks[blkno].next_page = pageno + 1;
+        self.timebomb_tick(blkno);
+
+        if self.blocks[blkno].flags & BLOCK_FAILED != 0 {
+            if !self.stats.frozen {
+                self.stats.prog_fail += 1;
+            }
+            seq_gen((page as usize * 57 + offset + 29) as u64, &mut self.pages[start..end]);
+            return Err(DharaError::BadBlock);
+        }
+
+        self.pages[start..end].copy_from_slice(data);
         Ok(())
     }
 
+    fn is_free_partial(&mut self, page: DharaPage, offset: usize, length: usize) -> bool {
+        let blk: usize = (page >> LOG2_PAGES_PER_BLOCK) as usize;
+        let pageno: usize = (page as usize) & ((1 << LOG2_PAGES_PER_BLOCK) - 1);
+        let start: usize = ((page as usize) << LOG2_PAGE_SIZE) + offset;
+        assert!(blk < NUM_BLOCKS, "sim: is_free_partial called on invalid block {blk}");
+
+        if !self.stats.frozen {
+            self.stats.is_erased += 1;
+        }
+        if pageno + 1 == self.blocks[blk].next_page {
+            // Can't tell unprogrammed from programmed with 0xff, which
+            // the trait allows.
+            return self.pages[start..start+length].iter().all(|&b| b == 0xFF);
+        }
+        self.blocks[blk].next_page <= pageno
+    }
+
+    fn copy_partial(&mut self, src: DharaPage, src_offset: usize,
+            dst: DharaPage, dst_offset: usize, length: usize) -> Result<(),DharaError> {
+        let mut buf: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+        self.read(src, src_offset, length, &mut buf[..length])?;
+        self.prog_partial(dst, dst_offset, &buf[..length])
+    }
 }
 
+#[allow(dead_code)] // Used across test modules but not detected by dead code analysis
 pub fn seq_gen(seed: u64, buf: &mut[u8]) -> () {
     let mut small_rng = SmallRng::seed_from_u64(seed);
     small_rng.fill_bytes(buf);
@@ -331,6 +477,7 @@ pub fn seq_gen(seed: u64, buf: &mut[u8]) -> () {
 
 }
 
+#[allow(dead_code)] // Used across test modules but not detected by dead code analysis
 pub fn seq_assert(seed: u64, buf: &[u8]) -> () {
     let mut small_rng = SmallRng::seed_from_u64(seed);
     let length = buf.len();
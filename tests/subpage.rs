@@ -0,0 +1,215 @@
+mod sim;
+
+use dhara_rs::subpage::SubpageMap;
+use dhara_rs::{DharaError, DharaMap, DharaSector};
+use sim::{seq_assert, seq_gen, SimNand, PAGE_SIZE};
+
+// Seven 64-byte sectors, and their footer, to each 512-byte page.
+const SECTOR_SIZE: usize = 64;
+type SmallMap = SubpageMap<PAGE_SIZE, SECTOR_SIZE, SimNand>;
+
+fn new_map() -> SmallMap {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = DharaMap::<PAGE_SIZE, SimNand>::new(nand, [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    SmallMap::new(map).expect("new")
+}
+
+fn write(m: &mut SmallMap, s: DharaSector, seed: u64) -> () {
+    let mut buf = [0u8; SECTOR_SIZE];
+    seq_gen(seed, &mut buf);
+    m.write(s, &buf).expect("write");
+}
+
+fn check(m: &mut SmallMap, s: DharaSector, seed: u64) -> () {
+    let mut buf = [0u8; SECTOR_SIZE];
+    m.read(s, &mut buf).expect("read");
+    seq_assert(seed, &buf);
+}
+
+fn check_blank(m: &mut SmallMap, s: DharaSector) -> () {
+    let mut buf = [0u8; SECTOR_SIZE];
+    m.read(s, &mut buf).expect("read");
+    assert_eq!(buf, [0xffu8; SECTOR_SIZE]);
+}
+
+#[test]
+fn packing() -> () {
+    let mut m = new_map();
+    assert_eq!(m.sectors_per_page(), 7);
+    assert!(m.get_capacity() > m.inner().get_capacity() * 4);
+    assert!(m.get_capacity() < m.inner().get_capacity() * 7);
+
+    for s in 0..100 {
+        write(&mut m, s, s as u64);
+    }
+    // Readable before and after they reach the map.
+    for s in 0..100 {
+        check(&mut m, s, s as u64);
+    }
+    m.sync().expect("sync");
+    assert_eq!(m.get_size(), 100);
+
+    // One page per seven sectors, and one index page.
+    assert_eq!(m.inner().get_size(), 15 + 1);
+    let (page, offset) = m.find(0).expect("find");
+    assert_eq!(offset, 0);
+    for s in 1..7 {
+        assert_eq!(m.find(s), Ok((page, s as usize * SECTOR_SIZE)));
+    }
+    assert_ne!(m.find(7).expect("find").0, page);
+    assert_eq!(m.find(100 + 7), Err(DharaError::NotFound));
+
+    // Rewrites and trims.
+    write(&mut m, 3, 1000);
+    m.trim(4).expect("trim");
+    m.trim(4).expect("trim");
+    assert_eq!(m.get_size(), 99);
+    m.sync().expect("sync");
+
+    m.resume().expect("resume");
+    assert_eq!(m.get_size(), 99);
+    for s in 0..100 {
+        match s {
+            3 => check(&mut m, s, 1000),
+            4 => check_blank(&mut m, s),
+            _ => check(&mut m, s, s as u64),
+        }
+    }
+
+    assert_eq!(m.write(0, &[0u8; 10]), Err(DharaError::BadLength));
+    assert_eq!(m.write(DharaSector::MAX, &[0u8; SECTOR_SIZE]),
+        Err(DharaError::BadSector));
+}
+
+#[test]
+fn write_order() -> () {
+    let mut m = new_map();
+    let sectors: [DharaSector; 7] = [5000, 3, 77777, 129, 40, 1_000_000, 6];
+
+    for (i, &s) in sectors.iter().enumerate() {
+        write(&mut m, s, i as u64);
+    }
+    m.sync().expect("sync");
+
+    // However scattered, sectors written together share a page.
+    let (page, _) = m.find(sectors[0]).expect("find");
+    for (i, &s) in sectors.iter().enumerate() {
+        assert_eq!(m.find(s), Ok((page, i * SECTOR_SIZE)));
+    }
+
+    m.resume().expect("resume");
+    assert_eq!(m.get_size(), 7);
+    for (i, &s) in sectors.iter().enumerate() {
+        check(&mut m, s, i as u64);
+    }
+    check_blank(&mut m, 4);
+}
+
+#[test]
+fn resume_allocation() -> () {
+    let mut m = new_map();
+    let base: DharaSector = 1 << 31;
+    let packed = |m: &mut SmallMap| {
+        let mut keys = Vec::new();
+        while let Some(key) = m.inner_mut().find_nearest(keys.last().map_or(base, |k| k + 1)).expect("find_nearest") {
+            keys.push(key);
+        }
+        keys
+    };
+
+    // Five packed pages, the first of them since emptied and collected,
+    // as garbage collection leaves the oldest.
+    for s in 0..35 {
+        write(&mut m, s, s as u64);
+    }
+    for s in 0..7 {
+        m.trim(s).expect("trim");
+    }
+    m.sync().expect("sync");
+    assert_eq!(packed(&mut m), [base, base + 1, base + 2, base + 3, base + 4]);
+    m.inner_mut().trim(base).expect("trim");
+    m.inner_mut().sync().expect("sync");
+
+    // After a resume, new pages still go after the newest, rather than
+    // into the oldest's old place, so the oldest are still collected first.
+    m.resume().expect("resume");
+    for s in 100..107 {
+        write(&mut m, s, s as u64);
+    }
+    m.sync().expect("sync");
+    assert_eq!(packed(&mut m), [base + 1, base + 2, base + 3, base + 4, base + 5]);
+    for s in 7..35 {
+        check(&mut m, s, s as u64);
+    }
+    for s in 100..107 {
+        check(&mut m, s, s as u64);
+    }
+}
+
+#[test]
+fn churn() -> () {
+    let mut m = new_map();
+    let cap = m.get_capacity();
+    let mut seeds: Vec<u64> = vec![0u64; cap as usize];
+
+    // Fill, then rewrite over and over, so that garbage collection has to
+    // find room among dead slots.
+    for s in 0..cap {
+        write(&mut m, s, s as u64);
+        seeds[s as usize] = s as u64;
+    }
+    let mut x: u32 = 1;
+    for i in 0..(cap * 3) {
+        x = x.wrapping_mul(1103515245).wrapping_add(12345);
+        let s = (x >> 8) % cap;
+        let seed = 10000 + i as u64;
+        write(&mut m, s, seed);
+        seeds[s as usize] = seed;
+    }
+    assert_eq!(m.get_size(), cap);
+
+    // Capacity can grow as the map learns how many blocks are bad, so top
+    // up to whatever it is now, and then expect no more.
+    let mut s = cap;
+    loop {
+        let mut buf = [0u8; SECTOR_SIZE];
+        seq_gen(s as u64, &mut buf);
+        match m.write(s, &buf) {
+            Ok(()) => seeds.push(s as u64),
+            Err(DharaError::MapFull) => break,
+            Err(e) => panic!("write: {:?}", e),
+        }
+        s += 1;
+    }
+    let cap = s;
+    assert_eq!(m.get_size(), cap);
+    assert_eq!(m.get_size(), m.get_capacity());
+    m.sync().expect("sync");
+    m.resume().expect("resume");
+    assert_eq!(m.get_size(), cap);
+    for s in 0..cap {
+        check(&mut m, s, seeds[s as usize]);
+    }
+
+    // Trimming makes room again.
+    m.trim(0).expect("trim");
+    write(&mut m, cap, 1);
+    check(&mut m, cap, 1);
+    check_blank(&mut m, 0);
+}
+
+#[test]
+fn unsynced_lost() -> () {
+    let mut m = new_map();
+    write(&mut m, 1, 1);
+    m.sync().expect("sync");
+
+    write(&mut m, 2, 2);
+    m.resume().expect("resume");
+    check(&mut m, 1, 1);
+    check_blank(&mut m, 2);
+    assert_eq!(m.get_size(), 1);
+}
@@ -0,0 +1,74 @@
+mod sim;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use dhara_rs::nand::{DharaBlock, DharaNand, DharaNandRead, DharaPage};
+use dhara_rs::{DharaError, DharaMap};
+use sim::{seq_assert, seq_gen, SimNand, PAGE_SIZE};
+
+// The map's driver, over a chip that a Reader can also get at.
+struct Shared(Rc<RefCell<SimNand>>);
+
+impl DharaNand for Shared {
+    fn get_log2_page_size(&self) -> u8 {self.0.borrow().get_log2_page_size()}
+    fn get_log2_ppb(&self) -> u8 {self.0.borrow().get_log2_ppb()}
+    fn get_num_blocks(&self) -> u32 {self.0.borrow().get_num_blocks()}
+    fn is_bad(&mut self, blk: DharaBlock) -> bool {self.0.borrow_mut().is_bad(blk)}
+    fn mark_bad(&mut self, blk: DharaBlock) -> () {self.0.borrow_mut().mark_bad(blk)}
+    fn erase(&mut self, blk: DharaBlock) -> Result<(),DharaError> {self.0.borrow_mut().erase(blk)}
+    fn prog(&mut self, page: DharaPage, data: &[u8]) -> Result<(),DharaError> {self.0.borrow_mut().prog(page, data)}
+    fn is_free(&mut self, page: DharaPage) -> bool {self.0.borrow_mut().is_free(page)}
+    fn read(&mut self, page: u32, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        self.0.borrow_mut().read(page, offset, length, data)
+    }
+    fn copy(&mut self, src: DharaPage, dst: DharaPage) -> Result<(),DharaError> {self.0.borrow_mut().copy(src, dst)}
+}
+
+// A handle that can only read, as a second driver instance might.
+struct Reader(Rc<RefCell<SimNand>>);
+
+impl DharaNandRead for Reader {
+    fn read_page(&mut self, page: DharaPage, offset: usize, length: usize, data: &mut[u8]) -> Result<(), DharaError> {
+        self.0.borrow_mut().read(page, offset, length, data)
+    }
+}
+
+#[test]
+fn read_only() -> () {
+    let chip = Rc::new(RefCell::new(SimNand::new()));
+    chip.borrow_mut().sim_erase_all();
+
+    let mut map = DharaMap::<PAGE_SIZE, Shared>::new(Shared(chip.clone()), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    for s in 0..20 {
+        let mut buf = [0u8; PAGE_SIZE];
+        seq_gen(s as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    map.trim(7).expect("trim");
+    // Leave some of it unsynced, so that some metadata is still buffered.
+    map.sync().expect("sync");
+    let mut buf = [0u8; PAGE_SIZE];
+    seq_gen(100, &mut buf);
+    map.write(3, &buf).expect("write");
+
+    // Two views at once, with the map itself borrowed immutably.
+    let shared = &map;
+    let mut reader = Reader(chip.clone());
+    let mut other = Reader(chip.clone());
+    let mut view = shared.read_only(&mut reader);
+    let mut second = shared.read_only(&mut other);
+
+    for s in 0..20 {
+        let mut buf = [0u8; PAGE_SIZE];
+        view.read(s, &mut buf).expect("read");
+        match s {
+            3 => seq_assert(100, &buf),
+            7 => assert_eq!(buf, [0xffu8; PAGE_SIZE]),
+            _ => seq_assert(s as u64, &buf),
+        }
+        assert_eq!(second.exists(s), Ok(s != 7));
+    }
+    assert_eq!(view.find(20), Err(DharaError::NotFound));
+    assert_eq!(shared.get_size(), 19);
+}
@@ -0,0 +1,42 @@
+mod sim;
+
+use dhara_rs::wear::WearNand;
+use dhara_rs::DharaMap;
+use sim::{seq_gen, SimNand, NUM_BLOCKS, PAGE_SIZE};
+
+type WearMap = DharaMap::<512, WearNand<SimNand, NUM_BLOCKS>>;
+
+#[test]
+fn erase_counts() -> () {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = WearMap::new(WearNand::new(nand), [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    assert_eq!(map.journal.nand.erase_counts().len(), NUM_BLOCKS);
+
+    // Rewrite a working set many times over, so that the log wraps around
+    // the chip several times.
+    let mut buf = [0u8; PAGE_SIZE];
+    for i in 0..4000u32 {
+        let s = i % 200;
+        seq_gen(i as u64, &mut buf);
+        map.write(s, &buf).expect("write");
+    }
+    map.sync().expect("sync");
+
+    let counts = map.journal.nand.erase_counts();
+    assert!(counts.iter().all(|&c| c > 0));
+    let total: u32 = counts.iter().sum();
+    assert!(total as usize > 3 * NUM_BLOCKS);
+
+    // The log visits blocks in turn, so no block is more than a lap ahead.
+    let (lo, hi) = map.journal.nand.erase_spread().expect("spread");
+    assert!(lo > 0);
+    assert!(hi - lo <= 1, "uneven wear: {}..{}", lo, hi);
+
+    map.journal.nand.reset_erase_counts();
+    map.journal.nand.set_erase_count(5, 100);
+    assert_eq!(map.journal.nand.erase_spread(), Some((0, 100)));
+    assert_eq!(map.journal.nand.erase_counts()[5], 100);
+}
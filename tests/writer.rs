@@ -0,0 +1,66 @@
+mod sim;
+
+use dhara_rs::writer::SECTOR_WRITER_PAD;
+use dhara_rs::{DharaError, DharaMap, DHARA_SECTOR_NONE};
+use sim::{seq_gen, SimNand, PAGE_SIZE};
+
+type SimMap = DharaMap<PAGE_SIZE, SimNand>;
+
+fn new_map() -> SimMap {
+    let mut nand = SimNand::new();
+    nand.sim_erase_all();
+
+    let mut map = SimMap::new(nand, [0u8; PAGE_SIZE], 4);
+    map.resume_or_format().expect("resume");
+    map
+}
+
+#[test]
+fn push_chunks() -> () {
+    let mut map = new_map();
+
+    // Two and a half pages, pushed a few bytes at a time, in chunks that
+    // straddle the page boundaries.
+    let mut data = vec![0u8; PAGE_SIZE * 5 / 2];
+    seq_gen(7, &mut data);
+    let mut w = map.sector_writer(10);
+    for chunk in data.chunks(37) {
+        w.push(chunk).expect("push");
+    }
+    assert_eq!(w.sector(), 12);
+    assert_eq!(w.bytes_pushed(), data.len());
+    assert_eq!(w.finish(), Ok(3));
+
+    let mut buf = [0u8; PAGE_SIZE];
+    for (i, expected) in data.chunks(PAGE_SIZE).enumerate() {
+        map.read(10 + i as u32, &mut buf).expect("read");
+        assert_eq!(&buf[..expected.len()], expected);
+        assert!(buf[expected.len()..].iter().all(|&b| b == SECTOR_WRITER_PAD));
+    }
+    assert_eq!(map.get_size(), 3);
+
+    // Exactly a page: written by push(), with nothing left for finish().
+    let mut w = map.sector_writer(20);
+    w.push(&data[..PAGE_SIZE]).expect("push");
+    assert_eq!(w.sector(), 21);
+    assert_eq!(w.finish(), Ok(1));
+    assert_eq!(map.sector_writer(30).finish(), Ok(0));
+    assert_eq!(map.get_size(), 4);
+}
+
+#[test]
+fn push_fails() -> () {
+    let mut map = new_map();
+    let data = [0x42u8; PAGE_SIZE * 2];
+
+    // The page bound for the reserved sector stays buffered, and the rest
+    // isn't taken.
+    let mut w = map.sector_writer(DHARA_SECTOR_NONE - 1);
+    assert_eq!(w.push(&data), Err(DharaError::BadSector));
+    assert_eq!(w.sector(), DHARA_SECTOR_NONE);
+    assert_eq!(w.bytes_pushed(), PAGE_SIZE * 2);
+    assert_eq!(w.push(&data[..1]), Err(DharaError::BadSector));
+    assert_eq!(w.bytes_pushed(), PAGE_SIZE * 2);
+    assert_eq!(w.finish(), Err(DharaError::BadSector));
+    assert_eq!(map.get_size(), 1);
+}